@@ -0,0 +1,45 @@
+use std::env;
+use std::path::PathBuf;
+
+use cbindgen::{Config, Language};
+
+const HEADER: &str = "\
+/*
+ * C interface to the CHIP-8 emulator. Link against libchip8_emulator, e.g.
+ *
+ *     Chip8 *chip8 = chip8_create(1);
+ *     chip8_load_rom(chip8, rom, rom_length);
+ *     chip8_set_keys(chip8, 1 << 0x5);
+ *     chip8_run_frame(chip8);
+ *     chip8_destroy(chip8);
+ */";
+
+// set to refresh the committed include/chip8.h, which builds otherwise leave alone
+const UPDATE_HEADER_VARIABLE: &str = "CHIP8_UPDATE_HEADER";
+
+/// Generates chip8.h from src/ffi.rs into OUT_DIR, and over include/chip8.h
+/// when CHIP8_UPDATE_HEADER is set.
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-env-changed={}", UPDATE_HEADER_VARIABLE);
+
+    let mut config = Config::default();
+    config.language = Language::C;
+    config.header = Some(HEADER.to_string());
+    config.autogen_warning = Some("/* Generated from src/ffi.rs by build.rs, do not edit. Refresh with CHIP8_UPDATE_HEADER=1 cargo build. */".to_string());
+    config.include_guard = Some("CHIP8_H".to_string());
+    config.cpp_compat = true;
+    config.documentation = true;
+
+    let bindings = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/ffi.rs")
+        .generate()
+        .expect("Could not generate the C header.");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("Cargo sets OUT_DIR."));
+    bindings.write_to_file(out_dir.join("chip8.h"));
+    if env::var_os(UPDATE_HEADER_VARIABLE).is_some(){
+        bindings.write_to_file("include/chip8.h");
+    }
+}
@@ -0,0 +1,52 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const SCREEN_WIDTH: u32 = 64;
+pub const SCREEN_HEIGHT: u32 = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Framebuffer{
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<bool>,
+}
+
+impl Framebuffer{
+    pub fn new() -> Framebuffer{
+        Framebuffer {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            pixels: vec![false; (SCREEN_WIDTH*SCREEN_HEIGHT) as usize],
+        }
+    }
+
+    pub fn clear(&mut self){
+        self.pixels.iter_mut().for_each(|pixel| *pixel = false);
+    }
+
+    fn get_pixels_xy_idx(&self, x: u32, y: u32) -> usize{
+        (y * self.width + x) as usize
+    }
+
+    pub fn get_pixel_at(&self, x: u32, y: u32) -> bool{
+        self.pixels[self.get_pixels_xy_idx(x, y)]
+    }
+
+    pub fn set_pixel_at(&mut self, x: u32, y: u32, value: bool){
+        let idx = self.get_pixels_xy_idx(x, y);
+        self.pixels[idx] = value;
+    }
+
+    /// XORs a pixel on and returns true if it was already lit, i.e. a collision.
+    pub fn flip_pixel(&mut self, x: u32, y: u32) -> bool{
+        let was_set = self.get_pixel_at(x, y);
+        self.set_pixel_at(x, y, !was_set);
+        was_set
+    }
+}
+
+impl Default for Framebuffer{
+    fn default() -> Self{
+        Framebuffer::new()
+    }
+}
@@ -0,0 +1,17 @@
+#![no_std]
+
+/*
+ * The emulation core: CPU, memory, timers, framebuffer and save states. It
+ * only needs an allocator, so it builds for targets without an operating
+ * system. Frontends, timing against a real clock and file handling live in
+ * the chip8-emulator crate, which enables the std feature.
+ */
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod framebuffer;
+pub mod machine;
+pub mod savestate;
+pub mod symbols;
@@ -0,0 +1,1007 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::framebuffer::Framebuffer;
+use crate::symbols::SymbolMap;
+
+/// Logs what an instruction did, unless the frontend turned tracing off.
+/// Without std there is nowhere to print to, so nothing is logged.
+macro_rules! trace {
+    ($machine:expr, $($arg:tt)*) => {
+        #[cfg(feature = "std")]
+        if $machine.trace{
+            std::println!($($arg)*);
+        }
+    };
+}
+
+pub const RAM_SIZE: usize = 4096;
+pub const PROGRAM_START: u16 = 512;
+/// Nested calls the stack holds, as in most interpreters since SCHIP.
+pub const STACK_SIZE: usize = 16;
+
+/// Behaviours that differ between CHIP-8 interpreters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks{
+    pub ignore_y_in_8xy_shift_instruction: bool,
+}
+
+impl Default for Quirks{
+    fn default() -> Self{
+        Quirks { ignore_y_in_8xy_shift_instruction: true }
+    }
+}
+
+/// Xorshift generator behind CXNN. It is part of the machine so that save
+/// states restore the exact same sequence of random numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rng{
+    pub state: u32,
+}
+
+impl Rng{
+    pub fn new(seed: u32) -> Rng{
+        // xorshift never leaves zero
+        Rng { state: if seed == 0 { 0x2545F491 } else { seed } }
+    }
+
+    pub fn next_byte(&mut self) -> u8{
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        (x >> 24) as u8
+    }
+}
+
+pub struct Machine{
+    /*
+     * 80 - 159 = Font data
+     * 160 - 162 = Index Register
+     */
+    pub ram: [u8; RAM_SIZE],
+    pub program_counter: u16,
+    pub stack: Vec<u16>, // really should be part of main memory
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub variable_registers: [u8; 16],
+    pub framebuffer: Framebuffer,
+    pub quirks: Quirks,
+    pub rng: Rng,
+
+    /// Keys currently held on the hex keypad, set by the frontend.
+    pub keypad: Vec<u8>,
+    /// Key that FX0A saw go down and is waiting on to be released.
+    pub key_wait: Option<u8>,
+    /// Set whenever the framebuffer is modified so frontends only redraw when needed.
+    pub screen_changed: bool,
+    pub rom_hash: u64,
+    pub symbols: SymbolMap,
+    /// Prints every executed instruction when built with std. Frontends that
+    /// own stdout, like the terminal one, switch this off.
+    pub trace: bool,
+    /// When set, the address and opcode of every executed instruction is
+    /// appended here for whoever drains it, like the web dashboard.
+    pub instruction_log: Option<Vec<(u16, u16)>>,
+}
+
+impl Machine{
+    pub fn new(quirks: Quirks, seed: u32) -> Machine{
+        let mut ram = [0; RAM_SIZE];
+        load_fonts(&mut ram);
+
+        Machine {
+            ram,
+            program_counter: PROGRAM_START,
+            stack: Vec::new(),
+            delay_timer: 0,
+            sound_timer: 0,
+            variable_registers: [0; 16],
+            framebuffer: Framebuffer::new(),
+            quirks,
+            rng: Rng::new(seed),
+            keypad: Vec::new(),
+            key_wait: None,
+            screen_changed: true,
+            rom_hash: 0,
+            symbols: SymbolMap::new(),
+            trace: true,
+            instruction_log: None,
+        }
+    }
+
+    pub fn load_rom(&mut self, content: &[u8]) -> Result<(), String>{
+        let program_start = PROGRAM_START as usize;
+        if content.len() > RAM_SIZE - program_start{
+            return Err(format!("ROM is {} bytes but only {} fit in memory", content.len(), RAM_SIZE - program_start));
+        }
+
+        // load memory in to 512 in decimal
+        self.ram[program_start..program_start+content.len()]
+            .clone_from_slice(content);
+        self.rom_hash = rom_hash(content);
+
+        Ok(())
+    }
+
+    /// Decrements both timers, called at 60 Hz.
+    pub fn tick_timers(&mut self){
+        if self.delay_timer > 0{
+            self.delay_timer -= 1;
+        }
+        if self.sound_timer > 0{
+            self.sound_timer -= 1;
+        }
+    }
+
+    /// Fetches, decodes and executes a single instruction. A program that
+    /// does something the machine cannot, like returning with an empty stack
+    /// or reading past the end of memory, gets an error and the machine is
+    /// left at the offending instruction.
+    pub fn step(&mut self) -> Result<(), String>{
+        let instruction_address = self.program_counter;
+        if instruction_address as usize + 1 >= RAM_SIZE{
+            return Err(format!("The program counter ran past the end of memory at {:#05x}.", instruction_address));
+        }
+
+        let first_byte = self.ram[instruction_address as usize];
+        let second_byte = self.ram[instruction_address as usize + 1];
+        if let Some(log) = &mut self.instruction_log{
+            log.push((instruction_address, u16::from_be_bytes([first_byte, second_byte])));
+        }
+        self.program_counter += 2;
+
+        let current_instruction = (
+            first_byte >> 4, first_byte & 0b00001111,
+            second_byte >> 4, second_byte & 0b00001111,
+        );
+
+        trace!(self, "{:?}", current_instruction);
+
+        match current_instruction {
+            // 00E0
+            (0, 0, 0xE, 0) =>{
+                self.framebuffer.clear();
+                self.screen_changed = true;
+                trace!(self, "Clear screen!");
+            },
+            // 00EE
+            (0, 0, 0xE, 0xE) =>{
+                let Some(return_point) = self.stack.pop() else {
+                    self.program_counter = instruction_address;
+                    return Err(format!("Returned from a subroutine with an empty stack at {:#05x}.", instruction_address));
+                };
+
+                trace!(self, "Returning from subroutine to address {}", return_point);
+
+                self.program_counter = return_point;
+            }
+            // 1NNN
+            (1, n0, n1, n2) =>{
+                // let address = u16::from_be_bytes([n0, (n1 << 4 | n2)]);
+                let address = extract_12_bit_number(n0, n1, n2);
+                trace!(self, "Jump to {}", self.symbols.describe(address));
+
+                self.program_counter = address;
+
+                // trace!(self, "Remove if not working on IBM Logo");
+                // ::std::thread::sleep(Duration::new(1000, 1));
+                // break; // TEMP BECAUSE IBM LOGO REPEATS HERE
+            },
+            //2NNN
+            (2, n0, n1, n2) =>{
+                // let address = u16::from_be_bytes([n0, (n1 << 4 | n2)]);
+                let address = extract_12_bit_number(n0, n1, n2);
+                trace!(self, "Calling address {}", self.symbols.describe(address));
+
+                if self.stack.len() >= STACK_SIZE{
+                    self.program_counter = instruction_address;
+                    return Err(format!("Stack overflow calling {:#05x} at {:#05x}, more than {} nested calls.", address, instruction_address, STACK_SIZE));
+                }
+                self.stack.push(self.program_counter);
+
+                self.program_counter = address;
+            }
+            //3XNN
+            (3, x, n0, n1) =>{
+                let value = extract_8_bit_number(n0, n1);
+
+                if self.variable_registers[x as usize] == value{
+                    trace!(self, "Skipping an instruction since {} = {}", self.variable_registers[x as usize], value);
+                    self.program_counter += 2;
+                } else{
+                    trace!(self, "Not skipping an instruction since {} != {}", self.variable_registers[x as usize], value);
+                }
+            }
+            //4XNN
+            (4, x, n0, n1) =>{
+                let value = extract_8_bit_number(n0, n1);
+
+                if self.variable_registers[x as usize] != value{
+                    trace!(self, "Skipping an instruction since {} != {}", self.variable_registers[x as usize], value);
+                    self.program_counter += 2;
+                } else{
+                    trace!(self, "Not skipping an instruction since {} = {}", self.variable_registers[x as usize], value);
+                }
+            }
+            //5XY0
+            (5, x, y, 0) =>{
+                if self.variable_registers[x as usize] == self.variable_registers[y as usize]{
+                    trace!(self, "Skipping an instruction since {} = {}", self.variable_registers[x as usize], self.variable_registers[y as usize]);
+                    self.program_counter += 2;
+                } else{
+                    trace!(self, "Not skipping an instruction since {} != {}", self.variable_registers[x as usize], self.variable_registers[y as usize]);
+                }
+            }
+            //6XNN
+            (6, x, n0, n1) =>{
+                let value = extract_8_bit_number(n0, n1);
+
+                trace!(self, "Set register V{} to {}", x, value);
+
+                self.variable_registers[x as usize] = value;
+            },
+            //7XNN
+            (7, x, n0, n1) =>{
+                let value = extract_8_bit_number(n0, n1);
+
+                trace!(self, "To register V{} add {}", x, value);
+
+                // is this alright if it overflows?
+                self.variable_registers[x as usize] = self.variable_registers[x as usize].wrapping_add(value);
+            },
+            //8XY0
+            (8, x, y, 0) =>{
+                self.variable_registers[x as usize] = self.variable_registers[y as usize];
+                trace!(self, "Register {} is set to register {} - value of {}", x, y, self.variable_registers[y as usize]);
+            },
+            //8XY1
+            (8, x, y, 1) =>{
+                self.variable_registers[x as usize] |= self.variable_registers[y as usize];
+                trace!(self, "Register {} is OR'd with register {}", x, y);
+            },
+            //8XY2
+            (8, x, y, 2) =>{
+                self.variable_registers[x as usize] &= self.variable_registers[y as usize];
+                trace!(self, "Register {} is AND'd with register {}", x, y);
+            },
+            //8XY3
+            (8, x, y, 3) =>{
+                self.variable_registers[x as usize] ^= self.variable_registers[y as usize];
+                trace!(self, "Register {} is XOR'd with register {}", x, y);
+            },
+            //8XY4
+            (8, x, y, 4) =>{
+                let (sum, is_overflow) = self.variable_registers[x as usize].overflowing_add(self.variable_registers[y as usize]);
+                
+                self.variable_registers[x as usize] = sum;
+
+                if is_overflow{
+                    self.variable_registers[0xF] = 1;
+                    trace!(self, "Adding register {} to {}. Overflowed, setting VF to 1.", x, y);
+                } else{
+                    self.variable_registers[0xF] = 0;
+                    trace!(self, "Adding register {} to {}. No overflow, setting VF to 0.", x, y);
+                }
+            },
+            //8XY5
+            (8, x, y, 5) =>{
+                let is_underflow = self.variable_registers[y as usize] > self.variable_registers[x as usize];
+                self.variable_registers[x as usize] = self.variable_registers[x as usize].wrapping_sub(self.variable_registers[y as usize]);
+                
+                if is_underflow{
+                    self.variable_registers[0xF] = 0;
+
+                    trace!(self, "Subtracting register {} from {}. Underflowed, setting VF to 0.", x, y);
+                } else{
+                    self.variable_registers[0xF] = 1;
+                    trace!(self, "Subtracting register {} from {}. No underflow, setting VF to 1.", x, y);
+                }
+            },
+            //8XY6
+            (8, x, y, 6) =>{
+                if self.quirks.ignore_y_in_8xy_shift_instruction{
+                    trace!(self, "Ignore Y in 8XY shift instruction");
+                } else{
+                    trace!(self, "Use Y in 8XY shift instruction");
+                    self.variable_registers[x as usize] = self.variable_registers[y as usize];
+                }
+
+                let shifted_out_bit = self.variable_registers[x as usize] & 0b00000001; 
+                self.variable_registers[x as usize] >>= 1;
+
+                self.variable_registers[0xF] = shifted_out_bit & 1;
+            },
+            //8XY7
+            (8, x, y, 7) =>{
+                let is_underflow = self.variable_registers[x as usize] > self.variable_registers[y as usize];
+                self.variable_registers[x as usize] = self.variable_registers[y as usize].wrapping_sub(self.variable_registers[x as usize]);
+                
+                if is_underflow{
+                    self.variable_registers[0xF] = 0;
+
+                    trace!(self, "Subtracting register {} from {}. Underflowed, setting VF to 0.", y, x);
+                } else{
+                    self.variable_registers[0xF] = 1;
+                    trace!(self, "Subtracting register {} from {}. No underflow, setting VF to 1.", y, x);
+                }
+            },
+            //8XYE
+            (8, x, y, 0xE) =>{
+                if self.quirks.ignore_y_in_8xy_shift_instruction{
+                    trace!(self, "Ignore Y in 8XY shift instruction");
+                } else{
+                    trace!(self, "Use Y in 8XY shift instruction");
+                    self.variable_registers[x as usize] = self.variable_registers[y as usize];
+                }
+
+                let shifted_out_bit = self.variable_registers[x as usize] >> 7 & 0b00000001; 
+                self.variable_registers[x as usize] <<= 1;
+
+                self.variable_registers[0xF] = shifted_out_bit & 1;
+            },
+            //9XY0
+            (9, x, y, 0) =>{
+                if self.variable_registers[x as usize] != self.variable_registers[y as usize]{
+                    trace!(self, "Skipping an instruction since {} != {}", self.variable_registers[x as usize], self.variable_registers[y as usize]);
+                    self.program_counter += 2;
+                } else{
+                    trace!(self, "Not skipping an instruction since {} == {}", self.variable_registers[x as usize], self.variable_registers[y as usize]);
+                }
+            }
+            //ANNN
+            (0xA, n0, n1, n2) =>{
+                // let value = u16::from_be_bytes([n0, (n1 << 4 | n2)]);
+                let value = extract_12_bit_number(n0, n1, n2);
+
+                trace!(self, "Set index register I to {}", value);
+
+                set_index_register(&mut self.ram, value);
+            },
+            //BNNN
+            (0xB, n0, n1, n2) =>{
+                let address = extract_12_bit_number(n0, n1, n2) + self.variable_registers[0] as u16;
+                trace!(self, "Jump to {} plus V0, {:#05x}", self.symbols.describe(extract_12_bit_number(n0, n1, n2)), address);
+
+                self.program_counter = address;
+            },
+            //CXNN
+            (0xC, x, n0, n1) =>{
+                let mask = extract_8_bit_number(n0, n1);
+                self.variable_registers[x as usize] = self.rng.next_byte() & mask;
+                trace!(self, "Setting V{} to a random number masked with {}", x, mask);
+            },
+            //DXYN
+            (0xD, x, y, n0) =>{
+                // the starting position wraps around the screen, the sprite itself is clipped
+                let x_start = self.variable_registers[x as usize] % 64;
+                let y_start = self.variable_registers[y as usize] % 32;
+
+                self.check_index_range(n0 as usize, instruction_address)?;
+                let index_register_value = get_index_register(&self.ram);
+
+                self.variable_registers[0x0f] = 0;
+
+                for row in 0..n0{
+                    let y_coord = y_start + row;
+
+                    if y_coord > 31{ break; }
+
+                    let sprite_data = self.ram[index_register_value as usize + row as usize];
+
+                    for column in 0..8{
+                        let x_coord = x_start + column;
+                        let bit = sprite_data >> (7 - column) & 1;
+
+                        if x_coord > 63{ break; }
+
+                        if bit == 1{ 
+                            if self.framebuffer.flip_pixel(x_coord as u32, y_coord as u32){
+                                self.variable_registers[0x0f] = 1;
+                            }
+                            self.screen_changed = true;
+                        }
+                    }
+                }
+
+            },
+            //EX9E
+            (0xE, x, 9, 0xE) =>{
+                let key = self.variable_registers[x as usize] & 0xF;
+
+                if self.keypad.contains(&key){
+                    trace!(self, "Keypad pressed {} so incrementing PC by 2", key);
+                    self.program_counter += 2;
+                } else{
+                    trace!(self, "Keypad DID NOT press {}", key);
+                }
+            },
+            //EXA1
+            (0xE, x, 0xA, 1) =>{
+                let key = self.variable_registers[x as usize] & 0xF;
+
+                if !self.keypad.contains(&key){
+                    trace!(self, "Keypad DID NOT press {} so incrementing PC by 2", key);
+                    self.program_counter += 2;
+                } else{
+                    trace!(self, "Keypad did press {}", key);
+                }
+            },
+            //FX07
+            (0xF, x, 0, 7) =>{
+                self.variable_registers[x as usize] = self.delay_timer;
+                trace!(self, "Setting V{} to value of delay timer", x);
+            },
+            //FX15 
+            (0xF, x, 1, 5) =>{
+                self.delay_timer = self.variable_registers[x as usize];
+                trace!(self, "Setting delay timer to value of V{} which is {}", x, self.variable_registers[x as usize]);
+            },
+            //FX18  
+            (0xF, x, 1, 8) =>{
+                self.sound_timer = self.variable_registers[x as usize];
+                trace!(self, "Setting sound timer to value of V{}", x);
+            },
+            //FX1E  
+            (0xF, x, 1, 0xE) =>{
+                increment_index_register(&mut self.ram, self.variable_registers[x as usize] as u16);
+                trace!(self, "Incrementing index register by value of V{}", x);
+
+                if get_index_register(&self.ram) > 0xFFF{
+                    self.variable_registers[0xF] = 1;
+                    trace!(self, "Index register overflowed so VF=1");
+                }
+            },
+            //FX0A  
+            // like the VIP, waits for a key to be pressed and then released
+            (0xF, x, 0, 0xA) =>{
+                match self.key_wait {
+                    Some(key) if !self.keypad.contains(&key) =>{
+                        self.key_wait = None;
+                        self.variable_registers[x as usize] = key;
+                        trace!(self, "Detected key pad {} released, setting in V{}", key, x);
+                    },
+                    Some(_) =>{
+                        trace!(self, "Waiting for key to be released so repeating instruction.");
+                        self.program_counter -= 2;
+                    },
+                    None =>{
+                        if let Some(key) = self.keypad.first(){
+                            trace!(self, "Detected key pad {} pressed, waiting for release", key);
+                            self.key_wait = Some(*key);
+                        } else{
+                            trace!(self, "No key pressed so repeating instruction.");
+                        }
+                        self.program_counter -= 2;
+                    },
+                }
+            },
+            //FX29 
+            (0xF, x, 2, 9) =>{
+                let hex_character = self.variable_registers[x as usize];
+                let address = get_font_character_address(hex_character);
+                set_index_register(&mut self.ram, address as u16);
+                trace!(self, "Setting index register to value of V{} which is a font at {}", x, address);
+            },
+            //FX33
+            (0xF, x, 3, 3) =>{
+                self.check_index_range(3, instruction_address)?;
+                let number = self.variable_registers[x as usize];
+                set_index_register_at_positions(&mut self.ram, number/100, (number/10)%10 , number%10);
+                trace!(self, "Setting memory at index register to each decimal digit of {}", number);
+            },
+            //FX55 
+            (0xF, x, 5, 5) =>{
+                self.check_index_range(x as usize + 1, instruction_address)?;
+                let slice = &self.variable_registers[0..(x+1) as usize];
+                set_index_register_with_value_registers(&mut self.ram, slice);
+                trace!(self, "Setting memory at index register with values from V0 to V{}", x);
+            },
+            //FX65
+            (0xF, x, 6, 5) =>{
+                self.check_index_range(x as usize + 1, instruction_address)?;
+                get_index_register_as_value_registers(
+                    &mut self.ram,
+                    &mut self.variable_registers[0..(x+1) as usize], 
+                    x+1
+                );
+                trace!(self, "Setting V0 to V{} from memory at index register", x);
+            },
+
+            _ =>{
+                trace!(self, "Unrecognized instruction");
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Fails if `length` bytes at I run past the end of memory, leaving the
+    /// machine at the instruction.
+    fn check_index_range(&mut self, length: usize, instruction_address: u16) -> Result<(), String>{
+        let index_register_value = get_index_register(&self.ram);
+        if index_register_value as usize + length > RAM_SIZE{
+            self.program_counter = instruction_address;
+            return Err(format!("{} bytes at I={:#05x} run past the end of memory at {:#05x}.", length, index_register_value, instruction_address));
+        }
+        Ok(())
+    }
+}
+
+/// Hash of the ROM contents, used to keep per-ROM files apart.
+pub fn rom_hash(content: &[u8]) -> u64{
+    hash_bytes(content)
+}
+
+/// 64-bit FNV-1a.
+pub fn hash_bytes(data: &[u8]) -> u64{
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn load_fonts(ram: &mut [u8; 4096]){
+    let font_data: [u8; 80] = [
+        0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+        0x20, 0x60, 0x20, 0x20, 0x70, // 1
+        0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+        0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+        0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+        0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+        0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+        0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+        0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+        0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+        0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+        0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+        0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+        0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+        0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+        0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+    ];
+
+    let font_memory_location = 80;
+    
+    ram[font_memory_location..font_memory_location + font_data.len()]
+        .clone_from_slice(&font_data);
+}
+
+/// Each of the 16 hex digits is 5 bytes of font data; only the low nibble counts.
+pub fn get_font_character_address(character: u8) -> u8{
+    let font_memory_location = 80;
+    font_memory_location + (character & 0xF) * 5
+}
+
+pub fn set_index_register(ram: &mut [u8; 4096], value: u16){
+    let index_register_position = 160;
+
+    ram[index_register_position..index_register_position+2]
+        .clone_from_slice(&value.to_be_bytes());
+}
+
+/// Writes three bytes to memory where the index register points.
+pub fn set_index_register_at_positions(ram: &mut [u8; 4096], value1: u8, value2: u8, value3: u8){
+    let index_register_position = get_index_register(ram) as usize;
+
+    ram[index_register_position] = value1;
+    ram[index_register_position+1] = value2;
+    ram[index_register_position+2] = value3;
+}
+
+/// Copies registers to memory where the index register points.
+pub fn set_index_register_with_value_registers(ram: &mut [u8; 4096], variable_registers: &[u8]){
+    let index_register_position = get_index_register(ram) as usize;
+
+    ram[index_register_position..index_register_position+variable_registers.len()]
+        .clone_from_slice(variable_registers);
+}
+/// Copies memory where the index register points into registers.
+pub fn get_index_register_as_value_registers(ram: &mut [u8; 4096], variable_registers: &mut [u8], length: u8){
+    let index_register_position = get_index_register(ram) as usize;
+
+    variable_registers
+        .clone_from_slice(&ram[index_register_position..index_register_position+length as usize]);
+}
+
+pub fn increment_index_register(ram: &mut [u8; 4096], increment: u16){
+    let index_register_position = 160;
+
+    let current_value = u16::from_be_bytes([ram[index_register_position], ram[index_register_position+1]]);
+
+    set_index_register(ram, current_value.wrapping_add(increment));
+}
+
+pub fn get_index_register(ram: &[u8; 4096]) -> u16{
+    let index_register_position = 160;
+    u16::from_be_bytes([ram[index_register_position], ram[index_register_position+1]])
+}
+
+pub fn extract_8_bit_number(n0: u8, n1: u8) -> u8{
+    (n0 << 4) + n1
+}
+
+pub fn extract_12_bit_number(n0: u8, n1: u8, n2: u8) -> u16{
+    u16::from_be_bytes([n0, (n1 << 4 | n2)])
+}
+
+#[cfg(test)]
+mod tests{
+    use alloc::vec;
+
+    use super::*;
+
+    // run under Node on wasm32, see Cargo.toml
+    #[cfg(target_arch = "wasm32")]
+    use wasm_bindgen_test::wasm_bindgen_test as test;
+
+    fn machine_with(program: &[u8]) -> Machine{
+        let mut machine = Machine::new(Quirks::default(), 1);
+        machine.trace = false;
+        machine.load_rom(program).unwrap();
+        machine
+    }
+
+    fn run(machine: &mut Machine, instructions: usize){
+        for _ in 0..instructions{
+            machine.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn loads_and_adds_with_wrapping(){
+        // V3 = 0xF0; V3 += 0x20
+        let mut machine = machine_with(&[0x63, 0xF0, 0x73, 0x20]);
+        run(&mut machine, 2);
+
+        assert_eq!(machine.variable_registers[3], 0x10);
+        // 7XNN leaves the carry flag alone
+        assert_eq!(machine.variable_registers[0xF], 0);
+    }
+
+    #[test]
+    fn logic_operations(){
+        // V0 = 0b1100, V1 = 0b1010, then OR, AND and XOR into V2..V4
+        let mut machine = machine_with(&[
+            0x60, 0x0C, 0x61, 0x0A,
+            0x82, 0x00, 0x82, 0x11,
+            0x83, 0x00, 0x83, 0x12,
+            0x84, 0x00, 0x84, 0x13,
+        ]);
+        run(&mut machine, 8);
+
+        assert_eq!(machine.variable_registers[2], 0b1110);
+        assert_eq!(machine.variable_registers[3], 0b1000);
+        assert_eq!(machine.variable_registers[4], 0b0110);
+    }
+
+    #[test]
+    fn add_sets_carry(){
+        // V0 = 0xFF, V1 = 2, V0 += V1, then V2 = 1, V2 += V2
+        let mut machine = machine_with(&[0x60, 0xFF, 0x61, 0x02, 0x80, 0x14, 0x62, 0x01, 0x82, 0x24]);
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 0x01);
+        assert_eq!(machine.variable_registers[0xF], 1);
+
+        run(&mut machine, 2);
+        assert_eq!(machine.variable_registers[2], 2);
+        assert_eq!(machine.variable_registers[0xF], 0);
+    }
+
+    #[test]
+    fn subtract_sets_not_borrow(){
+        // V0 = 200, V1 = 10, V0 -= V1
+        let mut machine = machine_with(&[0x60, 200, 0x61, 10, 0x80, 0x15]);
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 190);
+        assert_eq!(machine.variable_registers[0xF], 1);
+
+        // V0 = 10, V1 = 200, V0 -= V1
+        let mut machine = machine_with(&[0x60, 10, 0x61, 200, 0x80, 0x15]);
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 66);
+        assert_eq!(machine.variable_registers[0xF], 0);
+
+        // equal values do not borrow
+        let mut machine = machine_with(&[0x60, 7, 0x61, 7, 0x80, 0x15]);
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 0);
+        assert_eq!(machine.variable_registers[0xF], 1);
+    }
+
+    #[test]
+    fn reverse_subtract_sets_not_borrow(){
+        // V0 = 10, V1 = 200, V0 = V1 - V0
+        let mut machine = machine_with(&[0x60, 10, 0x61, 200, 0x80, 0x17]);
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 190);
+        assert_eq!(machine.variable_registers[0xF], 1);
+
+        // V0 = 200, V1 = 10, V0 = V1 - V0
+        let mut machine = machine_with(&[0x60, 200, 0x61, 10, 0x80, 0x17]);
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 66);
+        assert_eq!(machine.variable_registers[0xF], 0);
+    }
+
+    #[test]
+    fn shifts_follow_the_quirk(){
+        // V0 = 0b1000_0001, V1 = 0b0000_0010, V0 >>= 1, then V2 = V0, V2 <<= 1
+        let program = [0x60, 0x81, 0x61, 0x02, 0x80, 0x16, 0x82, 0x00, 0x82, 0x1E];
+
+        let mut machine = machine_with(&program);
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 0x40);
+        assert_eq!(machine.variable_registers[0xF], 1);
+        run(&mut machine, 2);
+        assert_eq!(machine.variable_registers[2], 0x80);
+        assert_eq!(machine.variable_registers[0xF], 0);
+
+        // the VIP shifts VY into VX instead
+        let mut machine = machine_with(&program);
+        machine.quirks.ignore_y_in_8xy_shift_instruction = false;
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[0], 0x01);
+        assert_eq!(machine.variable_registers[0xF], 0);
+        run(&mut machine, 2);
+        assert_eq!(machine.variable_registers[2], 0x04);
+        assert_eq!(machine.variable_registers[0xF], 0);
+    }
+
+    #[test]
+    fn shift_left_sets_the_bit_shifted_out(){
+        // V0 = 0x81, V0 <<= 1
+        let mut machine = machine_with(&[0x60, 0x81, 0x80, 0x0E]);
+        run(&mut machine, 2);
+        assert_eq!(machine.variable_registers[0], 0x02);
+        assert_eq!(machine.variable_registers[0xF], 1);
+    }
+
+    #[test]
+    fn conditional_skips(){
+        // V0 = 5, V1 = 5, then each skip over a V2 += 1
+        let mut machine = machine_with(&[
+            0x60, 0x05, 0x61, 0x05,
+            0x30, 0x05, 0x72, 0x01, // skipped, V0 == 5
+            0x40, 0x05, 0x72, 0x02, // not skipped, V0 == 5
+            0x50, 0x10, 0x72, 0x04, // skipped, V0 == V1
+            0x90, 0x10, 0x72, 0x08, // not skipped, V0 == V1
+        ]);
+        run(&mut machine, 2 + 2 + 2 + 2 + 2 - 2);
+
+        assert_eq!(machine.variable_registers[2], 0x02 | 0x08);
+        assert_eq!(machine.program_counter, PROGRAM_START + 20);
+    }
+
+    #[test]
+    fn jumps(){
+        // 0x200: jump 0x206; 0x206: V0 = 2, jump0 0x300 + V0
+        let mut machine = machine_with(&[0x12, 0x06, 0x00, 0x00, 0x00, 0x00, 0x60, 0x02, 0xB3, 0x00]);
+        run(&mut machine, 1);
+        assert_eq!(machine.program_counter, 0x206);
+
+        run(&mut machine, 2);
+        assert_eq!(machine.program_counter, 0x302);
+    }
+
+    #[test]
+    fn calls_and_returns(){
+        // 0x200: call 0x206, V1 = 1; 0x206: V0 = 7, return
+        let mut machine = machine_with(&[0x22, 0x06, 0x61, 0x01, 0x00, 0x00, 0x60, 0x07, 0x00, 0xEE]);
+        run(&mut machine, 1);
+        assert_eq!(machine.program_counter, 0x206);
+        assert_eq!(machine.stack, vec![0x202]);
+
+        run(&mut machine, 3);
+        assert!(machine.stack.is_empty());
+        assert_eq!(machine.variable_registers[0], 7);
+        assert_eq!(machine.variable_registers[1], 1);
+        assert_eq!(machine.program_counter, 0x204);
+    }
+
+    #[test]
+    fn index_register(){
+        // I = 0x123, V0 = 0x10, I += V0
+        let mut machine = machine_with(&[0xA1, 0x23, 0x60, 0x10, 0xF0, 0x1E]);
+        run(&mut machine, 3);
+        assert_eq!(get_index_register(&machine.ram), 0x133);
+    }
+
+    #[test]
+    fn binary_coded_decimal_is_written_at_i(){
+        // I = 0x300, V5 = 254, BCD V5
+        let mut machine = machine_with(&[0xA3, 0x00, 0x65, 0xFE, 0xF5, 0x33]);
+        run(&mut machine, 3);
+        assert_eq!(&machine.ram[0x300..0x303], &[2, 5, 4]);
+        assert_eq!(get_index_register(&machine.ram), 0x300);
+    }
+
+    #[test]
+    fn registers_are_stored_and_loaded_at_i(){
+        // V0 = 1, V1 = 2, V2 = 3, I = 0x300, save V0-V2, clear them, load V0-V1
+        let mut machine = machine_with(&[
+            0x60, 0x01, 0x61, 0x02, 0x62, 0x03,
+            0xA3, 0x00, 0xF2, 0x55,
+            0x60, 0x00, 0x61, 0x00, 0x62, 0x00,
+            0xF1, 0x65,
+        ]);
+        run(&mut machine, 5);
+        assert_eq!(&machine.ram[0x300..0x304], &[1, 2, 3, 0]);
+
+        run(&mut machine, 4);
+        assert_eq!(&machine.variable_registers[0..3], &[1, 2, 0]);
+        assert_eq!(get_index_register(&machine.ram), 0x300);
+    }
+
+    #[test]
+    fn font_characters(){
+        // V0 = 0xA, I = font for V0
+        let mut machine = machine_with(&[0x60, 0x0A, 0xF0, 0x29]);
+        run(&mut machine, 2);
+        let address = get_index_register(&machine.ram) as usize;
+        assert_eq!(&machine.ram[address..address + 5], &[0xF0, 0x90, 0xF0, 0x90, 0x90]);
+
+        // only the low nibble picks the character
+        let mut machine = machine_with(&[0x60, 0xFA, 0xF0, 0x29]);
+        run(&mut machine, 2);
+        assert_eq!(get_index_register(&machine.ram) as usize, address);
+    }
+
+    #[test]
+    fn drawing_flips_pixels_and_reports_collisions(){
+        // I = font 0, V0 = 62, V1 = 30, draw 0 twice
+        let mut machine = machine_with(&[0x60, 0x00, 0xF0, 0x29, 0x60, 0x3E, 0x61, 0x1E, 0xD0, 0x15, 0xD0, 0x15]);
+        run(&mut machine, 5);
+
+        // 0xF0 on the first row: clipped at the right edge
+        assert!(machine.framebuffer.get_pixel_at(62, 30));
+        assert!(machine.framebuffer.get_pixel_at(63, 30));
+        assert!(!machine.framebuffer.get_pixel_at(0, 30));
+        // only two rows fit above the bottom edge
+        assert!(machine.framebuffer.get_pixel_at(62, 31));
+        assert!(!machine.framebuffer.get_pixel_at(62, 0));
+        assert_eq!(machine.variable_registers[0xF], 0);
+        assert!(machine.screen_changed);
+
+        run(&mut machine, 1);
+        assert!(machine.framebuffer.pixels.iter().all(|pixel| !pixel));
+        assert_eq!(machine.variable_registers[0xF], 1);
+    }
+
+    #[test]
+    fn drawing_wraps_the_starting_position(){
+        // I = font 1, V0 = 64 + 1, V1 = 32 + 2, draw
+        let mut machine = machine_with(&[0x60, 0x01, 0xF0, 0x29, 0x60, 0x41, 0x61, 0x22, 0xD0, 0x11]);
+        run(&mut machine, 5);
+
+        // the first row of "1" is 0x20
+        assert!(machine.framebuffer.get_pixel_at(3, 2));
+        assert_eq!(machine.framebuffer.pixels.iter().filter(|pixel| **pixel).count(), 1);
+    }
+
+    #[test]
+    fn clear_screen(){
+        let mut machine = machine_with(&[0x00, 0xE0]);
+        machine.framebuffer.set_pixel_at(5, 5, true);
+        run(&mut machine, 1);
+        assert!(machine.framebuffer.pixels.iter().all(|pixel| !pixel));
+    }
+
+    #[test]
+    fn timers(){
+        // V0 = 3, DT = V0, ST = V0, V1 = DT
+        let mut machine = machine_with(&[0x60, 0x03, 0xF0, 0x15, 0xF0, 0x18, 0xF1, 0x07]);
+        run(&mut machine, 3);
+        machine.tick_timers();
+        run(&mut machine, 1);
+        assert_eq!(machine.variable_registers[1], 2);
+        assert_eq!(machine.sound_timer, 2);
+
+        for _ in 0..5{
+            machine.tick_timers();
+        }
+        assert_eq!(machine.delay_timer, 0);
+        assert_eq!(machine.sound_timer, 0);
+    }
+
+    #[test]
+    fn random_numbers_are_masked_and_follow_the_seed(){
+        // V0 = random & 0x0F, V1 = random & 0xFF
+        let program = [0xC0, 0x0F, 0xC1, 0xFF];
+        let mut first = machine_with(&program);
+        let mut second = machine_with(&program);
+        run(&mut first, 2);
+        run(&mut second, 2);
+
+        assert!(first.variable_registers[0] <= 0x0F);
+        assert_eq!(first.variable_registers, second.variable_registers);
+    }
+
+    #[test]
+    fn key_skips_use_the_low_nibble_of_vx(){
+        // V0 = 0x15, skip if key V0 pressed, V1 = 1, skip if key V0 not pressed, V2 = 1
+        let program = [0x60, 0x15, 0xE0, 0x9E, 0x61, 0x01, 0xE0, 0xA1, 0x62, 0x01];
+
+        let mut machine = machine_with(&program);
+        machine.keypad = vec![0x5];
+        run(&mut machine, 4);
+        assert_eq!(machine.variable_registers[1], 0);
+        assert_eq!(machine.variable_registers[2], 1);
+
+        let mut machine = machine_with(&program);
+        machine.keypad = vec![0x4];
+        run(&mut machine, 3);
+        assert_eq!(machine.variable_registers[1], 1);
+        run(&mut machine, 1);
+        assert_eq!(machine.program_counter, PROGRAM_START + 10);
+        assert_eq!(machine.variable_registers[2], 0);
+    }
+
+    #[test]
+    fn wait_for_key_needs_a_press_and_a_release(){
+        // V3 = wait for key, V4 = 1
+        let mut machine = machine_with(&[0xF3, 0x0A, 0x64, 0x01]);
+
+        run(&mut machine, 3);
+        assert_eq!(machine.program_counter, PROGRAM_START);
+
+        machine.keypad = vec![0x7];
+        run(&mut machine, 3);
+        assert_eq!(machine.program_counter, PROGRAM_START);
+        assert_eq!(machine.key_wait, Some(0x7));
+
+        // other keys going down meanwhile do not count
+        machine.keypad = vec![0x7, 0x2];
+        run(&mut machine, 1);
+        assert_eq!(machine.program_counter, PROGRAM_START);
+
+        machine.keypad = vec![0x2];
+        run(&mut machine, 1);
+        assert_eq!(machine.variable_registers[3], 0x7);
+        assert_eq!(machine.key_wait, None);
+        assert_eq!(machine.program_counter, PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn returning_with_an_empty_stack_is_an_error(){
+        let mut machine = machine_with(&[0x00, 0xEE]);
+        assert!(machine.step().is_err());
+        assert_eq!(machine.program_counter, PROGRAM_START);
+    }
+
+    #[test]
+    fn too_many_nested_calls_is_an_error(){
+        // calls itself forever
+        let mut machine = machine_with(&[0x22, 0x00]);
+        run(&mut machine, STACK_SIZE);
+        assert!(machine.step().is_err());
+        assert_eq!(machine.stack.len(), STACK_SIZE);
+        assert_eq!(machine.program_counter, PROGRAM_START);
+    }
+
+    #[test]
+    fn running_off_the_end_of_memory_is_an_error(){
+        // jump 0xFFE, whose second byte is the last in memory
+        let mut machine = machine_with(&[0x1F, 0xFE]);
+        machine.ram[0xFFE] = 0x1F;
+        machine.ram[0xFFF] = 0xFF;
+        run(&mut machine, 2);
+        assert_eq!(machine.program_counter, 0xFFF);
+        assert!(machine.step().is_err());
+    }
+
+    #[test]
+    fn reading_past_the_end_of_memory_is_an_error(){
+        // I = 0xFFE, draw 5 rows; I = 0xFFE, save V0-V3
+        let mut machine = machine_with(&[0xAF, 0xFE, 0xD0, 0x05]);
+        run(&mut machine, 1);
+        assert!(machine.step().is_err());
+        assert_eq!(machine.program_counter, PROGRAM_START + 2);
+
+        let mut machine = machine_with(&[0xAF, 0xFE, 0xF3, 0x55]);
+        run(&mut machine, 1);
+        assert!(machine.step().is_err());
+        assert_eq!(machine.ram[0xFFE], 0);
+    }
+}
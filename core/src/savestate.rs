@@ -0,0 +1,316 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::framebuffer::Framebuffer;
+use crate::framebuffer::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::machine::{Machine, Quirks, Rng, RAM_SIZE, STACK_SIZE};
+
+pub const STATE_MAGIC: &[u8; 4] = b"C8ST";
+/// Bump whenever the layout below changes; older files are rejected rather than misread.
+pub const STATE_VERSION: u16 = 2;
+
+/*
+ * Layout (big endian):
+ *   magic "C8ST", version u16, ROM hash u64,
+ *   RAM (4096 bytes, includes the index register at 160),
+ *   PC u16, stack length u8 + u16 entries,
+ *   delay timer u8, sound timer u8, V0-VF,
+ *   framebuffer width u16, height u16, pixels packed 8 per byte,
+ *   quirk flags u8, RNG state u32,
+ *   key FX0A is waiting on to be released u8 (0xFF if none)
+ */
+
+/// Largest state `save_state` produces, with a full stack and a 128x64
+/// screen. Hosts that need a fixed size up front pad states to this.
+pub const MAX_STATE_SIZE: usize = 4 + 2 + 8 + RAM_SIZE + 2 + 1 + STACK_SIZE * 2 + 1 + 1 + 16 + 2 + 2 + 128 * 64 / 8 + 1 + 4 + 1;
+
+// the last address an instruction can start at
+const LAST_INSTRUCTION: u16 = RAM_SIZE as u16 - 2;
+
+impl Machine{
+    pub fn save_state(&self) -> Vec<u8>{
+        let mut data = Vec::with_capacity(RAM_SIZE + 512);
+
+        data.extend_from_slice(STATE_MAGIC);
+        data.extend_from_slice(&STATE_VERSION.to_be_bytes());
+        data.extend_from_slice(&self.rom_hash.to_be_bytes());
+
+        data.extend_from_slice(&self.ram);
+        data.extend_from_slice(&self.program_counter.to_be_bytes());
+
+        data.push(self.stack.len() as u8);
+        for address in &self.stack{
+            data.extend_from_slice(&address.to_be_bytes());
+        }
+
+        data.push(self.delay_timer);
+        data.push(self.sound_timer);
+        data.extend_from_slice(&self.variable_registers);
+
+        data.extend_from_slice(&(self.framebuffer.width as u16).to_be_bytes());
+        data.extend_from_slice(&(self.framebuffer.height as u16).to_be_bytes());
+        for chunk in self.framebuffer.pixels.chunks(8){
+            let packed = chunk.iter()
+                .enumerate()
+                .fold(0u8, |byte, (bit, pixel)| byte | (*pixel as u8) << (7 - bit));
+            data.push(packed);
+        }
+
+        data.push(self.quirks.ignore_y_in_8xy_shift_instruction as u8);
+        data.extend_from_slice(&self.rng.state.to_be_bytes());
+        data.push(self.key_wait.unwrap_or(0xFF));
+
+        data
+    }
+
+    /// Restores a state produced by `save_state`. Nothing is modified if the
+    /// data is malformed, from another version or from another ROM. States
+    /// the machine could never have got into, like a program counter past
+    /// the end of memory, count as malformed.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String>{
+        let mut reader = StateReader { data, position: 0 };
+
+        if reader.bytes(4)? != STATE_MAGIC{
+            return Err("Not a save state file.".to_string());
+        }
+
+        let version = reader.u16()?;
+        if version != STATE_VERSION{
+            return Err(format!("Save state version {} is not supported (expected {}).", version, STATE_VERSION));
+        }
+
+        let rom_hash = reader.u64()?;
+        if rom_hash != self.rom_hash{
+            return Err(format!("Save state belongs to ROM {:016x}, not {:016x}.", rom_hash, self.rom_hash));
+        }
+
+        let mut ram = [0; RAM_SIZE];
+        ram.clone_from_slice(reader.bytes(RAM_SIZE)?);
+        let program_counter = reader.u16()?;
+        if program_counter > LAST_INSTRUCTION{
+            return Err(format!("Save state has the program counter at {:#05x}, past the end of memory.", program_counter));
+        }
+
+        let stack_length = reader.u8()? as usize;
+        if stack_length > STACK_SIZE{
+            return Err(format!("Save state has {} nested calls, the stack holds {}.", stack_length, STACK_SIZE));
+        }
+        let mut stack = Vec::with_capacity(stack_length);
+        for _ in 0..stack_length{
+            let return_point = reader.u16()?;
+            if return_point > LAST_INSTRUCTION{
+                return Err(format!("Save state returns to {:#05x}, past the end of memory.", return_point));
+            }
+            stack.push(return_point);
+        }
+
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let mut variable_registers = [0; 16];
+        variable_registers.clone_from_slice(reader.bytes(16)?);
+
+        let width = reader.u16()? as u32;
+        let height = reader.u16()? as u32;
+        let lores = (SCREEN_WIDTH, SCREEN_HEIGHT);
+        let hires = (SCREEN_WIDTH * 2, SCREEN_HEIGHT * 2);
+        if (width, height) != lores && (width, height) != hires{
+            return Err(format!("Save state has a {}x{} screen, not {}x{} or {}x{}.", width, height, lores.0, lores.1, hires.0, hires.1));
+        }
+        let pixel_count = (width * height) as usize;
+        let packed = reader.bytes(pixel_count.div_ceil(8))?;
+        let pixels = (0..pixel_count)
+            .map(|i| packed[i / 8] >> (7 - i % 8) & 1 == 1)
+            .collect();
+
+        let quirks = Quirks { ignore_y_in_8xy_shift_instruction: reader.u8()? != 0 };
+        let rng = Rng { state: reader.u32()? };
+        let key_wait = match reader.u8()? {
+            0xFF => None,
+            key if key <= 0xF => Some(key),
+            key => return Err(format!("Save state waits on key {:#x}, which is not on the keypad.", key)),
+        };
+
+        self.ram = ram;
+        self.program_counter = program_counter;
+        self.stack = stack;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.variable_registers = variable_registers;
+        self.framebuffer = Framebuffer { width, height, pixels };
+        self.quirks = quirks;
+        self.rng = rng;
+        self.key_wait = key_wait;
+        self.screen_changed = true;
+
+        Ok(())
+    }
+}
+
+struct StateReader<'a>{
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a>{
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String>{
+        let end = self.position + length;
+        if end > self.data.len(){
+            return Err("Save state is truncated.".to_string());
+        }
+
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String>{
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String>{
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String>{
+        let mut bytes = [0; 4];
+        bytes.clone_from_slice(self.bytes(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, String>{
+        let mut bytes = [0; 8];
+        bytes.clone_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use alloc::vec;
+
+    use super::*;
+
+    #[cfg(target_arch = "wasm32")]
+    use wasm_bindgen_test::wasm_bindgen_test as test;
+
+    // where the program counter and the stack start in a state
+    const PROGRAM_COUNTER_OFFSET: usize = 4 + 2 + 8 + RAM_SIZE;
+    const STACK_OFFSET: usize = PROGRAM_COUNTER_OFFSET + 2;
+
+    // V0 = random, call 0x206, wait for a key, draw font 0
+    const PROGRAM: [u8; 10] = [0xC0, 0xFF, 0x22, 0x06, 0x00, 0x00, 0xF1, 0x0A, 0xD0, 0x05];
+
+    fn running_machine() -> Machine{
+        let mut machine = Machine::new(Quirks::default(), 7);
+        machine.trace = false;
+        machine.load_rom(&PROGRAM).unwrap();
+        for _ in 0..3{
+            machine.step().unwrap();
+        }
+        machine.keypad = vec![0x9];
+        machine.step().unwrap();
+        machine.delay_timer = 20;
+        machine.sound_timer = 3;
+        machine.framebuffer.set_pixel_at(10, 4, true);
+        machine
+    }
+
+    #[test]
+    fn round_trip(){
+        let machine = running_machine();
+        let state = machine.save_state();
+        assert!(state.len() <= MAX_STATE_SIZE);
+
+        let mut restored = Machine::new(Quirks::default(), 1);
+        restored.trace = false;
+        restored.load_rom(&PROGRAM).unwrap();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.ram, machine.ram);
+        assert_eq!(restored.program_counter, machine.program_counter);
+        assert_eq!(restored.stack, vec![0x204]);
+        assert_eq!(restored.delay_timer, 20);
+        assert_eq!(restored.sound_timer, 3);
+        assert_eq!(restored.variable_registers, machine.variable_registers);
+        assert_eq!(restored.framebuffer.pixels, machine.framebuffer.pixels);
+        assert_eq!(restored.rng.state, machine.rng.state);
+        assert_eq!(restored.key_wait, Some(0x9));
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn round_trip_keeps_running_the_same_way(){
+        let mut machine = running_machine();
+        let mut restored = running_machine();
+        restored.load_state(&machine.save_state()).unwrap();
+
+        // release the key and draw
+        for emulated in [&mut machine, &mut restored]{
+            emulated.keypad = vec![];
+            emulated.step().unwrap();
+            emulated.step().unwrap();
+        }
+        assert_eq!(restored.save_state(), machine.save_state());
+    }
+
+    #[test]
+    fn rejects_states_from_other_roms(){
+        let state = running_machine().save_state();
+        let mut machine = Machine::new(Quirks::default(), 7);
+        machine.load_rom(&[0x12, 0x00]).unwrap();
+        assert!(machine.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_states_without_changing_anything(){
+        let valid = running_machine().save_state();
+
+        let mut not_a_state = valid.clone();
+        not_a_state[0] = b'X';
+
+        let truncated = valid[..valid.len() - 1].to_vec();
+
+        let mut program_counter_past_the_end = valid.clone();
+        program_counter_past_the_end[PROGRAM_COUNTER_OFFSET..STACK_OFFSET].copy_from_slice(&0xFFFu16.to_be_bytes());
+
+        let mut stack_too_deep = valid.clone();
+        stack_too_deep[STACK_OFFSET] = STACK_SIZE as u8 + 1;
+
+        let mut return_past_the_end = valid.clone();
+        return_past_the_end[STACK_OFFSET + 1..STACK_OFFSET + 3].copy_from_slice(&0x1000u16.to_be_bytes());
+
+        // the width follows the stack, the timers and the registers
+        let screen_offset = STACK_OFFSET + 1 + 2 + 2 + 16;
+        let mut odd_screen = valid.clone();
+        odd_screen[screen_offset..screen_offset + 2].copy_from_slice(&200u16.to_be_bytes());
+
+        let mut unknown_key = valid.clone();
+        *unknown_key.last_mut().unwrap() = 0x10;
+
+        for state in [not_a_state, truncated, program_counter_past_the_end, stack_too_deep, return_past_the_end, odd_screen, unknown_key]{
+            let mut machine = running_machine();
+            let before = machine.save_state();
+            assert!(machine.load_state(&state).is_err());
+            assert_eq!(machine.save_state(), before);
+        }
+    }
+
+    #[test]
+    fn accepts_hires_screens(){
+        let mut machine = running_machine();
+        machine.framebuffer = Framebuffer {
+            width: SCREEN_WIDTH * 2,
+            height: SCREEN_HEIGHT * 2,
+            pixels: vec![true; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize],
+        };
+        let state = machine.save_state();
+        assert!(state.len() <= MAX_STATE_SIZE);
+
+        let mut restored = running_machine();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.framebuffer.width, SCREEN_WIDTH * 2);
+        assert!(restored.framebuffer.pixels.iter().all(|pixel| *pixel));
+    }
+}
@@ -0,0 +1,87 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+/// Label names by address, written by the assembler next to the `.ch8` it
+/// produces so the emulator and disassembler can show source-level names.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolMap{
+    pub symbols: BTreeMap<u16, String>,
+}
+
+impl SymbolMap{
+    pub fn new() -> SymbolMap{
+        SymbolMap { symbols: BTreeMap::new() }
+    }
+
+    /// Keeps the first name given to an address when several labels share it.
+    pub fn insert(&mut self, address: u16, name: &str){
+        self.symbols.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    pub fn get(&self, address: u16) -> Option<&str>{
+        self.symbols.get(&address).map(|name| name.as_str())
+    }
+
+    /// Formats an address with its symbol name when one is known, e.g. `0x2A4 (draw_paddle)`.
+    pub fn describe(&self, address: u16) -> String{
+        match self.get(address) {
+            Some(name) => format!("{:#05X} ({})", address, name),
+            None => format!("{:#05X}", address),
+        }
+    }
+
+    pub fn to_text(&self) -> String{
+        self.symbols.iter()
+            .map(|(address, name)| format!("{:#05X} {}\n", address, name))
+            .collect()
+    }
+
+    pub fn parse(text: &str) -> Result<SymbolMap, String>{
+        let mut symbol_map = SymbolMap::new();
+
+        for (line_number, line) in text.lines().enumerate(){
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty(){
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (address, name) = match (parts.next(), parts.next()) {
+                (Some(address), Some(name)) => (address, name),
+                _ => return Err(format!("line {}: expected '<address> <name>'", line_number + 1)),
+            };
+
+            let address = u16::from_str_radix(address.trim_start_matches("0x").trim_start_matches("0X"), 16)
+                .map_err(|_| format!("line {}: invalid address {}", line_number + 1, address))?;
+
+            symbol_map.insert(address, name);
+        }
+
+        Ok(symbol_map)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load(path: &Path) -> Result<SymbolMap, String>{
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read symbol map {}: {}", path.display(), e))?;
+
+        SymbolMap::parse(&text)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save(&self, path: &Path) -> Result<(), String>{
+        fs::write(path, self.to_text())
+            .map_err(|e| format!("Could not write symbol map {}: {}", path.display(), e))
+    }
+
+    /// `roms/game.ch8` keeps its symbols in `roms/game.sym`.
+    #[cfg(feature = "std")]
+    pub fn path_for_rom(rom_path: &Path) -> PathBuf{
+        rom_path.with_extension("sym")
+    }
+}
@@ -0,0 +1,177 @@
+/*
+ * C interface to the CHIP-8 emulator. Link against libchip8_emulator, e.g.
+ *
+ *     Chip8 *chip8 = chip8_create(1);
+ *     chip8_load_rom(chip8, rom, rom_length);
+ *     chip8_set_keys(chip8, 1 << 0x5);
+ *     chip8_run_frame(chip8);
+ *     chip8_destroy(chip8);
+ */
+
+#ifndef CHIP8_H
+#define CHIP8_H
+
+/* Generated from src/ffi.rs by build.rs, do not edit. Refresh with CHIP8_UPDATE_HEADER=1 cargo build. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * An emulated machine. Create one with chip8_create and free it with chip8_destroy.
+ */
+typedef struct Chip8 Chip8;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a machine with no ROM loaded. `seed` drives the random numbers
+ * behind CXNN, so the same seed and inputs always give the same run.
+ */
+struct Chip8 *chip8_create(uint32_t seed);
+
+/**
+ * Frees a machine. Null is ignored.
+ *
+ * # Safety
+ *
+ * `chip8` must be null or come from chip8_create and not be used afterwards.
+ */
+void chip8_destroy(struct Chip8 *chip8);
+
+/**
+ * Powers the machine on with a ROM, keeping the quirks and speed.
+ *
+ * # Safety
+ *
+ * `data` must point to `length` readable bytes.
+ */
+bool chip8_load_rom(struct Chip8 *chip8, const uint8_t *data, uintptr_t length);
+
+/**
+ * Sets how much chip8_run_frame runs: "vip", "schip", "vip-cycles" or a
+ * number of instructions per frame.
+ *
+ * # Safety
+ *
+ * `speed` must be a NUL terminated string.
+ */
+bool chip8_set_speed(struct Chip8 *chip8, const char *speed);
+
+/**
+ * Whether 8XY6 and 8XYE shift VX in place rather than copying VY first.
+ *
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+void chip8_set_shift_quirk(struct Chip8 *chip8, bool ignore_y);
+
+/**
+ * Runs a single instruction. Timers are left alone. Fails when the program
+ * does something the machine cannot, like returning with an empty stack,
+ * and leaves the machine at that instruction.
+ *
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+bool chip8_step(struct Chip8 *chip8);
+
+/**
+ * Runs one 60 Hz frame at the set speed, then ticks the timers. Returns the
+ * number of instructions run, or -1 if one failed as in chip8_step, in
+ * which case the timers are left alone.
+ *
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+int64_t chip8_run_frame(struct Chip8 *chip8);
+
+/**
+ * Sets the keys held on the hex keypad, bit N standing for key N.
+ *
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+void chip8_set_keys(struct Chip8 *chip8, uint16_t mask);
+
+/**
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+uint32_t chip8_framebuffer_width(const struct Chip8 *chip8);
+
+/**
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+uint32_t chip8_framebuffer_height(const struct Chip8 *chip8);
+
+/**
+ * Copies the screen row by row, one byte per pixel (1 lit, 0 dark), and
+ * returns the number of pixels. With a null buffer only the count is
+ * returned; a buffer that is too small gets nothing and 0 is returned.
+ *
+ * # Safety
+ *
+ * `pixels` must be null or point to `length` writable bytes.
+ */
+uintptr_t chip8_read_framebuffer(struct Chip8 *chip8, uint8_t *pixels, uintptr_t length);
+
+/**
+ * Whether the buzzer is sounding, i.e. the sound timer is running.
+ *
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+bool chip8_sound_active(const struct Chip8 *chip8);
+
+/**
+ * A buffer of this size always fits a saved state.
+ */
+uintptr_t chip8_max_state_size(void);
+
+/**
+ * Saves the whole machine into `data` and returns the number of bytes
+ * written, or 0 if the buffer is too small.
+ *
+ * # Safety
+ *
+ * `data` must point to `length` writable bytes.
+ */
+uintptr_t chip8_save_state(struct Chip8 *chip8, uint8_t *data, uintptr_t length);
+
+/**
+ * Restores a state from chip8_save_state. The machine is left as it was
+ * if the state is malformed or belongs to another ROM.
+ *
+ * # Safety
+ *
+ * `data` must point to `length` readable bytes.
+ */
+bool chip8_load_state(struct Chip8 *chip8, const uint8_t *data, uintptr_t length);
+
+/**
+ * Why the last call that failed did so. The string belongs to the machine
+ * and stays valid until the next failure or chip8_destroy.
+ *
+ * # Safety
+ *
+ * `chip8` must come from chip8_create.
+ */
+const char *chip8_last_error(const struct Chip8 *chip8);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CHIP8_H */
@@ -0,0 +1,5 @@
+# The agent plays the left paddle, 1 up and 4 down.
+# VE holds the left player's points in tens and the right player's in units.
+reward = ve / 10 - ve % 10
+done = ve / 10 == 9 || ve % 10 == 9
+actions = none, 1, 4
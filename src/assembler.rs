@@ -0,0 +1,877 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::machine::{PROGRAM_START, RAM_SIZE};
+use crate::symbols::SymbolMap;
+
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+pub struct Assembly{
+    pub rom: Vec<u8>,
+    pub symbols: SymbolMap,
+}
+
+/// Assembles Octo source into a ROM image loaded at 0x200.
+pub fn assemble(source: &str) -> Result<Assembly, String>{
+    let mut assembler = Assembler::new(tokenize(source));
+    assembler.run()?;
+    assembler.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Token{
+    text: String,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token>{
+    let mut tokens = VecDeque::new();
+
+    for (line_number, line) in source.lines().enumerate(){
+        let line = line.split('#').next().unwrap();
+
+        for word in line.split_whitespace(){
+            tokens.push_back(Token { text: word.to_string(), line: line_number + 1 });
+        }
+    }
+
+    tokens
+}
+
+struct Macro{
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+enum FixupKind{
+    // low 12 bits of an opcode, e.g. 1NNN, 2NNN, ANNN, BNNN
+    Address12,
+    // the NNNN word following F000
+    Address16,
+}
+
+struct Fixup{
+    address: u16,
+    label: String,
+    kind: FixupKind,
+    line: usize,
+}
+
+enum Condition{
+    EqualValue(u8, u8),
+    NotEqualValue(u8, u8),
+    EqualRegister(u8, u8),
+    NotEqualRegister(u8, u8),
+    Key(u8),
+    NotKey(u8),
+}
+
+impl Condition{
+    /// Opcode that skips the next instruction when the condition holds.
+    fn skip_if_true(&self) -> u16{
+        match *self {
+            Condition::EqualValue(x, nn) => 0x3000 | (x as u16) << 8 | nn as u16,
+            Condition::NotEqualValue(x, nn) => 0x4000 | (x as u16) << 8 | nn as u16,
+            Condition::EqualRegister(x, y) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::NotEqualRegister(x, y) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::Key(x) => 0xE09E | (x as u16) << 8,
+            Condition::NotKey(x) => 0xE0A1 | (x as u16) << 8,
+        }
+    }
+
+    /// Opcode that skips the next instruction when the condition does not hold,
+    /// which is what `if ... then` compiles to.
+    fn skip_if_false(&self) -> u16{
+        match *self {
+            Condition::EqualValue(x, nn) => Condition::NotEqualValue(x, nn).skip_if_true(),
+            Condition::NotEqualValue(x, nn) => Condition::EqualValue(x, nn).skip_if_true(),
+            Condition::EqualRegister(x, y) => Condition::NotEqualRegister(x, y).skip_if_true(),
+            Condition::NotEqualRegister(x, y) => Condition::EqualRegister(x, y).skip_if_true(),
+            Condition::Key(x) => Condition::NotKey(x).skip_if_true(),
+            Condition::NotKey(x) => Condition::Key(x).skip_if_true(),
+        }
+    }
+}
+
+enum Block{
+    // address of the `jump` that leaves the `if` body when the condition fails
+    If(u16),
+    // address of the `jump` at the end of the `if` body that skips the `else` body
+    Else(u16),
+    Loop{ start: u16, breaks: Vec<u16> },
+}
+
+struct Assembler{
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    address: u16,
+    line: usize,
+    labels: HashMap<String, u16>,
+    label_order: Vec<String>,
+    constants: HashMap<String, u16>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    macro_expansions: usize,
+    entry_point_placed: bool,
+}
+
+impl Assembler{
+    fn new(tokens: VecDeque<Token>) -> Assembler{
+        Assembler {
+            tokens,
+            rom: Vec::new(),
+            address: PROGRAM_START,
+            line: 0,
+            labels: HashMap::new(),
+            label_order: Vec::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            macro_expansions: 0,
+            entry_point_placed: false,
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, String>{
+        Err(format!("line {}: {}", self.line, message))
+    }
+
+    fn next_token(&mut self) -> Result<String, String>{
+        match self.tokens.pop_front() {
+            Some(token) =>{
+                self.line = token.line;
+                Ok(token.text)
+            },
+            None => self.error("unexpected end of input".to_string()),
+        }
+    }
+
+    fn peek_token(&self) -> Option<&str>{
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect_token(&mut self, expected: &str) -> Result<(), String>{
+        let token = self.next_token()?;
+        if token != expected{
+            return self.error(format!("expected '{}' but found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), String>{
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if !self.blocks.is_empty(){
+            return self.error("unterminated 'if', 'else' or 'loop' block".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Assembly, String>{
+        for fixup in std::mem::take(&mut self.fixups){
+            let target = match self.labels.get(&fixup.label) {
+                Some(target) => *target,
+                None => return Err(format!("line {}: undefined label '{}'", fixup.line, fixup.label)),
+            };
+
+            match fixup.kind {
+                FixupKind::Address12 =>{
+                    if target > 0xFFF{
+                        return Err(format!("line {}: label '{}' is out of 12-bit range, use 'i := long'", fixup.line, fixup.label));
+                    }
+                    let opcode = self.read_u16(fixup.address) | target;
+                    self.write_u16(fixup.address, opcode);
+                },
+                FixupKind::Address16 => self.write_u16(fixup.address, target),
+            }
+        }
+
+        let mut symbols = SymbolMap::new();
+        for name in &self.label_order{
+            symbols.insert(self.labels[name], name);
+        }
+
+        Ok(Assembly { rom: self.rom, symbols })
+    }
+
+    fn read_u16(&self, address: u16) -> u16{
+        let offset = (address - PROGRAM_START) as usize;
+        u16::from_be_bytes([self.rom[offset], self.rom[offset + 1]])
+    }
+
+    fn write_u16(&mut self, address: u16, value: u16){
+        let offset = (address - PROGRAM_START) as usize;
+        self.rom[offset..offset + 2].clone_from_slice(&value.to_be_bytes());
+    }
+
+    /// Octo starts executing at `main`. When anything other than `: main` comes
+    /// first in the program, the first two bytes become a jump to it.
+    fn place_entry_point(&mut self, label: Option<&str>){
+        if self.entry_point_placed{
+            return;
+        }
+        self.entry_point_placed = true;
+
+        if label != Some("main"){
+            let _ = self.emit_address_opcode(0x1000, "main".to_string(), FixupKind::Address12);
+        }
+    }
+
+    fn emit_byte(&mut self, value: u8) -> Result<(), String>{
+        self.place_entry_point(None);
+
+        if self.address < PROGRAM_START{
+            return self.error(format!("cannot emit code below {:#05X}", PROGRAM_START));
+        }
+
+        let offset = (self.address - PROGRAM_START) as usize;
+        if offset >= RAM_SIZE - PROGRAM_START as usize{
+            return self.error(format!("the program does not fit in the {} bytes above {:#05X}", RAM_SIZE - PROGRAM_START as usize, PROGRAM_START));
+        }
+        if offset >= self.rom.len(){
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = value;
+        self.address += 1;
+
+        Ok(())
+    }
+
+    fn emit(&mut self, opcode: u16) -> Result<(), String>{
+        let [high, low] = opcode.to_be_bytes();
+        self.emit_byte(high)?;
+        self.emit_byte(low)
+    }
+
+    /// Emits an opcode whose address part may refer to a label defined later.
+    fn emit_address_opcode(&mut self, opcode: u16, label: String, kind: FixupKind) -> Result<(), String>{
+        self.place_entry_point(None);
+        self.fixups.push(Fixup { address: self.address, label, kind, line: self.line });
+        self.emit(opcode)
+    }
+
+    fn patch_jump(&mut self, jump_address: u16) -> Result<(), String>{
+        let opcode = self.jump_to(self.address)?;
+        self.write_u16(jump_address, opcode);
+        Ok(())
+    }
+
+    /// 1NNN to an address known now, which must be within the 12-bit range.
+    fn jump_to(&self, target: u16) -> Result<u16, String>{
+        if target > 0xFFF{
+            return self.error(format!("cannot jump to {:#06X}, it is out of 12-bit range", target));
+        }
+        Ok(0x1000 | target)
+    }
+
+    fn define_label(&mut self, name: String) -> Result<(), String>{
+        self.place_entry_point(Some(&name));
+
+        if self.labels.contains_key(&name){
+            return self.error(format!("label '{}' is already defined", name));
+        }
+        self.labels.insert(name.clone(), self.address);
+        self.label_order.push(name);
+        Ok(())
+    }
+
+    fn parse_number(text: &str) -> Option<i32>{
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+
+        let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")){
+            i32::from_str_radix(hex, 16).ok()?
+        } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")){
+            i32::from_str_radix(binary, 2).ok()?
+        } else{
+            digits.parse::<i32>().ok()?
+        };
+
+        Some(if negative { -value } else { value })
+    }
+
+    /// A value that must be known right now: a number or a `:const`.
+    fn value(&self, text: &str) -> Result<i32, String>{
+        if let Some(value) = Assembler::parse_number(text){
+            return Ok(value);
+        }
+        if let Some(value) = self.constants.get(text){
+            return Ok(*value as i32);
+        }
+        if let Some(value) = self.labels.get(text){
+            return Ok(*value as i32);
+        }
+        self.error(format!("unknown value '{}'", text))
+    }
+
+    fn byte_value(&self, text: &str) -> Result<u8, String>{
+        let value = self.value(text)?;
+        if !(-128..=255).contains(&value){
+            return self.error(format!("value {} does not fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble_value(&self, text: &str) -> Result<u8, String>{
+        let value = self.value(text)?;
+        if !(0..=15).contains(&value){
+            return self.error(format!("value {} does not fit in a nibble", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn register(&self, text: &str) -> Option<u8>{
+        if let Some(register) = self.aliases.get(text){
+            return Some(*register);
+        }
+
+        let lower = text.to_ascii_lowercase();
+        let digit = lower.strip_prefix('v')?;
+        if digit.len() != 1{
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn expect_register(&mut self) -> Result<u8, String>{
+        let token = self.next_token()?;
+        match self.register(&token) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register but found '{}'", token)),
+        }
+    }
+
+    /// Emits `opcode | address`, resolving the address now or once the label is defined.
+    fn emit_with_address(&mut self, opcode: u16, text: String) -> Result<(), String>{
+        if let Some(value) = Assembler::parse_number(&text).or_else(|| self.constants.get(&text).map(|v| *v as i32)){
+            if !(0..=0xFFF).contains(&value){
+                return self.error(format!("address {} does not fit in 12 bits", value));
+            }
+            return self.emit(opcode | value as u16);
+        }
+
+        self.emit_address_opcode(opcode, text, FixupKind::Address12)
+    }
+
+    fn statement(&mut self) -> Result<(), String>{
+        let token = self.next_token()?;
+
+        match token.as_str() {
+            ":" =>{
+                let name = self.next_token()?;
+                self.define_label(name)
+            },
+            ":const" =>{
+                let name = self.next_token()?;
+                let value_token = self.next_token()?;
+                let value = self.value(&value_token)?;
+                self.constants.insert(name, value as u16);
+                Ok(())
+            },
+            ":alias" =>{
+                let name = self.next_token()?;
+                let register = self.expect_register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            },
+            ":macro" => self.define_macro(),
+            ":org" =>{
+                let value_token = self.next_token()?;
+                let address = self.value(&value_token)?;
+                if !(PROGRAM_START as i32..RAM_SIZE as i32).contains(&address){
+                    return self.error(format!("':org {}' is outside {:#05X} to {:#05X}", value_token, PROGRAM_START, RAM_SIZE - 1));
+                }
+                self.address = address as u16;
+                Ok(())
+            },
+            ":byte" =>{
+                let value_token = self.next_token()?;
+                let value = self.byte_value(&value_token)?;
+                self.emit_byte(value)
+            },
+            ":call" =>{
+                let target = self.next_token()?;
+                self.emit_with_address(0x2000, target)
+            },
+            // Octo debugger annotations carry no code.
+            ":breakpoint" =>{
+                self.next_token()?;
+                Ok(())
+            },
+            ":monitor" =>{
+                self.next_token()?;
+                self.next_token()?;
+                Ok(())
+            },
+            "clear" => self.emit(0x00E0),
+            "return" | ";" => self.emit(0x00EE),
+            "exit" => self.emit(0x00FD),
+            "lores" => self.emit(0x00FE),
+            "hires" => self.emit(0x00FF),
+            "scroll-left" => self.emit(0x00FC),
+            "scroll-right" => self.emit(0x00FB),
+            "scroll-down" =>{
+                let amount_token = self.next_token()?;
+                let amount = self.nibble_value(&amount_token)?;
+                self.emit(0x00C0 | amount as u16)
+            },
+            "scroll-up" =>{
+                let amount_token = self.next_token()?;
+                let amount = self.nibble_value(&amount_token)?;
+                self.emit(0x00D0 | amount as u16)
+            },
+            "audio" => self.emit(0xF002),
+            "plane" =>{
+                let plane_token = self.next_token()?;
+                let plane = self.nibble_value(&plane_token)?;
+                self.emit(0xF001 | (plane as u16) << 8)
+            },
+            "jump" =>{
+                let target = self.next_token()?;
+                self.emit_with_address(0x1000, target)
+            },
+            "jump0" =>{
+                let target = self.next_token()?;
+                self.emit_with_address(0xB000, target)
+            },
+            "bcd" => self.register_instruction(0xF033),
+            "saveflags" => self.register_instruction(0xF075),
+            "loadflags" => self.register_instruction(0xF085),
+            "save" => self.save_or_load(0xF055, 0x5002),
+            "load" => self.save_or_load(0xF065, 0x5003),
+            "sprite" =>{
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let height_token = self.next_token()?;
+                let height = self.nibble_value(&height_token)?;
+                self.emit(0xD000 | (x as u16) << 8 | (y as u16) << 4 | height as u16)
+            },
+            "delay" =>{
+                self.expect_token(":=")?;
+                self.register_instruction(0xF015)
+            },
+            "buzzer" =>{
+                self.expect_token(":=")?;
+                self.register_instruction(0xF018)
+            },
+            "pitch" =>{
+                self.expect_token(":=")?;
+                self.register_instruction(0xF03A)
+            },
+            "i" => self.index_statement(),
+            "if" => self.if_statement(),
+            "else" => self.else_statement(),
+            "end" => self.end_statement(),
+            "loop" =>{
+                self.blocks.push(Block::Loop { start: self.address, breaks: Vec::new() });
+                Ok(())
+            },
+            "while" => self.while_statement(),
+            "again" => self.again_statement(),
+            _ =>{
+                if let Some(register) = self.register(&token){
+                    return self.register_statement(register);
+                }
+                if self.macros.contains_key(&token){
+                    return self.expand_macro(&token);
+                }
+                if let Some(value) = Assembler::parse_number(&token).or_else(|| self.constants.get(&token).map(|v| *v as i32)){
+                    if !(-128..=255).contains(&value){
+                        return self.error(format!("value {} does not fit in a byte", value));
+                    }
+                    return self.emit_byte(value as u8);
+                }
+                if token.starts_with(':'){
+                    return self.error(format!("unsupported directive '{}'", token));
+                }
+
+                // Any other bare word calls the label of that name.
+                self.emit_with_address(0x2000, token)
+            },
+        }
+    }
+
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), String>{
+        let x = self.expect_register()?;
+        self.emit(opcode | (x as u16) << 8)
+    }
+
+    fn save_or_load(&mut self, single_opcode: u16, range_opcode: u16) -> Result<(), String>{
+        let x = self.expect_register()?;
+
+        if self.peek_token() == Some("-"){
+            self.next_token()?;
+            let y = self.expect_register()?;
+            return self.emit(range_opcode | (x as u16) << 8 | (y as u16) << 4);
+        }
+
+        self.emit(single_opcode | (x as u16) << 8)
+    }
+
+    fn index_statement(&mut self) -> Result<(), String>{
+        let operator = self.next_token()?;
+
+        match operator.as_str() {
+            ":=" =>{
+                let operand = self.next_token()?;
+                match operand.as_str() {
+                    "hex" => self.register_instruction(0xF029),
+                    "bighex" => self.register_instruction(0xF030),
+                    "long" =>{
+                        let target = self.next_token()?;
+                        self.emit(0xF000)?;
+                        if let Some(value) = Assembler::parse_number(&target).or_else(|| self.constants.get(&target).map(|v| *v as i32)){
+                            if !(0..=0xFFFF).contains(&value){
+                                return self.error(format!("address {} does not fit in 16 bits", value));
+                            }
+                            return self.emit(value as u16);
+                        }
+                        self.emit_address_opcode(0x0000, target, FixupKind::Address16)
+                    },
+                    _ => self.emit_with_address(0xA000, operand),
+                }
+            },
+            "+=" => self.register_instruction(0xF01E),
+            _ => self.error(format!("unknown index operation '{}'", operator)),
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), String>{
+        let operator = self.next_token()?;
+        let operand = self.next_token()?;
+        let vx = (x as u16) << 8;
+
+        if let Some(y) = self.register(&operand){
+            let vy = (y as u16) << 4;
+            let opcode = match operator.as_str() {
+                ":=" => 0x8000,
+                "|=" => 0x8001,
+                "&=" => 0x8002,
+                "^=" => 0x8003,
+                "+=" => 0x8004,
+                "-=" => 0x8005,
+                ">>=" => 0x8006,
+                "=-" => 0x8007,
+                "<<=" => 0x800E,
+                _ => return self.error(format!("unknown register operation '{}'", operator)),
+            };
+            return self.emit(opcode | vx | vy);
+        }
+
+        match (operator.as_str(), operand.as_str()) {
+            (":=", "random") =>{
+                let mask_token = self.next_token()?;
+                let mask = self.byte_value(&mask_token)?;
+                self.emit(0xC000 | vx | mask as u16)
+            },
+            (":=", "delay") => self.emit(0xF007 | vx),
+            (":=", "key") => self.emit(0xF00A | vx),
+            (":=", _) =>{
+                let value = self.byte_value(&operand)?;
+                self.emit(0x6000 | vx | value as u16)
+            },
+            ("+=", _) =>{
+                let value = self.byte_value(&operand)?;
+                self.emit(0x7000 | vx | value as u16)
+            },
+            ("-=", _) =>{
+                let value = self.byte_value(&operand)?;
+                self.emit(0x7000 | vx | value.wrapping_neg() as u16)
+            },
+            _ => self.error(format!("unknown register operation '{} {}'", operator, operand)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String>{
+        let x = self.expect_register()?;
+        let operator = self.next_token()?;
+
+        match operator.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            _ => {},
+        }
+
+        let operand = self.next_token()?;
+        let register = self.register(&operand);
+
+        match (operator.as_str(), register) {
+            ("==", Some(y)) => Ok(Condition::EqualRegister(x, y)),
+            ("!=", Some(y)) => Ok(Condition::NotEqualRegister(x, y)),
+            ("==", None) => Ok(Condition::EqualValue(x, self.byte_value(&operand)?)),
+            ("!=", None) => Ok(Condition::NotEqualValue(x, self.byte_value(&operand)?)),
+            _ => self.error(format!("unsupported comparison '{}'", operator)),
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<(), String>{
+        let condition = self.condition()?;
+        let keyword = self.next_token()?;
+
+        match keyword.as_str() {
+            "then" => self.emit(condition.skip_if_false()),
+            "begin" =>{
+                self.emit(condition.skip_if_true())?;
+                self.blocks.push(Block::If(self.address));
+                self.emit(0x1000)
+            },
+            _ => self.error(format!("expected 'then' or 'begin' but found '{}'", keyword)),
+        }
+    }
+
+    fn else_statement(&mut self) -> Result<(), String>{
+        match self.blocks.pop() {
+            Some(Block::If(skip_jump)) =>{
+                let end_jump = self.address;
+                self.emit(0x1000)?;
+                self.patch_jump(skip_jump)?;
+                self.blocks.push(Block::Else(end_jump));
+                Ok(())
+            },
+            _ => self.error("'else' without a matching 'if ... begin'".to_string()),
+        }
+    }
+
+    fn end_statement(&mut self) -> Result<(), String>{
+        match self.blocks.pop() {
+            Some(Block::If(jump)) | Some(Block::Else(jump)) => self.patch_jump(jump),
+            _ => self.error("'end' without a matching 'if ... begin'".to_string()),
+        }
+    }
+
+    fn while_statement(&mut self) -> Result<(), String>{
+        let condition = self.condition()?;
+        self.emit(condition.skip_if_true())?;
+        let break_jump = self.address;
+        self.emit(0x1000)?;
+
+        match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) {
+            Some(Block::Loop { breaks, .. }) =>{
+                breaks.push(break_jump);
+                Ok(())
+            },
+            _ => self.error("'while' outside of a 'loop'".to_string()),
+        }
+    }
+
+    fn again_statement(&mut self) -> Result<(), String>{
+        match self.blocks.pop() {
+            Some(Block::Loop { start, breaks }) =>{
+                self.emit(self.jump_to(start)?)?;
+                for break_jump in breaks{
+                    self.patch_jump(break_jump)?;
+                }
+                Ok(())
+            },
+            _ => self.error("'again' without a matching 'loop'".to_string()),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), String>{
+        let name = self.next_token()?;
+        let mut parameters = Vec::new();
+
+        loop {
+            let token = self.next_token()?;
+            if token == "{"{
+                break;
+            }
+            parameters.push(token);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = match self.tokens.pop_front() {
+                Some(token) => token,
+                None => return self.error(format!("unterminated macro '{}'", name)),
+            };
+
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" =>{
+                    depth -= 1;
+                    if depth == 0{
+                        break;
+                    }
+                },
+                _ => {},
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name, Macro { parameters, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String>{
+        self.macro_expansions += 1;
+        if self.macro_expansions > MAX_MACRO_EXPANSIONS{
+            return self.error(format!("too many macro expansions while expanding '{}'", name));
+        }
+
+        let parameter_count = self.macros[name].parameters.len();
+        let mut arguments = HashMap::new();
+        for i in 0..parameter_count{
+            let argument = self.next_token()?;
+            arguments.insert(self.macros[name].parameters[i].clone(), argument);
+        }
+
+        let line = self.line;
+        let expansion: Vec<Token> = self.macros[name].body.iter()
+            .map(|token| Token {
+                text: arguments.get(&token.text).cloned().unwrap_or_else(|| token.text.clone()),
+                line,
+            })
+            .collect();
+
+        for token in expansion.into_iter().rev(){
+            self.tokens.push_front(token);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8>{
+        assemble(source).unwrap_or_else(|e| panic!("{}", e)).rom
+    }
+
+    #[test]
+    fn if_then_skips_when_the_condition_fails(){
+        assert_eq!(rom(": main if v0 == 5 then v1 := 2 if v2 key then clear if v3 != v4 then return"), vec![
+            0x40, 0x05, 0x61, 0x02,
+            0xE2, 0xA1, 0x00, 0xE0,
+            0x53, 0x40, 0x00, 0xEE,
+        ]);
+    }
+
+    #[test]
+    fn if_begin_else_end(){
+        let source = "
+            : main
+            if v0 != v1 begin
+                v2 := 1
+            else
+                v2 := 2
+            end";
+        assert_eq!(rom(source), vec![
+            0x90, 0x10, // skip the jump when the condition holds
+            0x12, 0x08, // to the else body
+            0x62, 0x01,
+            0x12, 0x0A, // past the else body
+            0x62, 0x02,
+        ]);
+    }
+
+    #[test]
+    fn loop_while_again(){
+        let source = "
+            : main
+            loop
+                v0 += 1
+                while v0 != 10
+                v1 += 2
+            again";
+        assert_eq!(rom(source), vec![
+            0x70, 0x01,
+            0x40, 0x0A, // skip the break when the condition holds
+            0x12, 0x0A, // out of the loop
+            0x71, 0x02,
+            0x12, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn macros_aliases_and_constants(){
+        let source = "
+            :macro add-both A B { va += A vb += B }
+            :alias counter v3
+            :const TILES 0x300
+            : main
+            add-both 1 2
+            counter := 7
+            counter += counter
+            i := TILES";
+        assert_eq!(rom(source), vec![0x7A, 0x01, 0x7B, 0x02, 0x63, 0x07, 0x83, 0x34, 0xA3, 0x00]);
+    }
+
+    #[test]
+    fn forward_labels_are_fixed_up(){
+        let assembly = assemble(": main jump later i := tile draw i := long tile : later return : draw ; : tile 0xFF").unwrap();
+        assert_eq!(assembly.rom, vec![
+            0x12, 0x0A,
+            0xA2, 0x0E,
+            0x22, 0x0C,
+            0xF0, 0x00, 0x02, 0x0E,
+            0x00, 0xEE,
+            0x00, 0xEE,
+            0xFF,
+        ]);
+        assert_eq!(assembly.symbols.get(0x20A), Some("later"));
+        assert_eq!(assembly.symbols.get(0x20E), Some("tile"));
+    }
+
+    #[test]
+    fn jumps_to_main_when_it_is_not_first(){
+        assert_eq!(rom(": helper return : main helper"), vec![0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+        assert_eq!(rom("0x12 : main clear"), vec![0x12, 0x03, 0x12, 0x00, 0xE0]);
+        assert_eq!(rom(": main clear"), vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn long_addresses_must_fit_in_16_bits(){
+        assert_eq!(rom(": main i := long 0xFFFF"), vec![0xF0, 0x00, 0xFF, 0xFF]);
+        assert!(assemble(": main i := long 0x10000").is_err());
+        assert!(assemble(": main i := long -1").is_err());
+    }
+
+    #[test]
+    fn reports_mistakes(){
+        assert_eq!(assemble(": main jump nowhere").err(), Some("line 1: undefined label 'nowhere'".to_string()));
+        assert!(assemble(": main else").is_err());
+        assert!(assemble(": main if v0 == 1 begin clear").is_err());
+        assert!(assemble(": main while v0 == 1").is_err());
+        assert!(assemble(": main : main").is_err());
+        assert!(assemble(": main v0 := 256").is_err());
+        assert!(assemble(": main jump 0x1000").is_err());
+        assert!(assemble(":macro forever { forever } : main forever").is_err());
+    }
+
+    #[test]
+    fn org_must_stay_inside_program_memory(){
+        assert_eq!(rom(": main :org 0x204 clear"), vec![0x00, 0x00, 0x00, 0x00, 0x00, 0xE0]);
+        assert!(assemble(": main :org 0x1FF clear").err().unwrap().contains(":org 0x1FF"));
+        assert!(assemble(": main :org 0xFFFF clear").is_err());
+        assert!(assemble(": main :org 0x10200 clear").is_err());
+        assert!(assemble(": main :org 0x1000").is_err());
+    }
+
+    #[test]
+    fn programs_past_the_end_of_memory_are_rejected(){
+        let fits = rom(": main :org 0xFFE clear");
+        assert_eq!(fits.len(), RAM_SIZE - PROGRAM_START as usize);
+
+        let error = assemble(": main :org 0xFFE clear clear").err().unwrap();
+        assert!(error.contains("does not fit"), "{}", error);
+        assert!(assemble(": main :org 0xFFF clear").is_err());
+    }
+
+    #[test]
+    fn jumps_out_of_12_bit_range_are_rejected(){
+        let error = assemble(": main :org 0xFFC if v0 == 1 begin end").err().unwrap();
+        assert!(error.contains("out of 12-bit range"), "{}", error);
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+use chip8_emulator::assembler;
+use chip8_emulator::symbols::SymbolMap;
+
+const USAGE: &str = "Usage: chip8-asm <source.8o> [-o <output.ch8>]";
+
+fn main() {
+    let mut source_path: Option<String> = None;
+    let mut output_path: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output_path = Some(args.next().unwrap_or_else(|| exit_with_usage())),
+            "-h" | "--help" =>{
+                println!("{}", USAGE);
+                return;
+            },
+            _ if source_path.is_none() => source_path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    let source_path = source_path.unwrap_or_else(|| exit_with_usage());
+    let output_path = output_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(&source_path).with_extension("ch8"));
+
+    let source = fs::read_to_string(&source_path)
+        .unwrap_or_else(|_| panic!("Could not read source: {}", source_path));
+
+    let assembly = assembler::assemble(&source).unwrap_or_else(|e| {
+        eprintln!("{}: {}", source_path, e);
+        process::exit(1);
+    });
+
+    fs::write(&output_path, &assembly.rom)
+        .unwrap_or_else(|_| panic!("Could not write ROM: {}", output_path.display()));
+
+    let symbol_path = SymbolMap::path_for_rom(&output_path);
+    assembly.symbols.save(&symbol_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    println!("Wrote {} bytes to {} and {} symbols to {}",
+        assembly.rom.len(), output_path.display(), assembly.symbols.symbols.len(), symbol_path.display());
+}
+
+fn exit_with_usage() -> !{
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
@@ -0,0 +1,70 @@
+use std::path::Path;
+use std::{env, fs, process};
+
+use chip8_emulator::disasm::{self, Dialect};
+use chip8_emulator::symbols::SymbolMap;
+
+const USAGE: &str = "Usage: chip8-disasm [--dialect chip8|schip|xochip] [--octo] [--symbols <file.sym>] <rom>";
+
+fn main() {
+    let mut dialect = Dialect::Chip8;
+    let mut octo_syntax = false;
+    let mut file_path: Option<String> = None;
+    let mut symbol_path: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dialect" =>{
+                let name = args.next().unwrap_or_else(|| exit_with_usage());
+                dialect = name.parse().unwrap_or_else(|e: String| {
+                    eprintln!("{}", e);
+                    exit_with_usage()
+                });
+            },
+            "--octo" => octo_syntax = true,
+            "--symbols" => symbol_path = Some(args.next().unwrap_or_else(|| exit_with_usage())),
+            "-h" | "--help" =>{
+                println!("{}", USAGE);
+                return;
+            },
+            _ if file_path.is_none() => file_path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    let file_path = file_path.unwrap_or_else(|| exit_with_usage());
+    let content = fs::read(&file_path)
+        .unwrap_or_else(|_| panic!("Could not read ROM: {}", file_path));
+
+    let mut disassembly = disasm::disassemble(&content, dialect);
+
+    // Pick up the assembler's symbol map automatically when it sits next to the ROM.
+    let default_symbol_path = SymbolMap::path_for_rom(Path::new(&file_path));
+    match symbol_path {
+        Some(path) =>{
+            let symbols = SymbolMap::load(Path::new(&path)).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+            disassembly.apply_symbols(&symbols);
+        },
+        None if default_symbol_path.exists() =>{
+            if let Ok(symbols) = SymbolMap::load(&default_symbol_path){
+                disassembly.apply_symbols(&symbols);
+            }
+        },
+        None => {},
+    }
+
+    if octo_syntax{
+        print!("{}", disassembly.to_octo());
+    } else{
+        print!("{}", disassembly.to_text());
+    }
+}
+
+fn exit_with_usage() -> !{
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
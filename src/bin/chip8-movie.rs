@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::{env, fs, process};
+
+use chip8_emulator::capture::{self, GifRecorder};
+use chip8_emulator::movie::{self, Movie};
+use chip8_emulator::palette::Palette;
+
+const USAGE: &str = "Usage: chip8-movie [--screenshot <file.png>] [--gif <file.gif>] [--scale <n>] [--palette <palette>] <rom> <movie>";
+
+/// Replays a movie without a window and exits non-zero on the first frame
+/// whose state differs from the recording, for use as a regression test.
+/// Optionally captures the final screen as a PNG or the whole replay as a GIF.
+fn main() {
+    let mut positional: Vec<String> = Vec::new();
+    let mut screenshot_path: Option<String> = None;
+    let mut gif_path: Option<String> = None;
+    let mut scale = 10;
+    let mut palette = Palette::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--screenshot" => screenshot_path = Some(args.next().unwrap_or_else(|| exit_with_usage())),
+            "--gif" => gif_path = Some(args.next().unwrap_or_else(|| exit_with_usage())),
+            "--scale" =>{
+                scale = args.next()
+                    .and_then(|scale| scale.parse().ok())
+                    .unwrap_or_else(|| exit_with_usage());
+            },
+            "--palette" =>{
+                let spec = args.next().unwrap_or_else(|| exit_with_usage());
+                palette = Palette::parse(&spec).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    exit_with_usage()
+                });
+            },
+            "-h" | "--help" =>{
+                println!("{}", USAGE);
+                return;
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 2{
+        exit_with_usage();
+    }
+    // fail before replaying rather than after
+    if let Err(e) = capture::capture_size(scale){
+        eprintln!("{}", e);
+        exit_with_usage();
+    }
+
+    let content = fs::read(&positional[0])
+        .unwrap_or_else(|_| panic!("Could not read ROM: {}", positional[0]));
+
+    let recording = Movie::load(Path::new(&positional[1])).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let mut machine = recording.create_machine(&content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let mut gif = gif_path.map(|path| {
+        GifRecorder::create(Path::new(&path), &palette, scale).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        })
+    });
+    let mut capture_error = None;
+
+    let result = movie::replay_with(&mut machine, &recording, |machine| {
+        if let Some(gif) = &mut gif{
+            if let Err(e) = gif.add_frame(&machine.framebuffer){
+                capture_error.get_or_insert(e);
+            }
+        }
+    });
+
+    if let Some(gif) = gif{
+        match gif.finish() {
+            Ok(path) => println!("Saved GIF to {}", path.display()),
+            Err(e) => { capture_error.get_or_insert(e); },
+        }
+    }
+
+    if let Some(path) = screenshot_path{
+        match capture::save_screenshot(Path::new(&path), &machine.framebuffer, &palette, scale) {
+            Ok(()) => println!("Saved screenshot to {}", path),
+            Err(e) => { capture_error.get_or_insert(e); },
+        }
+    }
+
+    if let Some(e) = capture_error{
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    match result {
+        Ok(frames) => println!("Replayed {} frames with no divergence.", frames),
+        Err(divergence) =>{
+            println!("Diverged at frame {}: expected state {:016x}, got {:016x}",
+                divergence.frame, divergence.expected, divergence.actual);
+            process::exit(1);
+        },
+    }
+}
+
+fn exit_with_usage() -> !{
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
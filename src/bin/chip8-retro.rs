@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_uint, c_void, CStr, CString};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Mutex;
+use std::{env, fs, process};
+
+use libloading::{Library, Symbol};
+
+use chip8_emulator::libretro::{
+    RetroAudioSampleBatchFn, RetroEnvironmentFn, RetroGameInfo, RetroInputPollFn, RetroInputStateFn,
+    RetroSystemAvInfo, RetroSystemInfo, RetroVariable, RetroVideoRefreshFn, RETRO_API_VERSION,
+    RETRO_ENVIRONMENT_GET_VARIABLE, RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+    RETRO_ENVIRONMENT_SET_VARIABLES, RETRO_PIXEL_FORMAT_XRGB8888,
+};
+
+const USAGE: &str = "Usage: chip8-retro [--frames <n>] [--screenshot <file.png>] <core> <rom>";
+
+/// What the core has sent back through the callbacks.
+struct Host{
+    // core options at their defaults, kept alive for GET_VARIABLE
+    variables: BTreeMap<String, CString>,
+    frame: Option<(u32, u32, Vec<u32>)>,
+    audio_frames: usize,
+}
+
+static HOST: Mutex<Host> = Mutex::new(Host {
+    variables: BTreeMap::new(),
+    frame: None,
+    audio_frames: 0,
+});
+
+/// A minimal libretro frontend: loads a core, runs a ROM headlessly with no
+/// input and checks that a save state taken halfway replays identically.
+fn main() {
+    let mut positional: Vec<String> = Vec::new();
+    let mut frames: u32 = 600;
+    let mut screenshot_path: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" =>{
+                frames = args.next()
+                    .and_then(|frames| frames.parse().ok())
+                    .unwrap_or_else(|| exit_with_usage());
+            },
+            "--screenshot" => screenshot_path = Some(args.next().unwrap_or_else(|| exit_with_usage())),
+            "-h" | "--help" =>{
+                println!("{}", USAGE);
+                return;
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 2{
+        exit_with_usage();
+    }
+
+    let result = unsafe { run(&positional[0], &positional[1], frames, screenshot_path.as_deref()) };
+    if let Err(e) = result{
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+unsafe fn run(core_path: &str, rom_path: &str, frames: u32, screenshot_path: Option<&str>) -> Result<(), String>{
+    let content = fs::read(rom_path)
+        .map_err(|e| format!("Could not read ROM {}: {}", rom_path, e))?;
+    let library = Library::new(core_path)
+        .map_err(|e| format!("Could not load core {}: {}", core_path, e))?;
+    let core = Core { library: &library };
+
+    let api_version = core.symbol::<unsafe extern "C" fn() -> c_uint>("retro_api_version")?();
+    if api_version != RETRO_API_VERSION{
+        return Err(format!("Core uses libretro API {}, expected {}.", api_version, RETRO_API_VERSION));
+    }
+
+    core.symbol::<unsafe extern "C" fn(RetroEnvironmentFn)>("retro_set_environment")?(environment);
+    core.symbol::<unsafe extern "C" fn(RetroVideoRefreshFn)>("retro_set_video_refresh")?(video_refresh);
+    core.symbol::<unsafe extern "C" fn(RetroAudioSampleBatchFn)>("retro_set_audio_sample_batch")?(audio_sample_batch);
+    core.symbol::<unsafe extern "C" fn(RetroInputPollFn)>("retro_set_input_poll")?(input_poll);
+    core.symbol::<unsafe extern "C" fn(RetroInputStateFn)>("retro_set_input_state")?(input_state);
+    core.symbol::<unsafe extern "C" fn()>("retro_init")?();
+
+    let mut system_info: RetroSystemInfo = std::mem::zeroed();
+    core.symbol::<unsafe extern "C" fn(*mut RetroSystemInfo)>("retro_get_system_info")?(&mut system_info);
+    println!("Loaded {} {}", c_string(system_info.library_name), c_string(system_info.library_version));
+
+    let rom_path_c = CString::new(rom_path).map_err(|e| e.to_string())?;
+    let game = RetroGameInfo {
+        path: rom_path_c.as_ptr(),
+        data: content.as_ptr() as *const c_void,
+        size: content.len(),
+        meta: std::ptr::null(),
+    };
+    if !core.symbol::<unsafe extern "C" fn(*const RetroGameInfo) -> bool>("retro_load_game")?(&game){
+        return Err(format!("The core could not load {}.", rom_path));
+    }
+
+    let mut av_info: RetroSystemAvInfo = std::mem::zeroed();
+    core.symbol::<unsafe extern "C" fn(*mut RetroSystemAvInfo)>("retro_get_system_av_info")?(&mut av_info);
+    println!("{}x{} at {} fps, {} Hz audio", av_info.geometry.base_width, av_info.geometry.base_height,
+        av_info.timing.fps, av_info.timing.sample_rate);
+
+    let retro_run = core.symbol::<unsafe extern "C" fn()>("retro_run")?;
+    let serialize_size = core.symbol::<unsafe extern "C" fn() -> usize>("retro_serialize_size")?();
+    let serialize = core.symbol::<unsafe extern "C" fn(*mut c_void, usize) -> bool>("retro_serialize")?;
+    let unserialize = core.symbol::<unsafe extern "C" fn(*const c_void, usize) -> bool>("retro_unserialize")?;
+
+    let halfway = frames / 2;
+    for _ in 0..halfway{
+        retro_run();
+    }
+
+    let mut state = vec![0u8; serialize_size];
+    if !serialize(state.as_mut_ptr() as *mut c_void, state.len()){
+        return Err("The core could not save its state.".to_string());
+    }
+
+    for _ in halfway..frames{
+        retro_run();
+    }
+    let first_run = last_frame()?;
+
+    if !unserialize(state.as_ptr() as *const c_void, state.len()){
+        return Err("The core could not load its state.".to_string());
+    }
+    for _ in halfway..frames{
+        retro_run();
+    }
+    let second_run = last_frame()?;
+
+    let audio_frames = HOST.lock().unwrap().audio_frames;
+    println!("Ran {} frames, {} audio frames per video frame", frames, audio_frames / (frames + frames - halfway).max(1) as usize);
+
+    if let Some(path) = screenshot_path{
+        save_frame(Path::new(path), &first_run)?;
+        println!("Saved screenshot to {}", path);
+    }
+
+    core.symbol::<unsafe extern "C" fn()>("retro_unload_game")?();
+    core.symbol::<unsafe extern "C" fn()>("retro_deinit")?();
+
+    if first_run != second_run{
+        return Err(format!("Frame {} differs after loading the state saved at frame {}.", frames, halfway));
+    }
+    println!("Save state from frame {} replays identically.", halfway);
+
+    Ok(())
+}
+
+struct Core<'a>{
+    library: &'a Library,
+}
+
+impl<'a> Core<'a>{
+    unsafe fn symbol<T>(&self, name: &str) -> Result<Symbol<'a, T>, String>{
+        self.library.get(name.as_bytes())
+            .map_err(|e| format!("The core has no {}: {}", name, e))
+    }
+}
+
+fn last_frame() -> Result<(u32, u32, Vec<u32>), String>{
+    HOST.lock().unwrap().frame.clone()
+        .ok_or_else(|| "The core never drew a frame.".to_string())
+}
+
+fn save_frame(path: &Path, (width, height, pixels): &(u32, u32, Vec<u32>)) -> Result<(), String>{
+    let file = File::create(path)
+        .map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), *width, *height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let rgb: Vec<u8> = pixels.iter()
+        .flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])
+        .collect();
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(&rgb))
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
+
+unsafe fn c_string(pointer: *const c_char) -> String{
+    if pointer.is_null(){
+        return String::new();
+    }
+    CStr::from_ptr(pointer).to_string_lossy().into_owned()
+}
+
+unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool{
+    match cmd {
+        RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => *(data as *const c_uint) == RETRO_PIXEL_FORMAT_XRGB8888,
+        RETRO_ENVIRONMENT_SET_VARIABLES =>{
+            let mut host = HOST.lock().unwrap();
+            let mut variable = data as *const RetroVariable;
+            while !(*variable).key.is_null() {
+                // "Description; first|second|..." defaults to the first value
+                let description = c_string((*variable).value);
+                let default = description.split_once("; ")
+                    .and_then(|(_, values)| values.split('|').next())
+                    .unwrap_or("");
+                host.variables.insert(c_string((*variable).key), CString::new(default).unwrap_or_default());
+                variable = variable.add(1);
+            }
+            true
+        },
+        RETRO_ENVIRONMENT_GET_VARIABLE =>{
+            let host = HOST.lock().unwrap();
+            let variable = &mut *(data as *mut RetroVariable);
+            match host.variables.get(&c_string(variable.key)) {
+                Some(value) =>{
+                    variable.value = value.as_ptr();
+                    true
+                },
+                None => false,
+            }
+        },
+        RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE =>{
+            *(data as *mut bool) = false;
+            true
+        },
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize){
+    // null means the frame did not change
+    if data.is_null(){
+        return;
+    }
+
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height as usize{
+        let row = (data as *const u8).add(y * pitch) as *const u32;
+        pixels.extend_from_slice(std::slice::from_raw_parts(row, width as usize));
+    }
+    HOST.lock().unwrap().frame = Some((width, height, pixels));
+}
+
+unsafe extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize{
+    HOST.lock().unwrap().audio_frames += frames;
+    frames
+}
+
+unsafe extern "C" fn input_poll(){
+}
+
+unsafe extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, _id: c_uint) -> i16{
+    0
+}
+
+fn exit_with_usage() -> !{
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
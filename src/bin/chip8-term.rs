@@ -0,0 +1,73 @@
+use std::path::Path;
+use std::{env, process};
+
+use chip8_emulator::config::Configuration;
+use chip8_emulator::emulator;
+use chip8_emulator::frontend::Frontend;
+use chip8_emulator::terminal::{TerminalDisplay, TerminalMode};
+
+const USAGE: &str = "Usage: chip8-term [--mode half-block|braille] <rom>";
+
+/// Runs a ROM in the terminal instead of a window, e.g. over SSH. Takes the
+/// same settings as the emulator from the environment or a .env file.
+fn main() {
+    let mut rom_path: Option<String> = None;
+    let mut mode: Option<TerminalMode> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mode" =>{
+                let name = args.next().unwrap_or_else(|| exit_with_usage());
+                mode = Some(name.parse().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    exit_with_usage()
+                }));
+            },
+            "-h" | "--help" =>{
+                println!("{}", USAGE);
+                return;
+            },
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    let Some(rom_path) = rom_path else {
+        exit_with_usage();
+    };
+
+    dotenv::dotenv().ok();
+    let config = envy::from_env::<Configuration>().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        process::exit(1);
+    });
+
+    if let Err(e) = run(&config, &rom_path, mode){
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(config: &Configuration, rom_path: &str, mode: Option<TerminalMode>) -> Result<(), String>{
+    let mode = match mode {
+        Some(mode) => mode,
+        None => config.terminal_mode()?,
+    };
+    let keymap = config.keymap()?;
+    let hotkeys = config.hotkeys(&keymap)?;
+    let palette = config.palette(Path::new(rom_path))?;
+
+    // dropped before any error is printed, so it shows on the normal screen
+    let mut display = TerminalDisplay::new(mode)?;
+    display.set_keymap(&keymap);
+    display.set_hotkeys(&hotkeys);
+    display.set_palette(palette);
+
+    emulator::run(&mut display, config, rom_path)
+}
+
+fn exit_with_usage() -> !{
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
@@ -0,0 +1,214 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::framebuffer::{Framebuffer, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::palette::Palette;
+
+/*
+ * Captures are drawn at the window's current scale, measured against the
+ * 64x32 screen like the window, so a 128x64 screen fills the same image. Pixels
+ * are stored as indexes into the palette, which keeps both formats small.
+ */
+
+/// The largest scale whose image still fits a GIF, which is at most 65535 pixels wide.
+pub const MAX_CAPTURE_SCALE: u32 = u16::MAX as u32 / SCREEN_WIDTH;
+
+/// Image size for a scale.
+pub fn capture_size(scale: u32) -> Result<(u32, u32), String>{
+    if scale > MAX_CAPTURE_SCALE{
+        return Err(format!("Scale {} is too large for a capture, the largest is {}.", scale, MAX_CAPTURE_SCALE));
+    }
+
+    let scale = scale.max(1);
+    Ok((SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale))
+}
+
+/// Palette index of every image pixel, row by row, scaled up from the framebuffer.
+fn palette_indexes(framebuffer: &Framebuffer, width: u32, height: u32) -> Vec<u8>{
+    let mut indexes = Vec::with_capacity((width * height) as usize);
+
+    for y in 0..height{
+        let source_y = y * framebuffer.height / height;
+        for x in 0..width{
+            let source_x = x * framebuffer.width / width;
+            indexes.push(framebuffer.get_pixel_at(source_x, source_y) as u8);
+        }
+    }
+
+    indexes
+}
+
+fn palette_bytes(palette: &Palette) -> Vec<u8>{
+    palette.colours.iter()
+        .flat_map(|colour| [colour.red, colour.green, colour.blue])
+        .collect()
+}
+
+/// First `<stem>-NNN.<extension>` in the directory that does not exist yet.
+pub fn next_capture_path(directory: &Path, stem: &str, extension: &str) -> Result<PathBuf, String>{
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("Could not create {}: {}", directory.display(), e))?;
+
+    (1..10000)
+        .map(|number| directory.join(format!("{}-{:03}.{}", stem, number, extension)))
+        .find(|path| !path.exists())
+        .ok_or_else(|| format!("No free capture names left in {}.", directory.display()))
+}
+
+pub fn save_screenshot(path: &Path, framebuffer: &Framebuffer, palette: &Palette, scale: u32) -> Result<(), String>{
+    let (width, height) = capture_size(scale)?;
+    let file = File::create(path)
+        .map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette_bytes(palette));
+
+    let mut writer = encoder.write_header()
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    writer.write_image_data(&palette_indexes(framebuffer, width, height))
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
+
+/// Records an animated GIF one 60 Hz frame at a time.
+///
+/// GIF delays are in hundredths of a second, so frames get 2 or 1 of them in
+/// turn to average out at 60 Hz. Runs of identical frames are merged into one.
+pub struct GifRecorder{
+    path: PathBuf,
+    encoder: gif::Encoder<BufWriter<File>>,
+    width: u32,
+    height: u32,
+    frames_recorded: u32,
+    // the latest frame is held back until it is known how long it stays on screen
+    pending: Option<(Vec<u8>, u32)>,
+}
+
+impl GifRecorder{
+    pub fn create(path: &Path, palette: &Palette, scale: u32) -> Result<GifRecorder, String>{
+        let (width, height) = capture_size(scale)?;
+        let file = File::create(path)
+            .map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+
+        let mut encoder = gif::Encoder::new(BufWriter::new(file), width as u16, height as u16, &palette_bytes(palette))
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        encoder.set_repeat(gif::Repeat::Infinite)
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+
+        Ok(GifRecorder {
+            path: path.to_path_buf(),
+            encoder,
+            width,
+            height,
+            frames_recorded: 0,
+            pending: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path{
+        &self.path
+    }
+
+    pub fn frames_recorded(&self) -> u32{
+        self.frames_recorded
+    }
+
+    /// Adds the screen as it looks at the end of a frame.
+    pub fn add_frame(&mut self, framebuffer: &Framebuffer) -> Result<(), String>{
+        let indexes = palette_indexes(framebuffer, self.width, self.height);
+        self.frames_recorded += 1;
+
+        match &mut self.pending {
+            Some((pending, frames)) if *pending == indexes =>{
+                *frames += 1;
+                Ok(())
+            },
+            _ =>{
+                let previous = self.pending.replace((indexes, 1));
+                self.write(previous)
+            },
+        }
+    }
+
+    /// Writes the last frame and closes the file.
+    pub fn finish(mut self) -> Result<PathBuf, String>{
+        let pending = self.pending.take();
+        self.write(pending)?;
+
+        Ok(self.path)
+    }
+
+    fn write(&mut self, frame: Option<(Vec<u8>, u32)>) -> Result<(), String>{
+        let Some((indexes, frames)) = frame else {
+            return Ok(());
+        };
+
+        // the frame started `frames` frames before the newest one
+        let end = self.frames_recorded - self.pending.as_ref().map_or(0, |(_, frames)| *frames);
+        let start = end - frames;
+        let delay = centiseconds(end) - centiseconds(start);
+
+        let mut gif_frame = gif::Frame::from_indexed_pixels(self.width as u16, self.height as u16, indexes, None);
+        gif_frame.delay = delay.min(u16::MAX as u32) as u16;
+
+        self.encoder.write_frame(&gif_frame)
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))
+    }
+}
+
+/// When a 60 Hz frame starts, rounded to a hundredth of a second.
+fn centiseconds(frame: u32) -> u32{
+    (frame as u64 * 100 / 60) as u32
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn capture_size_scales_the_low_resolution_screen(){
+        assert_eq!(capture_size(0), Ok((64, 32)));
+        assert_eq!(capture_size(10), Ok((640, 320)));
+        assert_eq!(capture_size(MAX_CAPTURE_SCALE), Ok((65472, 32736)));
+        assert!(capture_size(MAX_CAPTURE_SCALE + 1).is_err());
+        assert!(capture_size(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn captures_too_large_are_rejected_before_creating_the_file(){
+        let path = std::env::temp_dir().join(format!("chip8-capture-test-{}.gif", std::process::id()));
+
+        match GifRecorder::create(&path, &Palette::default(), MAX_CAPTURE_SCALE + 1) {
+            Ok(_) => panic!("a GIF wider than 65535 pixels was accepted"),
+            Err(e) => assert!(e.contains("too large")),
+        }
+        assert!(!path.exists());
+
+        let path = path.with_extension("png");
+        let error = save_screenshot(&path, &Framebuffer::new(), &Palette::default(), u32::MAX).unwrap_err();
+        assert!(error.contains("too large"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn gif_records_identical_frames_as_one(){
+        let path = std::env::temp_dir().join(format!("chip8-capture-test-{}-frames.gif", std::process::id()));
+        let framebuffer = Framebuffer::new();
+
+        let mut recorder = GifRecorder::create(&path, &Palette::default(), 2).unwrap();
+        for _ in 0..3 {
+            recorder.add_frame(&framebuffer).unwrap();
+        }
+        assert_eq!(recorder.frames_recorded(), 3);
+        let saved = recorder.finish().unwrap();
+
+        let mut decoder = gif::DecodeOptions::new().read_info(File::open(&saved).unwrap()).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+        // three frames at 60 Hz are 5 hundredths of a second
+        assert_eq!(decoder.read_next_frame().unwrap().unwrap().delay, 5);
+        assert!(decoder.read_next_frame().unwrap().is_none());
+        fs::remove_file(saved).unwrap();
+    }
+}
@@ -1,11 +1,257 @@
 use serde::Deserialize;
 
+use std::path::Path;
+
+use crate::gamepad::{self, Gamepad};
+use crate::hotkeys::HotkeyBindings;
+use crate::keymap::Keymap;
+use crate::machine::Quirks;
+use crate::palette::Palette;
+use crate::persistence::PhosphorFilter;
+use crate::scheduler::Speed;
+use crate::terminal::TerminalMode;
+use crate::viewport::ScaleMode;
+
 #[derive(Deserialize, Debug)]
 pub struct Configuration{
     #[serde(default = "default_ignore_y_in_8xy_shift_instruction")]
     pub ignore_y_in_8xy_shift_instruction: bool,
+    #[serde(default = "default_save_state_directory")]
+    pub save_state_directory: String,
+    #[serde(default = "default_rewind_capacity")]
+    pub rewind_capacity: usize,
+    #[serde(default = "default_rewind_interval")]
+    pub rewind_interval: u32,
+    /// Records keypad input to this movie file from power-on.
+    #[serde(default)]
+    pub movie_record: Option<String>,
+    /// Replays a movie file instead of reading the keyboard until it runs out.
+    #[serde(default)]
+    pub movie_play: Option<String>,
+    /// Keypad bindings applied over the COSMAC VIP layout, e.g. `5=W|Up,8=S|Down`.
+    #[serde(default)]
+    pub keymap: Option<String>,
+    /// Controller bindings applied over the defaults, e.g. `5=dpup|y,8=dpdown`.
+    /// A `.pad` file next to the ROM is applied after these.
+    #[serde(default)]
+    pub gamepad: Option<String>,
+    #[serde(default = "default_gamepad_deadzone")]
+    pub gamepad_deadzone: i16,
+    /// Hotkey bindings applied over the defaults, e.g. `pause=Space,turbo=LShift,save_state_1=Shift+F1`.
+    #[serde(default)]
+    pub hotkeys: Option<String>,
+    /// Speed multiplier while the turbo hotkey is held.
+    #[serde(default = "default_turbo_speed")]
+    pub turbo_speed: f64,
+    /// `vip`, `schip`, `uncapped`, `vip-cycles` or a number of instructions per 60 Hz frame.
+    /// `vip-cycles` approximates the COSMAC VIP's speed from estimated instruction costs.
+    #[serde(default = "default_instructions_per_frame")]
+    pub instructions_per_frame: String,
+    /// A theme (classic, amber, lcd, octo) or `#background,#foreground[,#plane2,#both]`.
+    /// A `.palette` file next to the ROM takes precedence.
+    #[serde(default = "default_palette")]
+    pub palette: String,
+    /// Frames a switched off pixel takes to fade out; 0 turns the phosphor filter off.
+    #[serde(default)]
+    pub persistence_frames: u32,
+    /// `linear` or `exponential`.
+    #[serde(default = "default_persistence_curve")]
+    pub persistence_curve: String,
+    /// Window pixels per CHIP-8 pixel, measured against the 64x32 screen.
+    #[serde(default = "default_scale")]
+    pub scale: u32,
+    /// `integer` or `aspect`.
+    #[serde(default = "default_scale_mode")]
+    pub scale_mode: String,
+    #[serde(default)]
+    pub fullscreen: bool,
+    /// Where screenshots and GIFs are saved.
+    #[serde(default = "default_capture_directory")]
+    pub capture_directory: String,
+    /// How chip8-term draws the screen: `half-block` or `braille`.
+    #[serde(default = "default_terminal_mode")]
+    pub terminal_mode: String,
+    /// Serves JSON-RPC remote control on 127.0.0.1 at this port, see rpc.rs.
+    #[serde(default)]
+    pub rpc_port: Option<u16>,
+    /// Serves the web dashboard on 127.0.0.1 at this port, see dashboard.rs.
+    #[serde(default)]
+    pub dashboard_port: Option<u16>,
+    /// Hosts a two-player netplay session on this port, waiting for the other player to join.
+    #[serde(default)]
+    pub netplay_host: Option<u16>,
+    /// The address a netplay host listens on: `127.0.0.1` for players on this
+    /// computer only, `0.0.0.0` to let players on the network join.
+    #[serde(default = "default_netplay_bind")]
+    pub netplay_bind: String,
+    /// Joins a netplay session, e.g. `192.168.1.5:7000`.
+    #[serde(default)]
+    pub netplay_connect: Option<String>,
+    /// Frames between pressing a key and it taking effect during netplay, which
+    /// hides the network's latency. Set by the host.
+    #[serde(default = "default_netplay_input_delay")]
+    pub netplay_input_delay: u32,
+    /// Broadcasts the session to spectators on 127.0.0.1 at this port, see spectate.rs.
+    #[serde(default)]
+    pub broadcast_port: Option<u16>,
+    /// Watches a broadcast read-only, e.g. `127.0.0.1:7100`. Needs the same ROM.
+    #[serde(default)]
+    pub spectate: Option<String>,
+}
+
+impl Configuration{
+    pub fn quirks(&self) -> Quirks{
+        Quirks {
+            ignore_y_in_8xy_shift_instruction: self.ignore_y_in_8xy_shift_instruction,
+        }
+    }
+
+    pub fn speed(&self) -> Result<Speed, String>{
+        self.instructions_per_frame.parse()
+    }
+
+    pub fn turbo_speed(&self) -> Result<f64, String>{
+        if !self.turbo_speed.is_finite() || self.turbo_speed <= 0.0{
+            return Err(format!("Invalid turbo speed {}, expected a multiplier above 0.", self.turbo_speed));
+        }
+        Ok(self.turbo_speed)
+    }
+
+    pub fn palette(&self, rom_path: &Path) -> Result<Palette, String>{
+        let rom_palette = Palette::path_for_rom(rom_path);
+        if rom_palette.exists(){
+            return Palette::load(&rom_palette);
+        }
+
+        Palette::parse(&self.palette)
+    }
+
+    pub fn phosphor_filter(&self) -> Result<Option<PhosphorFilter>, String>{
+        if self.persistence_frames == 0{
+            return Ok(None);
+        }
+
+        let curve = self.persistence_curve.parse()?;
+        Ok(Some(PhosphorFilter::new(self.persistence_frames, curve)))
+    }
+
+    pub fn scale_mode(&self) -> Result<ScaleMode, String>{
+        self.scale_mode.parse()
+    }
+
+    pub fn terminal_mode(&self) -> Result<TerminalMode, String>{
+        self.terminal_mode.parse()
+    }
+
+    pub fn keymap(&self) -> Result<Keymap, String>{
+        let mut keymap = Keymap::cosmac_vip();
+        if let Some(spec) = &self.keymap{
+            keymap.apply(spec)?;
+        }
+        Ok(keymap)
+    }
+
+    pub fn hotkeys(&self, keymap: &Keymap) -> Result<HotkeyBindings, String>{
+        let mut hotkeys = HotkeyBindings::standard();
+        if let Some(spec) = &self.hotkeys{
+            hotkeys.apply(spec)?;
+        }
+
+        hotkeys.check_conflicts(keymap)?;
+        Ok(hotkeys)
+    }
+
+    pub fn gamepad(&self, rom_path: &Path) -> Result<Gamepad, String>{
+        let mut bindings = gamepad::default_bindings();
+        if let Some(spec) = &self.gamepad{
+            bindings.apply(spec)?;
+        }
+
+        let rom_bindings = gamepad::path_for_rom(rom_path);
+        if rom_bindings.exists(){
+            gamepad::load_bindings(&mut bindings, &rom_bindings)?;
+        }
+
+        Ok(Gamepad::new(&bindings, self.gamepad_deadzone))
+    }
 }
 
 fn default_ignore_y_in_8xy_shift_instruction() -> bool{
     true
-}
\ No newline at end of file
+}
+
+fn default_save_state_directory() -> String{
+    "states".to_string()
+}
+
+// 30 seconds of rewind at one snapshot per frame
+fn default_rewind_capacity() -> usize{
+    1800
+}
+
+fn default_rewind_interval() -> u32{
+    1
+}
+
+fn default_gamepad_deadzone() -> i16{
+    gamepad::DEFAULT_DEADZONE
+}
+
+fn default_turbo_speed() -> f64{
+    4.0
+}
+
+fn default_instructions_per_frame() -> String{
+    "vip".to_string()
+}
+
+fn default_palette() -> String{
+    "classic".to_string()
+}
+
+fn default_persistence_curve() -> String{
+    "exponential".to_string()
+}
+
+fn default_scale() -> u32{
+    10
+}
+
+fn default_scale_mode() -> String{
+    "integer".to_string()
+}
+
+fn default_capture_directory() -> String{
+    "captures".to_string()
+}
+
+fn default_terminal_mode() -> String{
+    "half-block".to_string()
+}
+
+fn default_netplay_bind() -> String{
+    "127.0.0.1".to_string()
+}
+
+fn default_netplay_input_delay() -> u32{
+    3
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn configuration(variables: &[(&str, &str)]) -> Configuration{
+        envy::from_iter(variables.iter().map(|(name, value)| (name.to_string(), value.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn turbo_speed_must_be_above_zero(){
+        assert_eq!(configuration(&[]).turbo_speed(), Ok(4.0));
+        assert_eq!(configuration(&[("TURBO_SPEED", "2.5")]).turbo_speed(), Ok(2.5));
+        assert!(configuration(&[("TURBO_SPEED", "0")]).turbo_speed().is_err());
+        assert!(configuration(&[("TURBO_SPEED", "-2")]).turbo_speed().is_err());
+        assert!(configuration(&[("TURBO_SPEED", "inf")]).turbo_speed().is_err());
+        assert!(configuration(&[("TURBO_SPEED", "NaN")]).turbo_speed().is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::disasm::{self, Dialect};
+use crate::machine::Machine;
+use crate::net;
+use crate::rpc;
+
+/*
+ * A live view of a running emulator in the browser, for watching headless
+ * runs on build machines. `/` serves the page; `/ws` is a WebSocket that
+ * streams an update whenever something changed:
+ *
+ *     {"width": 64, "height": 32, "pixels": "0011...", "registers": {...}, "trace": ["0x200  CLS", ...]}
+ *
+ * `pixels` is only sent when the screen changed, a '0' or '1' per pixel row
+ * by row; `registers` is as JSON-RPC's read_registers. The browser sends keys
+ * back as {"key": 5, "pressed": true}.
+ */
+
+const PAGE: &str = include_str!("dashboard.html");
+
+// updates a slow browser can fall behind by before it skips ahead
+const UPDATE_BACKLOG: usize = 16;
+// only the last instructions of each update are sent, the browser could not keep up with more
+const TRACE_LINES_PER_UPDATE: usize = 32;
+
+#[derive(Deserialize)]
+struct KeyEvent{
+    key: u8,
+    pressed: bool,
+}
+
+pub struct Dashboard{
+    updates: broadcast::Sender<String>,
+    key_events: Receiver<(u8, bool)>,
+    // set when a browser joins or misses updates, so the next one carries the whole screen
+    needs_full_frame: Arc<AtomicBool>,
+    port: u16,
+}
+
+impl Dashboard{
+    /// Serves the dashboard on 127.0.0.1; port 0 picks a free one.
+    pub fn start(port: u16) -> Result<Dashboard, String>{
+        let (updates, _) = broadcast::channel(UPDATE_BACKLOG);
+        let (key_sender, key_events) = mpsc::channel();
+        let needs_full_frame = Arc::new(AtomicBool::new(true));
+
+        let connection = Connection { updates: updates.clone(), key_sender, needs_full_frame: needs_full_frame.clone() };
+        let port = net::serve_locally(port, "dashboard", move |stream| connection.clone().serve(stream))?;
+
+        Ok(Dashboard { updates, key_events, needs_full_frame, port })
+    }
+
+    pub fn port(&self) -> u16{
+        self.port
+    }
+
+    /// Sends watching browsers what changed since the last call, once a
+    /// frame. Instructions are only logged while someone is watching.
+    pub fn publish(&self, machine: &mut Machine){
+        if self.updates.receiver_count() == 0{
+            machine.instruction_log = None;
+            return;
+        }
+
+        let executed = machine.instruction_log.replace(Vec::new()).unwrap_or_default();
+        let full_frame = self.needs_full_frame.load(Ordering::Relaxed);
+        if executed.is_empty() && !machine.screen_changed && !full_frame{
+            return;
+        }
+
+        let trace: Vec<String> = executed[executed.len().saturating_sub(TRACE_LINES_PER_UPDATE)..].iter()
+            .map(|(address, opcode)| {
+                let instruction = disasm::decode(&opcode.to_be_bytes(), Dialect::Chip8);
+                format!("{:#05X}  {}", address, instruction.to_text(&machine.symbols.symbols))
+            })
+            .collect();
+
+        let framebuffer = &machine.framebuffer;
+        let mut update = json!({
+            "width": framebuffer.width,
+            "height": framebuffer.height,
+            "registers": rpc::registers(machine),
+            "trace": trace,
+        });
+        if machine.screen_changed || full_frame{
+            let pixels: String = framebuffer.pixels.iter().map(|pixel| if *pixel { '1' } else { '0' }).collect();
+            update["pixels"] = pixels.into();
+            self.needs_full_frame.store(false, Ordering::Relaxed);
+        }
+
+        // fails only when the last browser left in the meantime
+        let _ = self.updates.send(update.to_string());
+    }
+
+    /// Keys pressed and released in the browser since the last call.
+    pub fn key_events(&self) -> Vec<(u8, bool)>{
+        self.key_events.try_iter().collect()
+    }
+}
+
+/// What each connection shares with the emulator.
+#[derive(Clone)]
+struct Connection{
+    updates: broadcast::Sender<String>,
+    key_sender: Sender<(u8, bool)>,
+    needs_full_frame: Arc<AtomicBool>,
+}
+
+impl Connection{
+    async fn serve(self, mut stream: TcpStream){
+        let mut request = [0; 1024];
+        let length = match stream.peek(&mut request).await {
+            Ok(length) => length,
+            Err(_) => return,
+        };
+        let request_line = String::from_utf8_lossy(&request[..length]).lines().next().unwrap_or_default().to_string();
+
+        if request_line.starts_with("GET /ws "){
+            self.stream_updates(stream).await;
+        } else{
+            // the request itself is not needed, only read so that closing does not reset the connection
+            let _ = stream.read(&mut request).await;
+            let response = if request_line.starts_with("GET / "){
+                format!("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", PAGE.len(), PAGE)
+            } else{
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        }
+    }
+
+    async fn stream_updates(self, stream: TcpStream){
+        let socket = match tokio_tungstenite::accept_async(stream).await {
+            Ok(socket) => socket,
+            Err(e) => return println!("Dashboard WebSocket handshake failed: {}", e),
+        };
+        let (mut sender, mut receiver) = socket.split();
+        let mut updates = self.updates.subscribe();
+        self.needs_full_frame.store(true, Ordering::Relaxed);
+        // released when the browser goes away, so no key stays stuck down
+        let mut held = Vec::new();
+
+        loop{
+            tokio::select! {
+                update = updates.recv() =>{
+                    match update {
+                        Ok(update) =>{
+                            if sender.send(Message::text(update)).await.is_err(){
+                                break;
+                            }
+                        },
+                        Err(RecvError::Lagged(_)) => self.needs_full_frame.store(true, Ordering::Relaxed),
+                        Err(RecvError::Closed) => break,
+                    }
+                },
+                message = receiver.next() =>{
+                    match message {
+                        Some(Ok(Message::Text(text))) =>{
+                            if let Ok(KeyEvent { key, pressed }) = serde_json::from_str(&text){
+                                if key <= 0xF{
+                                    held.retain(|held| *held != key);
+                                    if pressed{
+                                        held.push(key);
+                                    }
+                                    let _ = self.key_sender.send((key, pressed));
+                                }
+                            }
+                        },
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {},
+                    }
+                },
+            }
+        }
+
+        for key in held{
+            let _ = self.key_sender.send((key, false));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use serde_json::Value;
+
+    use super::*;
+    use crate::machine::Quirks;
+
+    /// A dashboard without a server, and a browser subscribed to it if `watched`.
+    fn dashboard(watched: bool) -> (Dashboard, Option<broadcast::Receiver<String>>){
+        let (updates, _) = broadcast::channel(UPDATE_BACKLOG);
+        let browser = watched.then(|| updates.subscribe());
+        let (_, key_events) = mpsc::channel();
+        let dashboard = Dashboard { updates, key_events, needs_full_frame: Arc::new(AtomicBool::new(true)), port: 0 };
+        (dashboard, browser)
+    }
+
+    fn machine() -> Machine{
+        let mut machine = Machine::new(Quirks::default(), 0);
+        // v0 := 1, forever
+        machine.load_rom(&[0x60, 0x01, 0x12, 0x00]).unwrap();
+        machine.trace = false;
+        machine
+    }
+
+    fn next_update(browser: &mut broadcast::Receiver<String>) -> Option<Value>{
+        browser.try_recv().ok().map(|update| serde_json::from_str(&update).unwrap())
+    }
+
+    #[test]
+    fn sends_the_whole_screen_to_a_browser_that_joins(){
+        let (dashboard, browser) = dashboard(true);
+        let mut browser = browser.unwrap();
+        let mut machine = machine();
+
+        dashboard.publish(&mut machine);
+        let update = next_update(&mut browser).unwrap();
+        assert_eq!(update["pixels"].as_str().unwrap().len(), 64 * 32);
+        assert_eq!(update["width"], 64);
+
+        // the screen is unchanged, only the instructions that ran are sent
+        machine.screen_changed = false;
+        machine.step().unwrap();
+        dashboard.publish(&mut machine);
+        let update = next_update(&mut browser).unwrap();
+        assert!(update.get("pixels").is_none());
+        assert_eq!(update["trace"][0], "0x200  LD V0, 0x01");
+
+        // as when another browser joins
+        dashboard.needs_full_frame.store(true, Ordering::Relaxed);
+        dashboard.publish(&mut machine);
+        assert!(next_update(&mut browser).unwrap().get("pixels").is_some());
+    }
+
+    #[test]
+    fn sends_nothing_when_nothing_changed(){
+        let (dashboard, browser) = dashboard(true);
+        let mut browser = browser.unwrap();
+        let mut machine = machine();
+        dashboard.publish(&mut machine);
+        next_update(&mut browser).unwrap();
+
+        machine.screen_changed = false;
+        dashboard.publish(&mut machine);
+
+        assert_eq!(next_update(&mut browser), None);
+    }
+
+    #[test]
+    fn sends_only_the_last_instructions(){
+        let (dashboard, browser) = dashboard(true);
+        let mut browser = browser.unwrap();
+        let mut machine = machine();
+        dashboard.publish(&mut machine);
+        next_update(&mut browser).unwrap();
+
+        for _ in 0..TRACE_LINES_PER_UPDATE + 9{
+            machine.step().unwrap();
+        }
+        dashboard.publish(&mut machine);
+
+        let update = next_update(&mut browser).unwrap();
+        let trace = update["trace"].as_array().unwrap();
+        assert_eq!(trace.len(), TRACE_LINES_PER_UPDATE);
+        // 41 instructions alternate between the two, so the last one is at 0x200
+        assert!(trace.last().unwrap().as_str().unwrap().starts_with("0x200"));
+    }
+
+    #[test]
+    fn stops_logging_instructions_without_browsers(){
+        let (dashboard, _) = dashboard(false);
+        let mut machine = machine();
+        machine.instruction_log = Some(vec![(0x200, 0x6001)]);
+
+        dashboard.publish(&mut machine);
+
+        assert_eq!(machine.instruction_log, None);
+    }
+}
@@ -0,0 +1,689 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::machine::PROGRAM_START;
+use crate::symbols::SymbolMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect{
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Dialect{
+    pub fn name(&self) -> &'static str{
+        match self {
+            Dialect::Chip8 => "chip8",
+            Dialect::SuperChip => "schip",
+            Dialect::XoChip => "xochip",
+        }
+    }
+
+    fn has_superchip_instructions(&self) -> bool{
+        *self != Dialect::Chip8
+    }
+
+    fn has_xochip_instructions(&self) -> bool{
+        *self == Dialect::XoChip
+    }
+}
+
+impl FromStr for Dialect{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Dialect::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Dialect::SuperChip),
+            "xochip" | "xo-chip" => Ok(Dialect::XoChip),
+            _ => Err(format!("Unknown dialect: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Dialect{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction{
+    // 0NNN
+    System(u16),
+    // 00E0
+    ClearScreen,
+    // 00EE
+    Return,
+    // 1NNN
+    Jump(u16),
+    // 2NNN
+    Call(u16),
+    // 3XNN
+    SkipIfEqualValue(u8, u8),
+    // 4XNN
+    SkipIfNotEqualValue(u8, u8),
+    // 5XY0
+    SkipIfEqualRegister(u8, u8),
+    // 6XNN
+    SetValue(u8, u8),
+    // 7XNN
+    AddValue(u8, u8),
+    // 8XY0
+    SetRegister(u8, u8),
+    // 8XY1
+    Or(u8, u8),
+    // 8XY2
+    And(u8, u8),
+    // 8XY3
+    Xor(u8, u8),
+    // 8XY4
+    AddRegister(u8, u8),
+    // 8XY5
+    Subtract(u8, u8),
+    // 8XY6
+    ShiftRight(u8, u8),
+    // 8XY7
+    SubtractReversed(u8, u8),
+    // 8XYE
+    ShiftLeft(u8, u8),
+    // 9XY0
+    SkipIfNotEqualRegister(u8, u8),
+    // ANNN
+    SetIndex(u16),
+    // BNNN
+    JumpWithOffset(u16),
+    // CXNN
+    Random(u8, u8),
+    // DXYN
+    Draw(u8, u8, u8),
+    // EX9E
+    SkipIfKey(u8),
+    // EXA1
+    SkipIfNotKey(u8),
+    // FX07
+    GetDelayTimer(u8),
+    // FX0A
+    WaitForKey(u8),
+    // FX15
+    SetDelayTimer(u8),
+    // FX18
+    SetSoundTimer(u8),
+    // FX1E
+    AddToIndex(u8),
+    // FX29
+    FontCharacter(u8),
+    // FX33
+    BinaryCodedDecimal(u8),
+    // FX55
+    StoreRegisters(u8),
+    // FX65
+    LoadRegisters(u8),
+
+    // SCHIP 00CN
+    ScrollDown(u8),
+    // SCHIP 00FB
+    ScrollRight,
+    // SCHIP 00FC
+    ScrollLeft,
+    // SCHIP 00FD
+    Exit,
+    // SCHIP 00FE
+    LowResolution,
+    // SCHIP 00FF
+    HighResolution,
+    // SCHIP FX30
+    BigFontCharacter(u8),
+    // SCHIP FX75
+    StoreFlags(u8),
+    // SCHIP FX85
+    LoadFlags(u8),
+
+    // XO-CHIP 00DN
+    ScrollUp(u8),
+    // XO-CHIP 5XY2
+    StoreRange(u8, u8),
+    // XO-CHIP 5XY3
+    LoadRange(u8, u8),
+    // XO-CHIP F000 NNNN
+    SetIndexLong(u16),
+    // XO-CHIP FN01
+    SelectPlane(u8),
+    // XO-CHIP F002
+    LoadAudio,
+    // XO-CHIP FX3A
+    SetPitch(u8),
+
+    Unknown(u16),
+}
+
+/// How execution continues after an instruction, used to separate code from data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow{
+    Continue,
+    Skip,
+    Jump(u16),
+    Call(u16),
+    IndirectJump(u16),
+    Stop,
+}
+
+/// Decodes the instruction at the start of `bytes`. XO-CHIP's `F000 NNNN` reads
+/// two extra bytes, so callers should pass everything up to the end of the ROM.
+pub fn decode(bytes: &[u8], dialect: Dialect) -> Instruction{
+    let first_byte = bytes[0];
+    let second_byte = *bytes.get(1).unwrap_or(&0);
+    let opcode = u16::from_be_bytes([first_byte, second_byte]);
+
+    let nibbles = (
+        first_byte >> 4, first_byte & 0b00001111,
+        second_byte >> 4, second_byte & 0b00001111,
+    );
+
+    let nnn = opcode & 0x0FFF;
+    let nn = second_byte;
+
+    match nibbles {
+        (0, 0, 0xE, 0) => Instruction::ClearScreen,
+        (0, 0, 0xE, 0xE) => Instruction::Return,
+        (0, 0, 0xC, n) if dialect.has_superchip_instructions() => Instruction::ScrollDown(n),
+        (0, 0, 0xD, n) if dialect.has_xochip_instructions() => Instruction::ScrollUp(n),
+        (0, 0, 0xF, 0xB) if dialect.has_superchip_instructions() => Instruction::ScrollRight,
+        (0, 0, 0xF, 0xC) if dialect.has_superchip_instructions() => Instruction::ScrollLeft,
+        (0, 0, 0xF, 0xD) if dialect.has_superchip_instructions() => Instruction::Exit,
+        (0, 0, 0xF, 0xE) if dialect.has_superchip_instructions() => Instruction::LowResolution,
+        (0, 0, 0xF, 0xF) if dialect.has_superchip_instructions() => Instruction::HighResolution,
+        (0, _, _, _) => Instruction::System(nnn),
+        (1, _, _, _) => Instruction::Jump(nnn),
+        (2, _, _, _) => Instruction::Call(nnn),
+        (3, x, _, _) => Instruction::SkipIfEqualValue(x, nn),
+        (4, x, _, _) => Instruction::SkipIfNotEqualValue(x, nn),
+        (5, x, y, 0) => Instruction::SkipIfEqualRegister(x, y),
+        (5, x, y, 2) if dialect.has_xochip_instructions() => Instruction::StoreRange(x, y),
+        (5, x, y, 3) if dialect.has_xochip_instructions() => Instruction::LoadRange(x, y),
+        (6, x, _, _) => Instruction::SetValue(x, nn),
+        (7, x, _, _) => Instruction::AddValue(x, nn),
+        (8, x, y, 0) => Instruction::SetRegister(x, y),
+        (8, x, y, 1) => Instruction::Or(x, y),
+        (8, x, y, 2) => Instruction::And(x, y),
+        (8, x, y, 3) => Instruction::Xor(x, y),
+        (8, x, y, 4) => Instruction::AddRegister(x, y),
+        (8, x, y, 5) => Instruction::Subtract(x, y),
+        (8, x, y, 6) => Instruction::ShiftRight(x, y),
+        (8, x, y, 7) => Instruction::SubtractReversed(x, y),
+        (8, x, y, 0xE) => Instruction::ShiftLeft(x, y),
+        (9, x, y, 0) => Instruction::SkipIfNotEqualRegister(x, y),
+        (0xA, _, _, _) => Instruction::SetIndex(nnn),
+        (0xB, _, _, _) => Instruction::JumpWithOffset(nnn),
+        (0xC, x, _, _) => Instruction::Random(x, nn),
+        (0xD, x, y, n) => Instruction::Draw(x, y, n),
+        (0xE, x, 9, 0xE) => Instruction::SkipIfKey(x),
+        (0xE, x, 0xA, 1) => Instruction::SkipIfNotKey(x),
+        (0xF, 0, 0, 0) if dialect.has_xochip_instructions() && bytes.len() >= 4 =>{
+            Instruction::SetIndexLong(u16::from_be_bytes([bytes[2], bytes[3]]))
+        },
+        (0xF, n, 0, 1) if dialect.has_xochip_instructions() => Instruction::SelectPlane(n),
+        (0xF, 0, 0, 2) if dialect.has_xochip_instructions() => Instruction::LoadAudio,
+        (0xF, x, 0, 7) => Instruction::GetDelayTimer(x),
+        (0xF, x, 0, 0xA) => Instruction::WaitForKey(x),
+        (0xF, x, 1, 5) => Instruction::SetDelayTimer(x),
+        (0xF, x, 1, 8) => Instruction::SetSoundTimer(x),
+        (0xF, x, 1, 0xE) => Instruction::AddToIndex(x),
+        (0xF, x, 2, 9) => Instruction::FontCharacter(x),
+        (0xF, x, 3, 0) if dialect.has_superchip_instructions() => Instruction::BigFontCharacter(x),
+        (0xF, x, 3, 3) => Instruction::BinaryCodedDecimal(x),
+        (0xF, x, 3, 0xA) if dialect.has_xochip_instructions() => Instruction::SetPitch(x),
+        (0xF, x, 5, 5) => Instruction::StoreRegisters(x),
+        (0xF, x, 6, 5) => Instruction::LoadRegisters(x),
+        (0xF, x, 7, 5) if dialect.has_superchip_instructions() => Instruction::StoreFlags(x),
+        (0xF, x, 8, 5) if dialect.has_superchip_instructions() => Instruction::LoadFlags(x),
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+impl Instruction{
+    /// Size of the encoded instruction in bytes.
+    pub fn length(&self) -> u16{
+        match self {
+            Instruction::SetIndexLong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    pub fn flow(&self) -> Flow{
+        match *self {
+            Instruction::Return | Instruction::Exit | Instruction::Unknown(_) => Flow::Stop,
+            Instruction::Jump(address) => Flow::Jump(address),
+            Instruction::Call(address) => Flow::Call(address),
+            Instruction::JumpWithOffset(address) => Flow::IndirectJump(address),
+            Instruction::SkipIfEqualValue(..) |
+            Instruction::SkipIfNotEqualValue(..) |
+            Instruction::SkipIfEqualRegister(..) |
+            Instruction::SkipIfNotEqualRegister(..) |
+            Instruction::SkipIfKey(_) |
+            Instruction::SkipIfNotKey(_) => Flow::Skip,
+            _ => Flow::Continue,
+        }
+    }
+
+    /// Address loaded into the index register, which almost always points at data.
+    pub fn data_reference(&self) -> Option<u16>{
+        match *self {
+            Instruction::SetIndex(address) | Instruction::SetIndexLong(address) => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Classic Cowgod-style mnemonic, e.g. `LD VA, 0x02`.
+    pub fn to_text(&self, labels: &BTreeMap<u16, String>) -> String{
+        let address = |a: u16| format_address(a, labels);
+
+        match *self {
+            Instruction::System(a) => format!("SYS {}", address(a)),
+            Instruction::ClearScreen => "CLS".to_string(),
+            Instruction::Return => "RET".to_string(),
+            Instruction::Jump(a) => format!("JP {}", address(a)),
+            Instruction::Call(a) => format!("CALL {}", address(a)),
+            Instruction::SkipIfEqualValue(x, nn) => format!("SE V{:X}, {:#04X}", x, nn),
+            Instruction::SkipIfNotEqualValue(x, nn) => format!("SNE V{:X}, {:#04X}", x, nn),
+            Instruction::SkipIfEqualRegister(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            Instruction::SetValue(x, nn) => format!("LD V{:X}, {:#04X}", x, nn),
+            Instruction::AddValue(x, nn) => format!("ADD V{:X}, {:#04X}", x, nn),
+            Instruction::SetRegister(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            Instruction::AddRegister(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Instruction::Subtract(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            Instruction::SubtractReversed(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipIfNotEqualRegister(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            Instruction::SetIndex(a) => format!("LD I, {}", address(a)),
+            Instruction::JumpWithOffset(a) => format!("JP V0, {}", address(a)),
+            Instruction::Random(x, nn) => format!("RND V{:X}, {:#04X}", x, nn),
+            Instruction::Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipIfKey(x) => format!("SKP V{:X}", x),
+            Instruction::SkipIfNotKey(x) => format!("SKNP V{:X}", x),
+            Instruction::GetDelayTimer(x) => format!("LD V{:X}, DT", x),
+            Instruction::WaitForKey(x) => format!("LD V{:X}, K", x),
+            Instruction::SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+            Instruction::SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+            Instruction::AddToIndex(x) => format!("ADD I, V{:X}", x),
+            Instruction::FontCharacter(x) => format!("LD F, V{:X}", x),
+            Instruction::BinaryCodedDecimal(x) => format!("LD B, V{:X}", x),
+            Instruction::StoreRegisters(x) => format!("LD [I], V{:X}", x),
+            Instruction::LoadRegisters(x) => format!("LD V{:X}, [I]", x),
+            Instruction::ScrollDown(n) => format!("SCD {}", n),
+            Instruction::ScrollRight => "SCR".to_string(),
+            Instruction::ScrollLeft => "SCL".to_string(),
+            Instruction::Exit => "EXIT".to_string(),
+            Instruction::LowResolution => "LOW".to_string(),
+            Instruction::HighResolution => "HIGH".to_string(),
+            Instruction::BigFontCharacter(x) => format!("LD HF, V{:X}", x),
+            Instruction::StoreFlags(x) => format!("LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => format!("LD V{:X}, R", x),
+            Instruction::ScrollUp(n) => format!("SCU {}", n),
+            Instruction::StoreRange(x, y) => format!("SAVE V{:X}-V{:X}", x, y),
+            Instruction::LoadRange(x, y) => format!("LOAD V{:X}-V{:X}", x, y),
+            Instruction::SetIndexLong(a) => format!("LD I, LONG {}", address(a)),
+            Instruction::SelectPlane(n) => format!("PLANE {}", n),
+            Instruction::LoadAudio => "AUDIO".to_string(),
+            Instruction::SetPitch(x) => format!("PITCH V{:X}", x),
+            Instruction::Unknown(opcode) => format!("DW {:#06X}", opcode),
+        }
+    }
+
+    /// Octo syntax. Skips become `if ... then` with the condition inverted, since
+    /// Octo executes the following statement only when the condition holds.
+    pub fn to_octo(&self, labels: &BTreeMap<u16, String>) -> String{
+        let address = |a: u16| format_address(a, labels);
+
+        match *self {
+            Instruction::System(a) => format!("{:#04X} {:#04X}", (a >> 8) as u8, a as u8),
+            Instruction::ClearScreen => "clear".to_string(),
+            Instruction::Return => "return".to_string(),
+            Instruction::Jump(a) => format!("jump {}", address(a)),
+            Instruction::Call(a) => match labels.get(&a) {
+                Some(label) => label.clone(),
+                None => format!(":call {:#05X}", a),
+            },
+            Instruction::SkipIfEqualValue(x, nn) => format!("if v{:x} != {:#04X} then", x, nn),
+            Instruction::SkipIfNotEqualValue(x, nn) => format!("if v{:x} == {:#04X} then", x, nn),
+            Instruction::SkipIfEqualRegister(x, y) => format!("if v{:x} != v{:x} then", x, y),
+            Instruction::SetValue(x, nn) => format!("v{:x} := {:#04X}", x, nn),
+            Instruction::AddValue(x, nn) => format!("v{:x} += {:#04X}", x, nn),
+            Instruction::SetRegister(x, y) => format!("v{:x} := v{:x}", x, y),
+            Instruction::Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+            Instruction::And(x, y) => format!("v{:x} &= v{:x}", x, y),
+            Instruction::Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+            Instruction::AddRegister(x, y) => format!("v{:x} += v{:x}", x, y),
+            Instruction::Subtract(x, y) => format!("v{:x} -= v{:x}", x, y),
+            Instruction::ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+            Instruction::SubtractReversed(x, y) => format!("v{:x} =- v{:x}", x, y),
+            Instruction::ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+            Instruction::SkipIfNotEqualRegister(x, y) => format!("if v{:x} == v{:x} then", x, y),
+            Instruction::SetIndex(a) => format!("i := {}", address(a)),
+            Instruction::JumpWithOffset(a) => format!("jump0 {}", address(a)),
+            Instruction::Random(x, nn) => format!("v{:x} := random {:#04X}", x, nn),
+            Instruction::Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Instruction::SkipIfKey(x) => format!("if v{:x} -key then", x),
+            Instruction::SkipIfNotKey(x) => format!("if v{:x} key then", x),
+            Instruction::GetDelayTimer(x) => format!("v{:x} := delay", x),
+            Instruction::WaitForKey(x) => format!("v{:x} := key", x),
+            Instruction::SetDelayTimer(x) => format!("delay := v{:x}", x),
+            Instruction::SetSoundTimer(x) => format!("buzzer := v{:x}", x),
+            Instruction::AddToIndex(x) => format!("i += v{:x}", x),
+            Instruction::FontCharacter(x) => format!("i := hex v{:x}", x),
+            Instruction::BinaryCodedDecimal(x) => format!("bcd v{:x}", x),
+            Instruction::StoreRegisters(x) => format!("save v{:x}", x),
+            Instruction::LoadRegisters(x) => format!("load v{:x}", x),
+            Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+            Instruction::ScrollRight => "scroll-right".to_string(),
+            Instruction::ScrollLeft => "scroll-left".to_string(),
+            Instruction::Exit => "exit".to_string(),
+            Instruction::LowResolution => "lores".to_string(),
+            Instruction::HighResolution => "hires".to_string(),
+            Instruction::BigFontCharacter(x) => format!("i := bighex v{:x}", x),
+            Instruction::StoreFlags(x) => format!("saveflags v{:x}", x),
+            Instruction::LoadFlags(x) => format!("loadflags v{:x}", x),
+            Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+            Instruction::StoreRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+            Instruction::LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+            Instruction::SetIndexLong(a) => format!("i := long {}", address(a)),
+            Instruction::SelectPlane(n) => format!("plane {}", n),
+            Instruction::LoadAudio => "audio".to_string(),
+            Instruction::SetPitch(x) => format!("pitch := v{:x}", x),
+            Instruction::Unknown(opcode) => format!("{:#04X} {:#04X}", (opcode >> 8) as u8, opcode as u8),
+        }
+    }
+}
+
+fn format_address(address: u16, labels: &BTreeMap<u16, String>) -> String{
+    match labels.get(&address) {
+        Some(label) => label.clone(),
+        None => format!("{:#05X}", address),
+    }
+}
+
+/// Renders a byte as a row of sprite pixels, e.g. `0xF0` as `####....`.
+pub fn sprite_row(byte: u8) -> String{
+    (0..=7).rev()
+        .map(|bit_shift| if byte >> bit_shift & 1 == 1 { '#' } else { '.' })
+        .collect()
+}
+
+pub struct Disassembly{
+    pub dialect: Dialect,
+    pub origin: u16,
+    pub rom: Vec<u8>,
+    pub instructions: BTreeMap<u16, Instruction>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+/// Walks the ROM from the entry point, following jumps, calls and skips so that
+/// only reachable bytes are treated as instructions. Everything else is data.
+pub fn disassemble(rom: &[u8], dialect: Dialect) -> Disassembly{
+    let origin = PROGRAM_START;
+    let end = origin as usize + rom.len();
+
+    let mut instructions: BTreeMap<u16, Instruction> = BTreeMap::new();
+    let mut code_labels: BTreeMap<u16, &'static str> = BTreeMap::new();
+    let mut data_labels: Vec<u16> = Vec::new();
+
+    let in_rom = |address: u16| (address as usize) >= origin as usize && (address as usize) + 1 < end;
+    let decode_at = |address: u16| decode(&rom[(address - origin) as usize..], dialect);
+
+    let mut pending: VecDeque<u16> = VecDeque::from([origin]);
+
+    while let Some(address) = pending.pop_front() {
+        if !in_rom(address) || instructions.contains_key(&address){
+            continue;
+        }
+
+        let instruction = decode_at(address);
+        if let Instruction::Unknown(_) = instruction{
+            continue;
+        }
+        instructions.insert(address, instruction);
+
+        if let Some(target) = instruction.data_reference(){
+            data_labels.push(target);
+        }
+
+        let next = address + instruction.length();
+
+        match instruction.flow() {
+            Flow::Continue => pending.push_back(next),
+            Flow::Skip =>{
+                pending.push_back(next);
+                if in_rom(next){
+                    pending.push_back(next + decode_at(next).length());
+                }
+            },
+            Flow::Jump(target) =>{
+                code_labels.entry(target).or_insert("label");
+                pending.push_back(target);
+            },
+            Flow::Call(target) =>{
+                code_labels.insert(target, "sub");
+                pending.push_back(target);
+                pending.push_back(next);
+            },
+            Flow::IndirectJump(target) =>{
+                // BNNN jumps into a table whose offset is only known at runtime;
+                // the base is the best guess we have.
+                code_labels.entry(target).or_insert("table");
+                pending.push_back(target);
+            },
+            Flow::Stop => {},
+        }
+    }
+
+    // Only addresses inside the ROM get a label, everything else stays numeric.
+    data_labels.retain(|address| (*address as usize) >= origin as usize && (*address as usize) < end);
+    code_labels.retain(|address, _| in_rom(*address));
+
+    let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+    for address in data_labels{
+        labels.insert(address, format!("data_{:03X}", address));
+    }
+    for (address, prefix) in code_labels{
+        labels.insert(address, format!("{}_{:03X}", prefix, address));
+    }
+    labels.insert(origin, "main".to_string());
+
+    Disassembly{
+        dialect,
+        origin,
+        rom: rom.to_vec(),
+        instructions,
+        labels,
+    }
+}
+
+enum Line<'a>{
+    Code(u16, &'a Instruction),
+    Data(u16, u8),
+}
+
+impl Disassembly{
+    /// Replaces generated label names with names from an assembler symbol map.
+    pub fn apply_symbols(&mut self, symbols: &SymbolMap){
+        let end = self.origin as usize + self.rom.len();
+
+        for (address, name) in &symbols.symbols{
+            if (*address as usize) >= self.origin as usize && (*address as usize) < end{
+                self.labels.retain(|_, label| label != name);
+                self.labels.insert(*address, name.clone());
+            }
+        }
+    }
+
+    fn lines(&self) -> Vec<Line<'_>>{
+        let mut lines = Vec::new();
+        let mut address = self.origin;
+        let end = self.origin as usize + self.rom.len();
+
+        while (address as usize) < end {
+            match self.instructions.get(&address) {
+                Some(instruction) =>{
+                    lines.push(Line::Code(address, instruction));
+                    address += instruction.length();
+                },
+                None =>{
+                    lines.push(Line::Data(address, self.rom[(address - self.origin) as usize]));
+                    address += 1;
+                },
+            }
+        }
+
+        lines
+    }
+
+    /// Labels at the start of a line. One pointing into the middle of an
+    /// instruction, like a jump to an odd address, has nowhere to be defined,
+    /// so references to it stay numeric.
+    fn placed_labels(&self, lines: &[Line]) -> BTreeMap<u16, String>{
+        lines.iter()
+            .filter_map(|line| {
+                let address = match line { Line::Code(a, _) | Line::Data(a, _) => *a };
+                self.labels.get(&address).map(|label| (address, label.clone()))
+            })
+            .collect()
+    }
+
+    fn bytes_at(&self, address: u16, length: u16) -> String{
+        let start = (address - self.origin) as usize;
+        let end = (start + length as usize).min(self.rom.len());
+
+        self.rom[start..end].iter()
+            .map(|b| format!("{:02X}", b))
+            .collect()
+    }
+
+    /// Plain listing with addresses and raw bytes alongside each mnemonic.
+    pub fn to_text(&self) -> String{
+        let mut output = format!("; {} bytes, dialect {}\n", self.rom.len(), self.dialect);
+        let lines = self.lines();
+        let labels = self.placed_labels(&lines);
+
+        for line in lines{
+            let address = match line { Line::Code(a, _) | Line::Data(a, _) => a };
+            if let Some(label) = labels.get(&address){
+                output.push_str(&format!("\n{}:\n", label));
+            }
+
+            match line {
+                Line::Code(address, instruction) =>{
+                    output.push_str(&format!(
+                        "    {:03X}  {:<8}  {}\n",
+                        address,
+                        self.bytes_at(address, instruction.length()),
+                        instruction.to_text(&labels)
+                    ));
+                },
+                Line::Data(address, byte) =>{
+                    output.push_str(&format!(
+                        "    {:03X}  {:<8}  DB {:#04X}    ; {}\n",
+                        address, format!("{:02X}", byte), byte, sprite_row(byte)
+                    ));
+                },
+            }
+        }
+
+        output
+    }
+
+    /// Octo source that reassembles to the same bytes.
+    pub fn to_octo(&self) -> String{
+        let mut output = format!("# {} bytes, dialect {}\n", self.rom.len(), self.dialect);
+        let lines = self.lines();
+        let labels = self.placed_labels(&lines);
+
+        for line in lines{
+            let address = match line { Line::Code(a, _) | Line::Data(a, _) => a };
+            if let Some(label) = labels.get(&address){
+                output.push_str(&format!("\n: {}\n", label));
+            }
+
+            match line {
+                Line::Code(address, instruction) =>{
+                    output.push_str(&format!(
+                        "\t{:<24} # {:03X}\n",
+                        instruction.to_octo(&labels),
+                        address
+                    ));
+                },
+                Line::Data(_, byte) =>{
+                    output.push_str(&format!("\t{:#04X} # {}\n", byte, sprite_row(byte)));
+                },
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::assembler;
+
+    fn reassemble(rom: &[u8], dialect: Dialect) -> Vec<u8>{
+        let source = disassemble(rom, dialect).to_octo();
+        assembler::assemble(&source)
+            .unwrap_or_else(|e| panic!("{}\n{}", e, source))
+            .rom
+    }
+
+    #[test]
+    fn labels_inside_instructions_stay_numeric(){
+        let rom = [
+            0x22, 0x08, // call 0x208
+            0xA2, 0x05, // i := 0x205, the second byte of the next instruction
+            0xD0, 0x11, // sprite v0 v0 1
+            0x12, 0x09, // jump 0x209, the second byte of the subroutine's first instruction
+            0x60, 0x12, // v0 := 0x12, which read from 0x209 is jump 0x200
+            0x00, 0xEE, // return
+        ];
+        let disassembly = disassemble(&rom, Dialect::Chip8);
+        assert!(disassembly.labels.contains_key(&0x205));
+        assert!(disassembly.labels.contains_key(&0x209));
+
+        let source = disassembly.to_octo();
+        assert!(source.contains("i := 0x205"), "{}", source);
+        assert!(source.contains("jump 0x209"), "{}", source);
+        assert!(source.contains(": sub_208"), "{}", source);
+        assert!(disassembly.to_text().contains("JP 0x209"));
+
+        assert_eq!(reassemble(&rom, Dialect::Chip8), rom);
+    }
+
+    #[test]
+    fn data_and_skips_round_trip(){
+        let rom = [
+            0xA2, 0x0A, // i := data_20A
+            0x30, 0x01, // if v0 != 0x01 then
+            0x61, 0x02, //     v1 := 0x02
+            0xE1, 0xA1, // if v1 key then
+            0x00, 0xE0, //     clear
+            0xF0, 0x90, // data
+            0xF0,
+        ];
+        assert_eq!(reassemble(&rom, Dialect::Chip8), rom);
+    }
+
+    #[test]
+    fn bundled_roms_round_trip(){
+        for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/roms")).unwrap(){
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "ch8"){
+                let rom = std::fs::read(&path).unwrap();
+                assert_eq!(reassemble(&rom, Dialect::Chip8), rom, "{}", path.display());
+            }
+        }
+    }
+}
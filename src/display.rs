@@ -1,15 +1,39 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use sdl2::{pixels::Color, video::Window, render::Canvas, Sdl, rect::Rect, event::Event, keyboard::{Keycode, Scancode}};
+use sdl2::{pixels::Color, video::{Window, FullscreenType}, render::Canvas, EventPump, rect::Rect, event::{Event, WindowEvent}, keyboard::{Mod, Scancode}};
+use sdl2::{GameControllerSubsystem, controller::GameController};
 
-pub const SCREEN_WIDTH: u32 = 64;
-pub const SCREEN_HEIGHT: u32 = 32;
-pub const SCALE_FACTOR: u32 = 10;
+use chip8_emulator::frontend::{Frontend, Hotkey};
+use chip8_emulator::framebuffer::{Framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT};
+use chip8_emulator::gamepad::{self, Gamepad, GamepadEvent};
+use chip8_emulator::hotkeys::{self, Action, HotkeyBindings};
+use chip8_emulator::keymap::Keymap;
+use chip8_emulator::palette::{Colour, Palette};
+use chip8_emulator::persistence::PhosphorFilter;
+use chip8_emulator::viewport::{ScaleMode, Viewport};
+
+/// Window pixels per CHIP-8 pixel when the window opens.
+pub const DEFAULT_SCALE: u32 = 10;
+pub const MAX_SCALE: u32 = 40;
 
 pub struct Display{
-    pub sdl_context: Sdl,
     pub canvas: Canvas<Window>,
-    pub pixels: Vec<bool>,
+    palette: Palette,
+    pub scale_mode: ScaleMode,
+    // the window was resized or uncovered and has to be drawn again
+    redraw_requested: bool,
+    event_pump: EventPump,
+    keypad_scancodes: HashMap<Scancode, u8>,
+    // keyed by scancode and whether Shift is part of the binding
+    hotkey_scancodes: HashMap<(Scancode, bool), Action>,
+    held_scancodes: HashSet<Scancode>,
+    // every keypad key held at some point since the last sample, so taps
+    // shorter than a frame still reach the machine
+    keys_since_sample: BTreeSet<u8>,
+    game_controller_subsystem: GameControllerSubsystem,
+    // kept open so SDL keeps sending their events
+    controllers: Vec<GameController>,
+    gamepad: Gamepad,
 }
 
 impl Display{
@@ -17,123 +41,270 @@ impl Display{
         let sdl_context = sdl2::init().unwrap();
         let video_system = sdl_context.video().unwrap();
 
-        let window = video_system.window("Chip-8 Emulator", SCREEN_WIDTH*SCALE_FACTOR, SCREEN_HEIGHT*SCALE_FACTOR)
+        let window = video_system.window("Chip-8 Emulator", SCREEN_WIDTH*DEFAULT_SCALE, SCREEN_HEIGHT*DEFAULT_SCALE)
             .position_centered()
+            .resizable()
             .build()
             .unwrap();
 
         let canvas = window.into_canvas().build().unwrap();
+        let event_pump = sdl_context.event_pump().unwrap();
+        // controllers already plugged in are announced with a device added event
+        let game_controller_subsystem = sdl_context.game_controller().unwrap();
+
+        let keypad_scancodes = resolve_keymap(&Keymap::cosmac_vip())
+            .expect("The default keymap should only use known scancodes.");
+        let hotkey_scancodes = resolve_hotkeys(&HotkeyBindings::standard())
+            .expect("The default hotkeys should only use known scancodes.");
 
         Display { 
-            sdl_context,
             canvas,
-            pixels: vec![false; (SCREEN_WIDTH*SCREEN_HEIGHT) as usize],
+            palette: Palette::default(),
+            scale_mode: ScaleMode::Integer,
+            redraw_requested: false,
+            event_pump,
+            keypad_scancodes,
+            hotkey_scancodes,
+            held_scancodes: HashSet::new(),
+            keys_since_sample: BTreeSet::new(),
+            game_controller_subsystem,
+            controllers: Vec::new(),
+            gamepad: Gamepad::new(&gamepad::default_bindings(), gamepad::DEFAULT_DEADZONE),
         }
     }
 
-    pub fn clear_screen(&mut self){
-        self.canvas.clear();
-        self.canvas.present();
-    }
-
-    fn get_pixels_xy_idx(&self, x: u32, y: u32) -> usize{
-        (y * SCREEN_WIDTH + x) as usize
+    pub fn set_keymap(&mut self, keymap: &Keymap) -> Result<(), String>{
+        self.keypad_scancodes = resolve_keymap(keymap)?;
+        Ok(())
     }
 
-    pub fn get_pixel_at(&self, x: u32, y: u32) -> bool{
-        // println!("({}, {})", x, y);
-        self.pixels[self.get_pixels_xy_idx(x, y)]
+    pub fn set_hotkeys(&mut self, hotkeys: &HotkeyBindings) -> Result<(), String>{
+        self.hotkey_scancodes = resolve_hotkeys(hotkeys)?;
+        Ok(())
     }
 
-    fn set_pixel_at(&mut self, x: u32, y: u32, value: bool){
-        let idx = self.get_pixels_xy_idx(x, y);
-        self.pixels[idx] = value;
+    pub fn set_gamepad(&mut self, gamepad: Gamepad){
+        self.gamepad = gamepad;
     }
 
-    fn translate_point_to_rect(&self, x: u32, y: u32) -> Rect{
-        Rect::new(
-            (x * SCALE_FACTOR) as i32,
-            (y * SCALE_FACTOR) as i32,
-            SCALE_FACTOR,
-            SCALE_FACTOR
-        )
-    }
+    /// Fits a `width` x `height` screen into the window, with black borders
+    /// around it, and fills in every pixel that has a colour.
+    fn draw(&mut self, width: u32, height: u32, pixel_colour: impl Fn(u32, u32) -> Option<Colour>) -> Result<(), String>{
+        let (window_width, window_height) = self.canvas.output_size()?;
 
-    pub fn flip_pixel_on_screen(&mut self, x: u32, y: u32) -> Result<(), String>{
-        let scaled_rect = self.translate_point_to_rect(x, y);
-        // println!("Given coords ({}, {})", x, y);
-        // println!("Center of drawn rect {:?}", scaled_rect.center());
-        let color: Color;
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
 
-        if self.get_pixel_at(x, y){
-            color = Color::BLACK;
-            self.set_pixel_at(x, y, false);
-        } else{
-            color = Color::GREEN;
-            self.set_pixel_at(x, y, true);
+        if width == 0 || height == 0{
+            self.canvas.present();
+            return Ok(());
         }
 
-        self.canvas.set_draw_color(color);
-        self.canvas.fill_rect(scaled_rect)?;
+        let viewport = Viewport::fit(window_width, window_height, width, height, self.scale_mode);
+        self.canvas.set_draw_color(to_sdl_color(self.palette.background()));
+        self.canvas.fill_rect(Rect::new(viewport.x, viewport.y, viewport.width, viewport.height))?;
+
+        for y in 0..height{
+            for x in 0..width{
+                if let Some(colour) = pixel_colour(x, y){
+                    let (left, top, pixel_width, pixel_height) = viewport.pixel_rect(x, y);
+                    self.canvas.set_draw_color(to_sdl_color(colour));
+                    self.canvas.fill_rect(Rect::new(left, top, pixel_width, pixel_height))?;
+                }
+            }
+        }
 
-        // // temp testing
-        // if self.get_pixel_at(x, y){
-        //     self.canvas.set_draw_color(Color::MAGENTA);
-        //     self.canvas.draw_line(scaled_rect.top_left(), scaled_rect.bottom_left())?;
-        // }
         self.canvas.present();
 
         Ok(())
     }
 
-    pub fn tick(&mut self){
-        let mut event_pump = self.sdl_context.event_pump().unwrap();
+    /// The action a key triggers, preferring its Shift binding while Shift is held.
+    fn hotkey_for(&self, scancode: Scancode, keymod: Mod) -> Option<Action>{
+        let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+        shift.then(|| self.hotkey_scancodes.get(&(scancode, true)))
+            .flatten()
+            .or_else(|| self.hotkey_scancodes.get(&(scancode, false)))
+            .copied()
+    }
+
+    /// Keypad keys held right now on the keyboard or any connected controller.
+    fn held_keypad_keys(&self) -> Vec<u8>{
+        self.held_scancodes.iter()
+            .filter_map(|code| self.keypad_scancodes.get(code).copied())
+            .chain(self.gamepad.get_keypad_press())
+            .collect()
+    }
+}
+
+impl Frontend for Display{
+    /// Redraws the whole window from the machine's framebuffer.
+    fn render(&mut self, framebuffer: &Framebuffer) -> Result<(), String>{
+        let foreground = self.palette.foreground();
+
+        self.draw(framebuffer.width, framebuffer.height, |x, y| {
+            framebuffer.get_pixel_at(x, y).then_some(foreground)
+        })
+    }
+
+    /// Redraws the window with fading pixels blended into the background.
+    fn render_with_persistence(&mut self, filter: &PhosphorFilter) -> Result<(), String>{
+        let background = self.palette.background();
+        let foreground = self.palette.foreground();
+
+        self.draw(filter.width(), filter.height(), |x, y| {
+            let intensity = filter.intensity_at(x, y);
+            (intensity > 0.0).then(|| background.blend(foreground, intensity))
+        })
+    }
+
+    /// Drains pending window events, turning the configured hotkeys into actions.
+    fn tick(&mut self) -> Vec<Hotkey>{
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        let mut hotkeys = Vec::new();
         
-        for event in event_pump.poll_iter() {
+        for event in events {
             match event {
-                Event::Quit {..} |
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                Event::KeyDown { scancode: Some(scancode), .. } =>{
+                    self.held_scancodes.insert(scancode);
+                },
+                Event::KeyUp { scancode: Some(scancode), .. } =>{
+                    self.held_scancodes.remove(&scancode);
                 },
                 _ => {}
             }
+
+            match event {
+                Event::Quit {..} =>{
+                    hotkeys.push(Hotkey::Pressed(Action::Quit));
+                },
+                Event::Window { win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed, .. } =>{
+                    self.redraw_requested = true;
+                },
+                Event::KeyDown { scancode: Some(scancode), keymod, repeat: false, .. } =>{
+                    if let Some(action) = self.hotkey_for(scancode, keymod){
+                        hotkeys.push(Hotkey::Pressed(action));
+                    }
+                },
+                Event::KeyUp { scancode: Some(scancode), .. } =>{
+                    // Shift may have been let go first, so release whichever binding was held
+                    for shift in [false, true]{
+                        if let Some(&action) = self.hotkey_scancodes.get(&(scancode, shift)).filter(|action| action.is_held()){
+                            hotkeys.push(Hotkey::Released(action));
+                        }
+                    }
+                },
+                Event::ControllerDeviceAdded { which, .. } =>{
+                    match self.game_controller_subsystem.open(which) {
+                        Ok(controller) =>{
+                            self.report(&format!("Connected controller {}", controller.name()));
+                            self.controllers.push(controller);
+                        },
+                        Err(e) => self.report(&format!("Could not open controller {}: {}", which, e)),
+                    }
+                },
+                Event::ControllerDeviceRemoved { which, .. } =>{
+                    self.controllers.retain(|controller| controller.instance_id() != which);
+                    self.gamepad.handle_event(&GamepadEvent::Removed { controller: which });
+                },
+                Event::ControllerButtonDown { which, button, .. } =>{
+                    self.gamepad.handle_event(&GamepadEvent::Button { controller: which, button: button.string(), pressed: true });
+                },
+                Event::ControllerButtonUp { which, button, .. } =>{
+                    self.gamepad.handle_event(&GamepadEvent::Button { controller: which, button: button.string(), pressed: false });
+                },
+                Event::ControllerAxisMotion { which, axis, value, .. } =>{
+                    self.gamepad.handle_event(&GamepadEvent::Axis { controller: which, axis: axis.string(), value });
+                },
+                _ => {}
+            }
+
+            let held = self.held_keypad_keys();
+            self.keys_since_sample.extend(held);
         }
+
+        hotkeys
     }
 
-    fn get_key_presses(&self) -> Vec<Scancode>{
-        let event_pump = self.sdl_context.event_pump().unwrap();
+    /// Samples the keypad for the next frame: keys held now plus any pressed
+    /// and released since the previous sample.
+    fn get_keypad_press(&mut self) -> Vec<u8>{
+        let mut keys = std::mem::take(&mut self.keys_since_sample);
+        keys.extend(self.held_keypad_keys());
 
-        event_pump.keyboard_state()
-            .pressed_scancodes()
-            .collect()
+        keys.into_iter().collect()
     }
 
-    pub fn get_keypad_press(&self) -> Vec<u8>{
-        let keypad_scancodes: HashMap<Scancode, u8> = HashMap::from([
-            (Scancode::Num0, 0x0),
-            (Scancode::Num1, 0x1),
-            (Scancode::Num2, 0x2),
-            (Scancode::Num3, 0x3),
-            (Scancode::Num4, 0x4), 
-            (Scancode::Num5, 0x5),
-            (Scancode::Num6, 0x6),
-            (Scancode::Num7, 0x7),
-            (Scancode::Num8, 0x8),
-            (Scancode::Num9, 0x9),
-            (Scancode::A, 0xA),
-            (Scancode::B, 0xB),
-            (Scancode::C, 0xC),
-            (Scancode::D, 0xD),
-            (Scancode::E, 0xE),
-            (Scancode::F, 0xF),
-        ]);
-        
-        let scancodes_pressed = self.get_key_presses();
+    fn palette(&self) -> &Palette{
+        &self.palette
+    }
 
-        scancodes_pressed.iter()
-            .filter(|code| keypad_scancodes.contains_key(code))
-            .map(|code| *keypad_scancodes.get(code).unwrap())
-            .collect()
+    fn set_palette(&mut self, palette: Palette){
+        self.palette = palette;
+        self.redraw_requested = true;
+    }
+
+    /// The window scale, measured against the 64x32 screen whatever the
+    /// current resolution.
+    fn scale(&self) -> u32{
+        let (width, height) = self.canvas.window().size();
+        (width / SCREEN_WIDTH).min(height / SCREEN_HEIGHT).max(1)
+    }
+
+    /// Resizes the window to a whole multiple of the 64x32 screen. The same
+    /// window then fits 128x64 at half the scale.
+    fn set_scale(&mut self, scale: u32) -> Result<(), String>{
+        let scale = scale.clamp(1, MAX_SCALE);
+
+        self.canvas.window_mut()
+            .set_size(SCREEN_WIDTH*scale, SCREEN_HEIGHT*scale)
+            .map_err(|e| e.to_string())?;
+        self.redraw_requested = true;
+
+        Ok(())
+    }
+
+    fn is_fullscreen(&self) -> bool{
+        self.canvas.window().fullscreen_state() != FullscreenType::Off
+    }
+
+    fn set_fullscreen(&mut self, fullscreen: bool) -> Result<(), String>{
+        let state = if fullscreen { FullscreenType::Desktop } else { FullscreenType::Off };
+
+        self.canvas.window_mut().set_fullscreen(state)?;
+        self.redraw_requested = true;
+
+        Ok(())
+    }
+
+    /// Whether the window needs drawing again even though the screen did not change.
+    fn take_redraw_request(&mut self) -> bool{
+        std::mem::take(&mut self.redraw_requested)
     }
+}
+
+/// Looks up the SDL scancode behind every host key name in the keymap.
+fn resolve_keymap(keymap: &Keymap) -> Result<HashMap<Scancode, u8>, String>{
+    keymap.bindings()
+        .map(|(name, key)| {
+            Scancode::from_name(name)
+                .map(|scancode| (scancode, key))
+                .ok_or_else(|| format!("Unknown key '{}' bound to keypad key {:X}.", name, key))
+        })
+        .collect()
+}
 
+fn resolve_hotkeys(hotkeys: &HotkeyBindings) -> Result<HashMap<(Scancode, bool), Action>, String>{
+    hotkeys.bindings()
+        .map(|(name, action)| {
+            let (key, shift) = hotkeys::split_shift(name);
+            Scancode::from_name(key)
+                .map(|scancode| ((scancode, shift), action))
+                .ok_or_else(|| format!("Unknown key '{}' bound to the {} hotkey.", name, action))
+        })
+        .collect()
 }
 
+fn to_sdl_color(colour: Colour) -> Color{
+    Color::RGB(colour.red, colour.green, colour.blue)
+}
@@ -0,0 +1,584 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::capture::{self, GifRecorder};
+use crate::config::Configuration;
+use crate::dashboard::Dashboard;
+use crate::frontend::{Frontend, Hotkey};
+use crate::hotkeys::Action;
+use crate::machine::{self, Machine, RAM_SIZE};
+use crate::movie::{self, Movie, MovieFrame, MoviePlayer, MovieRecorder};
+use crate::netplay::{Netplay, Session};
+use crate::rewind::RewindBuffer;
+use crate::rpc::{self, RpcCall, RpcRequest, RpcServer};
+use crate::savestate;
+use crate::scheduler::{FrameScheduler, Speed};
+use crate::spectate::{BroadcastEvent, Broadcaster, Viewer};
+use crate::symbols::SymbolMap;
+use crate::timing::{self, VipTiming};
+
+// instructions run between checks of the clock when uncapped
+const UNCAPPED_BATCH: u32 = 100;
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 8.0;
+
+/// Runs a ROM on any frontend until it asks to quit. This is the emulator
+/// proper: frame pacing, hotkeys, save states, rewind, movies and captures.
+pub fn run(frontend: &mut dyn Frontend, config: &Configuration, file_path: &str) -> Result<(), String>{
+    let mut rom_path = file_path.to_string();
+    let mut content = read_rom(&rom_path)?;
+    let symbols = load_symbols(&rom_path)?;
+
+    let mut phosphor_filter = config.phosphor_filter()?;
+
+    let mut player = config.movie_play.as_ref()
+        .map(|path| Movie::load(Path::new(path)).map(MoviePlayer::new))
+        .transpose()?;
+
+    let mut seed = match &player {
+        Some(player) => player.movie.seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or(1),
+    };
+    let mut quirks = config.quirks();
+    let mut speed_setting = config.speed()?;
+    let turbo_speed = config.turbo_speed()?;
+
+    // viewers take over the broadcaster's machine with the first snapshot
+    let mut viewer = match &config.spectate {
+        Some(_) if config.netplay_host.is_some() || config.netplay_connect.is_some() =>{
+            return Err("Spectating cannot be combined with netplay.".to_string());
+        },
+        Some(_) if player.is_some() || config.movie_record.is_some() =>{
+            return Err("Movies cannot be recorded or played back while spectating.".to_string());
+        },
+        Some(address) =>{
+            frontend.report(&format!("Spectating {}", address));
+            Some(Viewer::join(address)?)
+        },
+        None => None,
+    };
+
+    // both players start from the same machine, the guest taking the host's settings
+    let mut netplay = match (config.netplay_host, &config.netplay_connect) {
+        (Some(_), Some(_)) => return Err("Set either NETPLAY_HOST or NETPLAY_CONNECT, not both.".to_string()),
+        (Some(_), None) | (None, Some(_)) if player.is_some() =>{
+            return Err("Movies cannot be played back during netplay.".to_string());
+        },
+        (Some(port), None) =>{
+            let listener = Netplay::listen(&config.netplay_bind, port)?;
+            frontend.report(&format!("Waiting for the other player on {}:{}", config.netplay_bind, port));
+            let session = Session {
+                rom_hash: machine::rom_hash(&content),
+                seed,
+                quirks,
+                speed: speed_setting,
+                input_delay: config.netplay_input_delay,
+            };
+            Some(Netplay::host(listener, &session)?)
+        },
+        (None, Some(address)) =>{
+            frontend.report(&format!("Joining {}", address));
+            let (netplay, session) = Netplay::join(address, machine::rom_hash(&content))?;
+            seed = session.seed;
+            quirks = session.quirks;
+            speed_setting = session.speed;
+            Some(netplay)
+        },
+        (None, None) => None,
+    };
+    if let Some(netplay) = &netplay{
+        frontend.report(&format!("Netplay started as player {:?} with {} frames of input delay", netplay.player, netplay.input_delay));
+    }
+
+    let mut machine = match &player {
+        Some(player) => player.movie.create_machine(&content)?,
+        None =>{
+            let mut machine = Machine::new(quirks, seed);
+            machine.load_rom(&content)?;
+            machine
+        },
+    };
+    machine.symbols = symbols;
+    machine.trace = frontend.allows_instruction_trace();
+
+    let mut recording = config.movie_record.as_ref()
+        .map(|path| MovieRecorder::create(Path::new(path), Movie::new(machine.rom_hash, machine.quirks, seed)))
+        .transpose()?;
+    // Jumping around in time would make the recorded inputs meaningless.
+    let movie_active = recording.is_some() || player.is_some();
+
+    let save_state_directory = Path::new(&config.save_state_directory);
+    let capture_directory = Path::new(&config.capture_directory);
+    let mut capture_stem = capture_stem(&rom_path);
+    let mut gif_recorder: Option<GifRecorder> = None;
+    let mut rewind_buffer = RewindBuffer::new(config.rewind_capacity, config.rewind_interval);
+    let mut rewinding = false;
+    let mut paused = false;
+    // frames still to run while paused
+    let mut frames_to_advance: u32 = 0;
+    // step calls answered once frames_to_advance runs out
+    let mut step_requests: Vec<RpcRequest> = Vec::new();
+    // keys held over JSON-RPC or in the dashboard, on top of the frontend's
+    let mut remote_keys: Vec<u8> = Vec::new();
+    let mut turbo = false;
+    let mut speed: f64 = 1.0;
+
+    frontend.report(&format!("Running at {}", speed_setting));
+    let mut scheduler = FrameScheduler::new();
+    let mut vip_timing = VipTiming::new();
+
+    let rpc_server = config.rpc_port.map(RpcServer::start).transpose()?;
+    if let Some(server) = &rpc_server{
+        frontend.report(&format!("JSON-RPC server listening on 127.0.0.1:{}", server.port()));
+    }
+    let dashboard = config.dashboard_port.map(Dashboard::start).transpose()?;
+    if let Some(dashboard) = &dashboard{
+        frontend.report(&format!("Dashboard at http://127.0.0.1:{}/", dashboard.port()));
+    }
+    let mut broadcaster = config.broadcast_port.map(Broadcaster::start).transpose()?;
+    if let Some(broadcaster) = &broadcaster{
+        frontend.report(&format!("Broadcasting to spectators on 127.0.0.1:{}", broadcaster.port()));
+    }
+
+    loop{
+        let mut quit = false;
+        for hotkey in frontend.tick(){
+            match hotkey {
+                Hotkey::Pressed(Action::SaveState(slot)) =>{
+                    match savestate::save_to_slot(&machine, save_state_directory, slot) {
+                        Ok(path) => frontend.report(&format!("Saved state to {}", path.display())),
+                        Err(e) => frontend.report(&format!("Could not save state to slot {}: {}", slot, e)),
+                    }
+                },
+                Hotkey::Pressed(Action::LoadState(_)) | Hotkey::Pressed(Action::Rewind) | Hotkey::Pressed(Action::Reset)
+                | Hotkey::Pressed(Action::Pause) | Hotkey::Pressed(Action::FrameAdvance) if netplay.is_some() || viewer.is_some() =>{
+                    frontend.report("Loading states, rewinding, resetting and pausing are disabled during netplay and while spectating.");
+                },
+                Hotkey::Pressed(Action::LoadState(_)) | Hotkey::Pressed(Action::Rewind) | Hotkey::Pressed(Action::Reset) if movie_active =>{
+                    frontend.report("Loading states, rewinding and resetting are disabled while a movie is recording or playing.");
+                },
+                Hotkey::Pressed(Action::LoadState(slot)) =>{
+                    match savestate::load_from_slot(&mut machine, save_state_directory, slot) {
+                        Ok(path) => frontend.report(&format!("Loaded state from {}", path.display())),
+                        Err(e) => frontend.report(&format!("Could not load state from slot {}: {}", slot, e)),
+                    }
+                },
+                Hotkey::Pressed(Action::Rewind) =>{
+                    rewinding = true;
+                },
+                Hotkey::Released(Action::Rewind) =>{
+                    rewinding = false;
+                },
+                Hotkey::Pressed(Action::Quit) =>{
+                    quit = true;
+                },
+                Hotkey::Pressed(Action::Pause) =>{
+                    paused = !paused;
+                    frontend.report(if paused { "Paused" } else { "Resumed" });
+                },
+                Hotkey::Pressed(Action::FrameAdvance) =>{
+                    if paused{
+                        frames_to_advance = frames_to_advance.saturating_add(1);
+                    }
+                },
+                Hotkey::Pressed(Action::Reset) =>{
+                    let symbols = std::mem::take(&mut machine.symbols);
+                    machine = Machine::new(machine.quirks, seed);
+                    machine.load_rom(&content)?;
+                    machine.symbols = symbols;
+                    machine.trace = frontend.allows_instruction_trace();
+                    vip_timing = VipTiming::new();
+                    frontend.report(&format!("Reset {}", rom_path));
+                },
+                Hotkey::Pressed(Action::Turbo) =>{
+                    turbo = true;
+                },
+                Hotkey::Released(Action::Turbo) =>{
+                    turbo = false;
+                },
+                Hotkey::Pressed(Action::SpeedUp) =>{
+                    speed = (speed * 2.0).min(MAX_SPEED);
+                    frontend.report(&format!("Speed x{}", speed));
+                },
+                Hotkey::Pressed(Action::SpeedDown) =>{
+                    speed = (speed / 2.0).max(MIN_SPEED);
+                    frontend.report(&format!("Speed x{}", speed));
+                },
+                Hotkey::Pressed(Action::NextPalette) =>{
+                    let palette = frontend.palette().next_theme();
+                    frontend.report(&format!("Palette {}", palette.name));
+                    frontend.set_palette(palette);
+                    machine.screen_changed = true;
+                },
+                Hotkey::Pressed(Action::ToggleFullscreen) =>{
+                    let fullscreen = !frontend.is_fullscreen();
+                    if let Err(e) = frontend.set_fullscreen(fullscreen){
+                        frontend.report(&format!("Could not change fullscreen: {}", e));
+                    }
+                },
+                Hotkey::Pressed(Action::ScaleUp) | Hotkey::Pressed(Action::ScaleDown) if frontend.is_fullscreen() =>{
+                    frontend.report("The scale follows the screen size in fullscreen.");
+                },
+                Hotkey::Pressed(Action::ScaleUp) =>{
+                    let scale = frontend.scale() + 1;
+                    match frontend.set_scale(scale) {
+                        Ok(()) => frontend.report(&format!("Scale x{}", frontend.scale())),
+                        Err(e) => frontend.report(&format!("Could not resize the window: {}", e)),
+                    }
+                },
+                Hotkey::Pressed(Action::ScaleDown) =>{
+                    let scale = frontend.scale().saturating_sub(1);
+                    match frontend.set_scale(scale) {
+                        Ok(()) => frontend.report(&format!("Scale x{}", frontend.scale())),
+                        Err(e) => frontend.report(&format!("Could not resize the window: {}", e)),
+                    }
+                },
+                Hotkey::Pressed(Action::Screenshot) =>{
+                    let result = capture::next_capture_path(capture_directory, &capture_stem, "png")
+                        .and_then(|path| {
+                            capture::save_screenshot(&path, &machine.framebuffer, frontend.palette(), frontend.scale())?;
+                            Ok(path)
+                        });
+                    match result {
+                        Ok(path) => frontend.report(&format!("Saved screenshot to {}", path.display())),
+                        Err(e) => frontend.report(&format!("Could not save screenshot: {}", e)),
+                    }
+                },
+                Hotkey::Pressed(Action::RecordGif) =>{
+                    match gif_recorder.take() {
+                        Some(recorder) => finish_gif(frontend, recorder),
+                        None =>{
+                            let result = capture::next_capture_path(capture_directory, &capture_stem, "gif")
+                                .and_then(|path| GifRecorder::create(&path, frontend.palette(), frontend.scale()));
+                            match result {
+                                Ok(recorder) =>{
+                                    frontend.report(&format!("Recording GIF to {}", recorder.path().display()));
+                                    gif_recorder = Some(recorder);
+                                },
+                                Err(e) => frontend.report(&format!("Could not start recording a GIF: {}", e)),
+                            }
+                        },
+                    }
+                },
+                Hotkey::Released(_) => {},
+            }
+        }
+
+        for request in rpc_server.iter().flat_map(|server| server.pending()){
+            let result = match &request.call {
+                RpcCall::LoadRom { .. } | RpcCall::LoadState { .. } | RpcCall::Pause | RpcCall::Step { .. } if netplay.is_some() || viewer.is_some() =>{
+                    Err("Loading ROMs and states, pausing and stepping are disabled during netplay and while spectating.".to_string())
+                },
+                RpcCall::LoadRom { .. } | RpcCall::LoadState { .. } if movie_active =>{
+                    Err("Loading ROMs and states is disabled while a movie is recording or playing.".to_string())
+                },
+                RpcCall::LoadRom { path } =>{
+                    let loaded = read_rom(path).and_then(|rom| {
+                        let mut loaded = Machine::new(machine.quirks, seed);
+                        loaded.load_rom(&rom)?;
+                        loaded.symbols = load_symbols(path)?;
+                        loaded.trace = frontend.allows_instruction_trace();
+                        Ok((rom, loaded))
+                    });
+                    match loaded {
+                        Ok((rom, loaded)) =>{
+                            content = rom;
+                            machine = loaded;
+                            rom_path = path.clone();
+                            capture_stem = self::capture_stem(&rom_path);
+                            vip_timing = VipTiming::new();
+                            rewind_buffer = RewindBuffer::new(config.rewind_capacity, config.rewind_interval);
+                            frontend.report(&format!("Loaded {}", rom_path));
+                            Ok(Value::Null)
+                        },
+                        Err(e) => Err(e),
+                    }
+                },
+                RpcCall::Pause =>{
+                    paused = true;
+                    frontend.report("Paused");
+                    Ok(Value::Null)
+                },
+                RpcCall::Resume =>{
+                    paused = false;
+                    frontend.report("Resumed");
+                    Ok(Value::Null)
+                },
+                RpcCall::Step { frames } if paused =>{
+                    frames_to_advance = frames_to_advance.saturating_add(*frames);
+                    step_requests.push(request);
+                    continue;
+                },
+                RpcCall::Step { .. } => Err("Pause before stepping frames.".to_string()),
+                RpcCall::PressKey { key } =>{
+                    if !remote_keys.contains(key){
+                        remote_keys.push(*key);
+                    }
+                    Ok(Value::Null)
+                },
+                RpcCall::ReleaseKey { key } =>{
+                    remote_keys.retain(|held| held != key);
+                    Ok(Value::Null)
+                },
+                RpcCall::ReadRegisters => Ok(rpc::registers(&machine)),
+                RpcCall::ReadMemory { address, length } => match address.checked_add(*length) {
+                    Some(end) if end <= RAM_SIZE => Ok(json!({ "data": rpc::to_hex(&machine.ram[*address..end]) })),
+                    _ => Err(format!("{} bytes at {:#05x} run past the end of memory.", length, address)),
+                },
+                RpcCall::ReadFramebuffer =>{
+                    let framebuffer = &machine.framebuffer;
+                    let rows: Vec<String> = framebuffer.pixels.chunks(framebuffer.width as usize)
+                        .map(|row| row.iter().map(|pixel| if *pixel { '1' } else { '0' }).collect())
+                        .collect();
+                    Ok(json!({ "width": framebuffer.width, "height": framebuffer.height, "rows": rows }))
+                },
+                RpcCall::SaveState => Ok(json!({ "state": rpc::to_hex(&machine.save_state()) })),
+                RpcCall::LoadState { state } => machine.load_state(state).map(|()| Value::Null),
+            };
+            request.reply(result);
+        }
+
+        for (key, pressed) in dashboard.iter().flat_map(|dashboard| dashboard.key_events()){
+            remote_keys.retain(|held| *held != key);
+            if pressed{
+                remote_keys.push(key);
+            }
+        }
+
+        // spectators run the frames the broadcaster sends, as they arrive
+        let mut spectated_frame = None;
+        if let Some(viewer) = &mut viewer{
+            while let Some(event) = viewer.next_event() {
+                match event {
+                    BroadcastEvent::Frame(frame) =>{
+                        spectated_frame = Some(frame);
+                        break;
+                    },
+                    BroadcastEvent::Snapshot(state) =>{
+                        if let Err(e) = machine.load_state(&state){
+                            frontend.report(&format!("Spectating ended: {}", e));
+                            quit = true;
+                            break;
+                        }
+                        // a viewer that halted carries on from wherever the broadcaster went next
+                        paused = false;
+                    },
+                    BroadcastEvent::Ended(e) =>{
+                        frontend.report(&format!("Spectating ended: {}", e));
+                        quit = true;
+                        break;
+                    },
+                }
+            }
+        }
+
+        if quit{
+            break;
+        }
+
+        let catch_up = viewer.as_ref().map_or(1.0, Viewer::speed_multiplier);
+        scheduler.set_speed_multiplier(catch_up * if turbo { speed * turbo_speed } else { speed });
+
+        if rewinding{
+            if let Some(state) = rewind_buffer.rewind(){
+                machine.load_state(&state).expect("Could not restore rewind snapshot.");
+            }
+            if let Some(filter) = &mut phosphor_filter{
+                filter.update(&machine.framebuffer);
+            }
+        } else if (!paused || frames_to_advance > 0) && (viewer.is_none() || spectated_frame.is_some()){
+            frames_to_advance = frames_to_advance.saturating_sub(1);
+
+            // during playback and spectating each frame runs exactly the inputs and instructions that were recorded
+            let recorded_frame = spectated_frame.or_else(|| player.as_ref().and_then(|player| player.current_frame()));
+            machine.keypad = match recorded_frame {
+                Some(frame) => movie::mask_to_keys(frame.keys),
+                None =>{
+                    let mut keys = frontend.get_keypad_press();
+                    keys.extend(&remote_keys);
+                    keys.sort();
+                    keys.dedup();
+                    keys
+                },
+            };
+
+            if let Some(session) = &mut netplay{
+                match session.exchange_inputs(&machine.keypad) {
+                    Ok(keys) => machine.keypad = keys,
+                    Err(e) =>{
+                        frontend.report(&format!("Netplay ended: {}", e));
+                        break;
+                    },
+                }
+            }
+
+            let frame_result = match (recorded_frame, speed_setting) {
+                (Some(MovieFrame { instructions, .. }), _) | (None, Speed::InstructionsPerFrame(instructions)) =>{
+                    timing::run_instructions(&mut machine, instructions).map(|()| instructions)
+                },
+                (None, Speed::Uncapped) =>{
+                    let mut instructions = 0;
+                    let mut result = Ok(());
+                    while result.is_ok() && !scheduler.is_frame_due() {
+                        result = timing::run_instructions(&mut machine, UNCAPPED_BATCH);
+                        instructions += UNCAPPED_BATCH;
+                    }
+                    result.map(|()| instructions)
+                },
+                (None, Speed::VipCycles) => vip_timing.run_frame(&mut machine),
+            };
+
+            // a faulting program halts the machine until a state is loaded or it is reset
+            let instructions_this_frame = match frame_result {
+                Ok(instructions) => instructions,
+                Err(e) =>{
+                    frontend.report(&format!("Machine halted: {}", e));
+                    if netplay.is_some(){
+                        break;
+                    }
+                    paused = true;
+                    frames_to_advance = 0;
+                    for request in step_requests.drain(..){
+                        request.reply(Err(format!("Machine halted: {}", e)));
+                    }
+                    scheduler.wait_for_next_frame();
+                    continue;
+                },
+            };
+
+            // each frame ends with a 60 Hz timer tick
+            machine.tick_timers();
+
+            if let Some(filter) = &mut phosphor_filter{
+                filter.update(&machine.framebuffer);
+            }
+
+            if let Some(recorder) = &mut gif_recorder{
+                if let Err(e) = recorder.add_frame(&machine.framebuffer){
+                    frontend.report(&format!("Stopped recording GIF: {}", e));
+                    gif_recorder = None;
+                }
+            }
+
+            if let Some(recorder) = &mut recording{
+                if let Err(e) = recorder.record_frame(&machine.keypad, instructions_this_frame, &machine){
+                    frontend.report(&format!("Stopped recording movie: {}", e));
+                    recording = None;
+                }
+            }
+
+            if let Some(player) = player.as_mut().filter(|player| !player.is_finished()){
+                let had_diverged = player.divergence.is_some();
+
+                if let Err(divergence) = player.end_frame(&machine){
+                    if !had_diverged{
+                        frontend.report(&format!("Movie diverged at frame {}: expected state {:016x}, got {:016x}",
+                            divergence.frame, divergence.expected, divergence.actual));
+                    }
+                }
+                if player.is_finished(){
+                    frontend.report(&format!("Movie playback finished after {} frames", player.frame));
+                }
+            }
+
+            if let Some(session) = &mut netplay{
+                match session.end_frame(&machine) {
+                    Ok(Some(desync)) =>{
+                        frontend.report(&format!("Netplay desynced at frame {}: state {:016x} here, {:016x} for the other player",
+                            desync.frame, desync.local, desync.remote));
+                    },
+                    Ok(None) => {},
+                    Err(e) =>{
+                        frontend.report(&format!("Netplay ended: {}", e));
+                        break;
+                    },
+                }
+            }
+
+            if let Some(broadcaster) = &broadcaster{
+                broadcaster.publish_frame(&machine.keypad, instructions_this_frame);
+            }
+
+            rewind_buffer.record_frame(&machine);
+
+            if let Some(report) = scheduler.record_frame(instructions_this_frame){
+                frontend.report(&format!("{:.1} fps, {:.0} instructions/s", report.frames_per_second, report.instructions_per_second));
+            }
+
+            if frames_to_advance == 0{
+                for request in step_requests.drain(..){
+                    request.reply(Ok(Value::Null));
+                }
+            }
+        }
+
+        if let Some(broadcaster) = &mut broadcaster{
+            broadcaster.publish_state(&machine);
+        }
+
+        if let Some(dashboard) = &dashboard{
+            dashboard.publish(&mut machine);
+        }
+
+        if frontend.take_redraw_request(){
+            machine.screen_changed = true;
+        }
+
+        match &phosphor_filter {
+            // fading pixels need redrawing even when nothing was drawn
+            Some(filter) if machine.screen_changed || filter.is_fading() =>{
+                frontend.render_with_persistence(filter)?;
+            },
+            None if machine.screen_changed =>{
+                frontend.render(&machine.framebuffer)?;
+            },
+            _ => {},
+        }
+        machine.screen_changed = false;
+
+        scheduler.wait_for_next_frame();
+    }
+
+    if let Some(recorder) = gif_recorder{
+        finish_gif(frontend, recorder);
+    }
+
+    if let Some(recorder) = &recording{
+        frontend.report(&format!("Saved movie of {} frames to {}", recorder.movie.frames.len(), recorder.path().display()));
+    }
+
+    Ok(())
+}
+
+fn read_rom(path: &str) -> Result<Vec<u8>, String>{
+    fs::read(path).map_err(|e| format!("Could not read ROM {}: {}", path, e))
+}
+
+/// ROMs built with chip8-asm come with a symbol map, used to name jump and call targets.
+fn load_symbols(rom_path: &str) -> Result<SymbolMap, String>{
+    let symbol_path = SymbolMap::path_for_rom(Path::new(rom_path));
+    if symbol_path.exists(){
+        SymbolMap::load(&symbol_path)
+    } else{
+        Ok(SymbolMap::new())
+    }
+}
+
+fn capture_stem(rom_path: &str) -> String{
+    Path::new(rom_path).file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "capture".to_string())
+}
+
+fn finish_gif(frontend: &mut dyn Frontend, recorder: GifRecorder){
+    let frames = recorder.frames_recorded();
+    match recorder.finish() {
+        Ok(path) => frontend.report(&format!("Saved GIF of {} frames to {}", frames, path.display())),
+        Err(e) => frontend.report(&format!("Could not save GIF: {}", e)),
+    }
+}
@@ -0,0 +1,341 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::expression::Expression;
+use crate::machine::{Machine, Quirks, Rng};
+use crate::scheduler::Speed;
+use crate::timing::{self, VipTiming};
+
+/*
+ * A Gym-style environment for reinforcement learning: an agent picks one of
+ * a fixed list of actions, each a set of held keys, and gets back the screen,
+ * a reward and whether the episode is over.
+ *
+ * What counts as reward and as the end of an episode is game specific, so it
+ * is read from a `.rl` file next to the ROM:
+ *
+ *     # Pong: VE holds the left player's points in tens and the right's in units
+ *     reward = ve / 10 - ve % 10
+ *     done = ve / 10 == 9 || ve % 10 == 9
+ *     actions = none, 1, 4
+ *
+ * The reward for a step is how much the reward expression went up during it,
+ * so a score expression rewards every point as it is scored. Without an
+ * actions line every single key is an action, after doing nothing.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvSpec{
+    pub reward: Expression,
+    pub done: Option<Expression>,
+    /// The keys held for each action.
+    pub actions: Vec<Vec<u8>>,
+}
+
+impl EnvSpec{
+    pub fn parse(text: &str) -> Result<EnvSpec, String>{
+        let mut reward = None;
+        let mut done = None;
+        let mut actions = None;
+
+        for (line_number, line) in text.lines().enumerate(){
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty(){
+                continue;
+            }
+
+            let (name, value) = line.split_once('=')
+                .filter(|(name, _)| !name.trim().is_empty() && !name.contains(['<', '>', '!']))
+                .ok_or_else(|| format!("line {}: expected '<setting> = <value>'", line_number + 1))?;
+            let in_line = |e: String| format!("line {}: {}", line_number + 1, e);
+
+            match name.trim() {
+                "reward" => reward = Some(Expression::parse(value).map_err(in_line)?),
+                "done" => done = Some(Expression::parse(value).map_err(in_line)?),
+                "actions" => actions = Some(parse_actions(value).map_err(in_line)?),
+                other => return Err(in_line(format!("unknown setting '{}', expected reward, done or actions", other))),
+            }
+        }
+
+        Ok(EnvSpec {
+            reward: reward.ok_or("No reward expression given.")?,
+            done,
+            actions: actions.unwrap_or_else(|| (0..=0x10).map(|key| if key == 0 { vec![] } else { vec![key - 1] }).collect()),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<EnvSpec, String>{
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read environment spec {}: {}", path.display(), e))?;
+
+        EnvSpec::parse(&text)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// `roms/Pong.ch8` keeps its spec in `roms/Pong.rl`.
+    pub fn path_for_rom(rom_path: &Path) -> PathBuf{
+        rom_path.with_extension("rl")
+    }
+}
+
+/// `none, 1, 4, 1+C`: keys held together are joined with `+`.
+fn parse_actions(text: &str) -> Result<Vec<Vec<u8>>, String>{
+    text.split(',')
+        .map(|action| {
+            let action = action.trim();
+            if action.eq_ignore_ascii_case("none"){
+                return Ok(vec![]);
+            }
+
+            action.split('+')
+                .map(|key| {
+                    u8::from_str_radix(key.trim(), 16).ok()
+                        .filter(|key| *key <= 0xF)
+                        .ok_or_else(|| format!("invalid key '{}' in action '{}'", key.trim(), action))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnvOptions{
+    /// Frames each step repeats its action for; rewards are summed over them.
+    pub frame_skip: u32,
+    /// Chance each frame of repeating the previous action instead of the one
+    /// asked for, which stops agents from memorising exact input timings.
+    pub sticky_action_probability: f64,
+    pub speed: Speed,
+    pub quirks: Quirks,
+    /// Drives both the machine's random numbers and sticky actions.
+    pub seed: u32,
+}
+
+impl Default for EnvOptions{
+    fn default() -> Self{
+        EnvOptions {
+            frame_skip: 1,
+            sticky_action_probability: 0.0,
+            speed: Speed::VIP,
+            quirks: Quirks::default(),
+            seed: 0,
+        }
+    }
+}
+
+/// What a step leads to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step{
+    /// The screen row by row, one byte per pixel: 1 lit, 0 dark.
+    pub observation: Vec<u8>,
+    pub reward: f64,
+    pub done: bool,
+}
+
+pub struct Env{
+    machine: Machine,
+    spec: EnvSpec,
+    options: EnvOptions,
+    vip_timing: VipTiming,
+    // the machine as powered on, which every episode starts from
+    start_state: Vec<u8>,
+    rng: Rng,
+    // the action last applied, which sticky actions repeat
+    previous_action: usize,
+    score: i64,
+    done: bool,
+}
+
+impl Env{
+    pub fn new(rom: &[u8], spec: EnvSpec, options: EnvOptions) -> Result<Env, String>{
+        if spec.actions.is_empty(){
+            return Err("The environment needs at least one action.".to_string());
+        }
+        timing::paced_speed(options.speed)?;
+
+        let mut machine = Machine::new(options.quirks, options.seed);
+        machine.load_rom(rom)?;
+        machine.trace = false;
+        let start_state = machine.save_state();
+
+        let mut env = Env {
+            machine,
+            spec,
+            options,
+            vip_timing: VipTiming::new(),
+            start_state,
+            rng: Rng::new(options.seed),
+            previous_action: 0,
+            score: 0,
+            done: false,
+        };
+        env.reset();
+
+        Ok(env)
+    }
+
+    /// Reads the ROM and the `.rl` spec next to it.
+    pub fn from_rom_file(rom_path: &Path, options: EnvOptions) -> Result<Env, String>{
+        let rom = fs::read(rom_path)
+            .map_err(|e| format!("Could not read ROM {}: {}", rom_path.display(), e))?;
+        let spec = EnvSpec::load(&EnvSpec::path_for_rom(rom_path))?;
+
+        Env::new(&rom, spec, options)
+    }
+
+    pub fn action_count(&self) -> usize{
+        self.spec.actions.len()
+    }
+
+    /// Width and height of the observations.
+    pub fn observation_shape(&self) -> (u32, u32){
+        (self.machine.framebuffer.width, self.machine.framebuffer.height)
+    }
+
+    pub fn machine(&self) -> &Machine{
+        &self.machine
+    }
+
+    /// Starts a new episode from power-on and returns the first observation.
+    /// Sticky actions keep drawing from the same random numbers, so episodes
+    /// differ while the whole run stays reproducible from the seed.
+    pub fn reset(&mut self) -> Vec<u8>{
+        self.machine.load_state(&self.start_state)
+            .expect("The start state was saved from this machine.");
+        self.machine.keypad.clear();
+        self.vip_timing = VipTiming::new();
+        self.previous_action = 0;
+        self.score = self.spec.reward.evaluate(&self.machine);
+        self.done = false;
+
+        self.observation()
+    }
+
+    /// Holds the action's keys for `frame_skip` frames, stopping early if
+    /// the episode ends.
+    pub fn step(&mut self, action: usize) -> Result<Step, String>{
+        if action >= self.spec.actions.len(){
+            return Err(format!("There is no action {}, there are {}.", action, self.spec.actions.len()));
+        }
+        if self.done{
+            return Err("The episode is over, reset to start another.".to_string());
+        }
+
+        let mut reward = 0;
+        for _ in 0..self.options.frame_skip.max(1){
+            let sticky = self.options.sticky_action_probability > 0.0
+                && (self.rng.next_byte() as f64) < self.options.sticky_action_probability * 256.0;
+            if !sticky{
+                self.previous_action = action;
+            }
+            self.machine.keypad = self.spec.actions[self.previous_action].clone();
+
+            timing::run_paced_frame(&mut self.machine, self.options.speed, &mut self.vip_timing)?;
+
+            let score = self.spec.reward.evaluate(&self.machine);
+            reward += score - self.score;
+            self.score = score;
+
+            if self.spec.done.as_ref().is_some_and(|done| done.evaluate(&self.machine) != 0){
+                self.done = true;
+                break;
+            }
+        }
+
+        Ok(Step {
+            observation: self.observation(),
+            reward: reward as f64,
+            done: self.done,
+        })
+    }
+
+    fn observation(&self) -> Vec<u8>{
+        self.machine.framebuffer.pixels.iter()
+            .map(|pixel| *pixel as u8)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn pong() -> Env{
+        Env::from_rom_file(Path::new("roms/Pong (alt).ch8"), EnvOptions {
+            sticky_action_probability: 0.25,
+            frame_skip: 4,
+            seed: 1234,
+            ..EnvOptions::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn parses_the_pong_spec(){
+        let spec = EnvSpec::load(Path::new("roms/Pong (alt).rl")).unwrap();
+
+        assert_eq!(spec.reward, Expression::parse("ve / 10 - ve % 10").unwrap());
+        assert_eq!(spec.done, Some(Expression::parse("ve / 10 == 9 || ve % 10 == 9").unwrap()));
+        assert_eq!(spec.actions, vec![vec![], vec![1], vec![4]]);
+    }
+
+    #[test]
+    fn parses_combined_keys_and_defaults_to_every_key(){
+        let spec = EnvSpec::parse("reward = v0\nactions = NONE, 1+c, F").unwrap();
+        assert_eq!(spec.done, None);
+        assert_eq!(spec.actions, vec![vec![], vec![1, 0xC], vec![0xF]]);
+
+        let spec = EnvSpec::parse("reward = v0").unwrap();
+        assert_eq!(spec.actions.len(), 17);
+        assert!(spec.actions[0].is_empty());
+        assert_eq!(spec.actions[16], vec![0xF]);
+    }
+
+    #[test]
+    fn rejects_bad_specs_with_line_numbers(){
+        assert_eq!(EnvSpec::parse("done = v0").unwrap_err(), "No reward expression given.");
+        assert!(EnvSpec::parse("reward = v0\nspeed = 3").unwrap_err().starts_with("line 2:"));
+        assert!(EnvSpec::parse("reward = v0\nactions = 10").unwrap_err().starts_with("line 2:"));
+        assert!(EnvSpec::parse("reward == v0").unwrap_err().starts_with("line 1:"));
+    }
+
+    #[test]
+    fn the_same_seed_plays_the_same_episodes(){
+        let actions: Vec<usize> = (0..200).map(|step| (step / 7) % 3).collect();
+        let play = |env: &mut Env| {
+            let mut steps = Vec::new();
+            for _ in 0..2 {
+                steps.push(Step { observation: env.reset(), reward: 0.0, done: false });
+                for action in &actions {
+                    let step = env.step(*action).unwrap();
+                    let done = step.done;
+                    steps.push(step);
+                    if done {
+                        break;
+                    }
+                }
+            }
+            steps
+        };
+
+        assert_eq!(play(&mut pong()), play(&mut pong()));
+    }
+
+    #[test]
+    fn reset_starts_from_power_on(){
+        let mut env = pong();
+        let first = env.reset();
+        for _ in 0..50 {
+            env.step(2).unwrap();
+        }
+
+        assert_eq!(env.reset(), first);
+        assert_eq!(env.machine().framebuffer.pixels, first.iter().map(|pixel| *pixel != 0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rejects_unknown_actions(){
+        let mut env = pong();
+
+        assert!(env.step(3).is_err());
+    }
+}
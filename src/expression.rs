@@ -0,0 +1,292 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::machine::{self, Machine, RAM_SIZE};
+
+/*
+ * Small integer expressions over the machine's state, used to define
+ * rewards and end conditions per ROM, e.g. `[0x2F0] - [0x2F1]` or
+ * `v3 == 0 && [0x1FF] > 2`.
+ *
+ *   [address]      byte of memory, the address itself an expression
+ *   v0-vf, i       registers and the index register
+ *   dt, st         delay and sound timers
+ *   + - * / % &    arithmetic and bitwise and; division by zero gives 0
+ *   == != < <= > >= && || !   comparisons and logic, true is 1
+ *
+ * Numbers are decimal or 0x hex. Everything is evaluated as i64.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression{
+    Number(i64),
+    Memory(Box<Expression>),
+    Register(u8),
+    IndexRegister,
+    DelayTimer,
+    SoundTimer,
+    Negate(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator{
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    BitAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
+
+impl Operator{
+    fn apply(self, left: i64, right: i64) -> i64{
+        match self {
+            Operator::Add => left.wrapping_add(right),
+            Operator::Subtract => left.wrapping_sub(right),
+            Operator::Multiply => left.wrapping_mul(right),
+            Operator::Divide => left.checked_div(right).unwrap_or(0),
+            Operator::Remainder => left.checked_rem(right).unwrap_or(0),
+            Operator::BitAnd => left & right,
+            Operator::Equal => (left == right) as i64,
+            Operator::NotEqual => (left != right) as i64,
+            Operator::Less => (left < right) as i64,
+            Operator::LessOrEqual => (left <= right) as i64,
+            Operator::Greater => (left > right) as i64,
+            Operator::GreaterOrEqual => (left >= right) as i64,
+            Operator::And => (left != 0 && right != 0) as i64,
+            Operator::Or => (left != 0 || right != 0) as i64,
+        }
+    }
+}
+
+/// Operators from loosest to tightest binding.
+const PRECEDENCE: [&[(&str, Operator)]; 5] = [
+    &[("||", Operator::Or)],
+    &[("&&", Operator::And)],
+    &[("==", Operator::Equal), ("!=", Operator::NotEqual), ("<=", Operator::LessOrEqual),
+        (">=", Operator::GreaterOrEqual), ("<", Operator::Less), (">", Operator::Greater)],
+    &[("+", Operator::Add), ("-", Operator::Subtract)],
+    &[("*", Operator::Multiply), ("/", Operator::Divide), ("%", Operator::Remainder), ("&", Operator::BitAnd)],
+];
+
+impl Expression{
+    pub fn parse(text: &str) -> Result<Expression, String>{
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+
+        let expression = parser.binary(0)?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(format!("Unexpected '{}' in '{}'.", token, text)),
+        }
+    }
+
+    pub fn evaluate(&self, machine: &Machine) -> i64{
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Memory(address) =>{
+                let address = address.evaluate(machine).rem_euclid(RAM_SIZE as i64);
+                machine.ram[address as usize] as i64
+            },
+            Expression::Register(register) => machine.variable_registers[*register as usize] as i64,
+            Expression::IndexRegister => machine::get_index_register(&machine.ram) as i64,
+            Expression::DelayTimer => machine.delay_timer as i64,
+            Expression::SoundTimer => machine.sound_timer as i64,
+            Expression::Negate(operand) => operand.evaluate(machine).wrapping_neg(),
+            Expression::Not(operand) => (operand.evaluate(machine) == 0) as i64,
+            Expression::Binary(operator, left, right) => operator.apply(left.evaluate(machine), right.evaluate(machine)),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String>{
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<Chars> = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace(){
+            chars.next();
+        } else if c.is_ascii_alphanumeric(){
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+                word.push(c.to_ascii_lowercase());
+                chars.next();
+            }
+            tokens.push(word);
+        } else{
+            chars.next();
+            let pair: String = [c].into_iter().chain(chars.peek().copied()).collect();
+            if ["==", "!=", "<=", ">=", "&&", "||"].contains(&pair.as_str()){
+                chars.next();
+                tokens.push(pair);
+            } else if "+-*/%&<>![]()".contains(c){
+                tokens.push(c.to_string());
+            } else{
+                return Err(format!("Unexpected '{}' in '{}'.", c, text));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a>{
+    tokens: &'a [String],
+    position: usize,
+}
+
+impl<'a> Parser<'a>{
+    fn peek(&self) -> Option<&'a str>{
+        self.tokens.get(self.position).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<&'a str, String>{
+        let token = self.peek().ok_or_else(|| "Expression ends too early.".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String>{
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("Expected '{}' but found '{}'.", expected, token)),
+        }
+    }
+
+    /// Operators at `level` of PRECEDENCE and tighter, left to right.
+    fn binary(&mut self, level: usize) -> Result<Expression, String>{
+        if level == PRECEDENCE.len(){
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(&(_, operator)) = PRECEDENCE[level].iter().find(|(symbol, _)| Some(*symbol) == self.peek()) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String>{
+        match self.next()? {
+            "-" => Ok(Expression::Negate(Box::new(self.unary()?))),
+            "!" => Ok(Expression::Not(Box::new(self.unary()?))),
+            "(" =>{
+                let inner = self.binary(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            },
+            "[" =>{
+                let address = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expression::Memory(Box::new(address)))
+            },
+            "i" => Ok(Expression::IndexRegister),
+            "dt" => Ok(Expression::DelayTimer),
+            "st" => Ok(Expression::SoundTimer),
+            token => parse_register(token)
+                .map(Expression::Register)
+                .or_else(|| parse_number(token).map(Expression::Number))
+                .ok_or_else(|| format!("Unknown value '{}'.", token)),
+        }
+    }
+}
+
+fn parse_register(token: &str) -> Option<u8>{
+    let digit = token.strip_prefix('v')?;
+    if digit.len() != 1{
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn parse_number(token: &str) -> Option<i64>{
+    match token.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => token.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::machine::Quirks;
+
+    fn evaluate(text: &str, machine: &Machine) -> i64{
+        Expression::parse(text).unwrap().evaluate(machine)
+    }
+
+    fn machine() -> Machine{
+        Machine::new(Quirks::default(), 0)
+    }
+
+    #[test]
+    fn operators_bind_by_precedence(){
+        let machine = machine();
+
+        assert_eq!(evaluate("1 + 2 * 3", &machine), 7);
+        assert_eq!(evaluate("(1 + 2) * 3", &machine), 9);
+        assert_eq!(evaluate("10 - 4 - 3", &machine), 3);
+        assert_eq!(evaluate("7 & 3 + 1", &machine), 4);
+        assert_eq!(evaluate("1 + 1 == 2 && 3 > 2", &machine), 1);
+        assert_eq!(evaluate("0 && 1 || 1", &machine), 1);
+        assert_eq!(evaluate("-2 * -3", &machine), 6);
+        assert_eq!(evaluate("!0 + !5", &machine), 1);
+    }
+
+    #[test]
+    fn division_by_zero_gives_zero(){
+        let machine = machine();
+
+        assert_eq!(evaluate("5 / 0", &machine), 0);
+        assert_eq!(evaluate("5 % 0", &machine), 0);
+        assert_eq!(evaluate("7 / 2", &machine), 3);
+    }
+
+    #[test]
+    fn memory_addresses_wrap_around_ram(){
+        let mut machine = machine();
+        machine.ram[0x2F0] = 42;
+        machine.ram[RAM_SIZE - 1] = 7;
+
+        assert_eq!(evaluate("[0x2F0]", &machine), 42);
+        assert_eq!(evaluate("[0x2F0 + 0x1000]", &machine), 42);
+        assert_eq!(evaluate("[-1]", &machine), 7);
+    }
+
+    #[test]
+    fn reads_registers_and_timers(){
+        let mut machine = machine();
+        machine.variable_registers[0xE] = 0x35;
+        machine.delay_timer = 9;
+        machine.sound_timer = 4;
+        machine::set_index_register(&mut machine.ram, 0x300);
+
+        assert_eq!(evaluate("VE / 16", &machine), 3);
+        assert_eq!(evaluate("ve % 16", &machine), 5);
+        assert_eq!(evaluate("dt - st", &machine), 5);
+        assert_eq!(evaluate("i", &machine), 0x300);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions(){
+        assert!(Expression::parse("1 +").is_err());
+        assert!(Expression::parse("(1").is_err());
+        assert!(Expression::parse("[0x200").is_err());
+        assert!(Expression::parse("1 2").is_err());
+        assert!(Expression::parse("vg").is_err());
+        assert!(Expression::parse("1 $ 2").is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::machine::{Machine, Quirks};
+use crate::movie;
+use crate::savestate::MAX_STATE_SIZE;
+use crate::scheduler::Speed;
+use crate::timing::{self, VipTiming};
+
+/*
+ * A C ABI over the machine for test harnesses written in C and C++. The
+ * header, include/chip8.h, is generated from this file by build.rs, so the
+ * doc comments here are what C users read. Builds only write it to OUT_DIR;
+ * after changing this file, refresh the committed copy with
+ * `CHIP8_UPDATE_HEADER=1 cargo build`.
+ *
+ * Functions that can fail return false (or 0, or -1 where 0 is a valid
+ * result) and leave a message for chip8_last_error. Every function takes
+ * the handle from chip8_create and must not be given a null or destroyed
+ * one. A panic unwinding into C would abort the host, so functions that run
+ * the machine catch them and fail instead, as the libretro core does.
+ */
+
+/// An emulated machine. Create one with chip8_create and free it with chip8_destroy.
+pub struct Chip8{
+    machine: Machine,
+    seed: u32,
+    speed: Speed,
+    vip_timing: VipTiming,
+    last_error: CString,
+}
+
+impl Chip8{
+    fn fail(&mut self, error: String) -> bool{
+        self.last_error = CString::new(error).unwrap_or_default();
+        false
+    }
+}
+
+/// Runs the body of an entry point, returning `fallback` with an error if it panics.
+unsafe fn catch_panic<T>(chip8: *mut Chip8, entry_point: &str, fallback: T, body: impl FnOnce(&mut Chip8) -> T) -> T{
+    match panic::catch_unwind(AssertUnwindSafe(|| body(&mut *chip8))) {
+        Ok(result) => result,
+        Err(_) =>{
+            (*chip8).fail(format!("{} panicked, the machine may be left part way through an instruction.", entry_point));
+            fallback
+        },
+    }
+}
+
+/// Creates a machine with no ROM loaded. `seed` drives the random numbers
+/// behind CXNN, so the same seed and inputs always give the same run.
+#[no_mangle]
+pub extern "C" fn chip8_create(seed: u32) -> *mut Chip8{
+    let mut machine = Machine::new(Quirks::default(), seed);
+    machine.trace = false;
+
+    Box::into_raw(Box::new(Chip8 {
+        machine,
+        seed,
+        speed: Speed::VIP,
+        vip_timing: VipTiming::new(),
+        last_error: CString::default(),
+    }))
+}
+
+/// Frees a machine. Null is ignored.
+///
+/// # Safety
+///
+/// `chip8` must be null or come from chip8_create and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_destroy(chip8: *mut Chip8){
+    if !chip8.is_null(){
+        drop(Box::from_raw(chip8));
+    }
+}
+
+/// Powers the machine on with a ROM, keeping the quirks and speed.
+///
+/// # Safety
+///
+/// `data` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(chip8: *mut Chip8, data: *const u8, length: usize) -> bool{
+    catch_panic(chip8, "chip8_load_rom", false, |chip8| {
+        if data.is_null(){
+            return chip8.fail("No ROM data given.".to_string());
+        }
+
+        let mut machine = Machine::new(chip8.machine.quirks, chip8.seed);
+        machine.trace = false;
+        if let Err(e) = machine.load_rom(std::slice::from_raw_parts(data, length)){
+            return chip8.fail(e);
+        }
+
+        chip8.machine = machine;
+        chip8.vip_timing = VipTiming::new();
+        true
+    })
+}
+
+/// Sets how much chip8_run_frame runs: "vip", "schip", "vip-cycles" or a
+/// number of instructions per frame.
+///
+/// # Safety
+///
+/// `speed` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_speed(chip8: *mut Chip8, speed: *const c_char) -> bool{
+    let chip8 = &mut *chip8;
+    if speed.is_null(){
+        return chip8.fail("No speed given.".to_string());
+    }
+
+    match CStr::from_ptr(speed).to_string_lossy().parse().and_then(timing::paced_speed) {
+        Ok(parsed) =>{
+            chip8.speed = parsed;
+            true
+        },
+        Err(e) => chip8.fail(e),
+    }
+}
+
+/// Whether 8XY6 and 8XYE shift VX in place rather than copying VY first.
+///
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_shift_quirk(chip8: *mut Chip8, ignore_y: bool){
+    (*chip8).machine.quirks.ignore_y_in_8xy_shift_instruction = ignore_y;
+}
+
+/// Runs a single instruction. Timers are left alone. Fails when the program
+/// does something the machine cannot, like returning with an empty stack,
+/// and leaves the machine at that instruction.
+///
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_step(chip8: *mut Chip8) -> bool{
+    catch_panic(chip8, "chip8_step", false, |chip8| {
+        match chip8.machine.step() {
+            Ok(()) => true,
+            Err(e) => chip8.fail(e),
+        }
+    })
+}
+
+/// Runs one 60 Hz frame at the set speed, then ticks the timers. Returns the
+/// number of instructions run, or -1 if one failed as in chip8_step, in
+/// which case the timers are left alone.
+///
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frame(chip8: *mut Chip8) -> i64{
+    catch_panic(chip8, "chip8_run_frame", -1, |chip8| {
+        match timing::run_paced_frame(&mut chip8.machine, chip8.speed, &mut chip8.vip_timing) {
+            Ok(instructions) => instructions as i64,
+            Err(e) =>{
+                chip8.fail(e);
+                -1
+            },
+        }
+    })
+}
+
+/// Sets the keys held on the hex keypad, bit N standing for key N.
+///
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_keys(chip8: *mut Chip8, mask: u16){
+    (*chip8).machine.keypad = movie::mask_to_keys(mask);
+}
+
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer_width(chip8: *const Chip8) -> u32{
+    (*chip8).machine.framebuffer.width
+}
+
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer_height(chip8: *const Chip8) -> u32{
+    (*chip8).machine.framebuffer.height
+}
+
+/// Copies the screen row by row, one byte per pixel (1 lit, 0 dark), and
+/// returns the number of pixels. With a null buffer only the count is
+/// returned; a buffer that is too small gets nothing and 0 is returned.
+///
+/// # Safety
+///
+/// `pixels` must be null or point to `length` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_read_framebuffer(chip8: *mut Chip8, pixels: *mut u8, length: usize) -> usize{
+    let chip8 = &mut *chip8;
+    let framebuffer = &chip8.machine.framebuffer.pixels;
+    if pixels.is_null(){
+        return framebuffer.len();
+    }
+    if length < framebuffer.len(){
+        chip8.fail(format!("The screen needs {} bytes but only {} were given.", framebuffer.len(), length));
+        return 0;
+    }
+
+    let buffer = std::slice::from_raw_parts_mut(pixels, framebuffer.len());
+    for (byte, pixel) in buffer.iter_mut().zip(framebuffer){
+        *byte = *pixel as u8;
+    }
+
+    framebuffer.len()
+}
+
+/// Whether the buzzer is sounding, i.e. the sound timer is running.
+///
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_active(chip8: *const Chip8) -> bool{
+    (*chip8).machine.sound_timer > 0
+}
+
+/// A buffer of this size always fits a saved state.
+#[no_mangle]
+pub extern "C" fn chip8_max_state_size() -> usize{
+    MAX_STATE_SIZE
+}
+
+/// Saves the whole machine into `data` and returns the number of bytes
+/// written, or 0 if the buffer is too small.
+///
+/// # Safety
+///
+/// `data` must point to `length` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(chip8: *mut Chip8, data: *mut u8, length: usize) -> usize{
+    catch_panic(chip8, "chip8_save_state", 0, |chip8| {
+        let state = chip8.machine.save_state();
+        if data.is_null() || length < state.len(){
+            chip8.fail(format!("The state needs {} bytes but only {} were given.", state.len(), length));
+            return 0;
+        }
+
+        std::slice::from_raw_parts_mut(data, state.len()).copy_from_slice(&state);
+        state.len()
+    })
+}
+
+/// Restores a state from chip8_save_state. The machine is left as it was
+/// if the state is malformed or belongs to another ROM.
+///
+/// # Safety
+///
+/// `data` must point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(chip8: *mut Chip8, data: *const u8, length: usize) -> bool{
+    catch_panic(chip8, "chip8_load_state", false, |chip8| {
+        if data.is_null(){
+            return chip8.fail("No state data given.".to_string());
+        }
+
+        match chip8.machine.load_state(std::slice::from_raw_parts(data, length)) {
+            Ok(()) => true,
+            Err(e) => chip8.fail(e),
+        }
+    })
+}
+
+/// Why the last call that failed did so. The string belongs to the machine
+/// and stays valid until the next failure or chip8_destroy.
+///
+/// # Safety
+///
+/// `chip8` must come from chip8_create.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_last_error(chip8: *const Chip8) -> *const c_char{
+    (*chip8).last_error.as_ptr()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // v0 := 0, point I at its font glyph, draw it, then loop
+    const ROM: [u8; 8] = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+
+    unsafe fn last_error(chip8: *const Chip8) -> String{
+        CStr::from_ptr(chip8_last_error(chip8)).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn runs_saves_and_restores_a_machine(){
+        unsafe {
+            let chip8 = chip8_create(7);
+            assert!(chip8_load_rom(chip8, ROM.as_ptr(), ROM.len()));
+            assert!(chip8_set_speed(chip8, c"4".as_ptr()));
+            chip8_set_keys(chip8, 1 << 0x5);
+            assert_eq!((*chip8).machine.keypad, vec![0x5]);
+            assert_eq!(chip8_run_frame(chip8), 4);
+
+            let width = chip8_framebuffer_width(chip8) as usize;
+            let height = chip8_framebuffer_height(chip8) as usize;
+            let mut pixels = vec![0xAA; width * height];
+            assert_eq!(chip8_read_framebuffer(chip8, pixels.as_mut_ptr(), pixels.len()), width * height);
+            assert_eq!(&pixels[..5], &[1, 1, 1, 1, 0]);
+
+            let mut state = vec![0; chip8_max_state_size()];
+            let length = chip8_save_state(chip8, state.as_mut_ptr(), state.len());
+            assert!(length > 0);
+
+            assert_eq!(chip8_run_frame(chip8), 4);
+            assert!(chip8_step(chip8));
+            assert!(chip8_load_state(chip8, state.as_ptr(), length));
+            assert_eq!((*chip8).machine.save_state(), state[..length]);
+
+            chip8_destroy(chip8);
+        }
+    }
+
+    #[test]
+    fn reports_buffers_that_are_too_small_or_missing(){
+        unsafe {
+            let chip8 = chip8_create(7);
+            assert!(chip8_load_rom(chip8, ROM.as_ptr(), ROM.len()));
+
+            let pixel_count = chip8_read_framebuffer(chip8, std::ptr::null_mut(), 0);
+            assert_eq!(pixel_count, 64 * 32);
+            let mut pixels = vec![0xAA; pixel_count - 1];
+            assert_eq!(chip8_read_framebuffer(chip8, pixels.as_mut_ptr(), pixels.len()), 0);
+            assert!(pixels.iter().all(|pixel| *pixel == 0xAA));
+            assert!(last_error(chip8).starts_with("The screen needs 2048 bytes"));
+
+            let mut state = vec![0; 16];
+            assert_eq!(chip8_save_state(chip8, state.as_mut_ptr(), state.len()), 0);
+            assert!(last_error(chip8).starts_with("The state needs"));
+            assert_eq!(chip8_save_state(chip8, std::ptr::null_mut(), chip8_max_state_size()), 0);
+
+            assert!(!chip8_load_state(chip8, std::ptr::null(), 0));
+            assert_eq!(last_error(chip8), "No state data given.");
+            assert!(!chip8_load_state(chip8, state.as_ptr(), state.len()));
+            assert!(!chip8_load_rom(chip8, std::ptr::null(), 0));
+            assert_eq!(last_error(chip8), "No ROM data given.");
+            assert!(!chip8_set_speed(chip8, c"uncapped".as_ptr()));
+
+            chip8_destroy(chip8);
+            chip8_destroy(std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn a_failing_instruction_returns_minus_one(){
+        unsafe {
+            let chip8 = chip8_create(0);
+            // return with an empty stack
+            let rom = [0x00, 0xEE];
+            assert!(chip8_load_rom(chip8, rom.as_ptr(), rom.len()));
+
+            assert_eq!(chip8_run_frame(chip8), -1);
+            assert!(!chip8_step(chip8));
+            assert!(!last_error(chip8).is_empty());
+
+            chip8_destroy(chip8);
+        }
+    }
+
+    #[test]
+    fn panics_become_errors(){
+        unsafe {
+            let chip8 = chip8_create(0);
+
+            assert_eq!(catch_panic(chip8, "chip8_test", -1, |_| panic!("boom")), -1);
+            assert_eq!(last_error(chip8), "chip8_test panicked, the machine may be left part way through an instruction.");
+
+            chip8_destroy(chip8);
+        }
+    }
+}
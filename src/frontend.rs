@@ -0,0 +1,66 @@
+use crate::framebuffer::Framebuffer;
+use crate::hotkeys::Action;
+use crate::palette::Palette;
+use crate::persistence::PhosphorFilter;
+
+/// Emulator actions triggered from the keyboard, separate from the hex keypad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey{
+    Pressed(Action),
+    /// Only sent for actions that last while held, like turbo and rewind.
+    Released(Action),
+}
+
+/// Where the screen is shown and input comes from, e.g. the SDL window or a
+/// terminal. The emulator loop only talks to the frontend through this.
+pub trait Frontend{
+    /// Redraws the screen from the machine's framebuffer.
+    fn render(&mut self, framebuffer: &Framebuffer) -> Result<(), String>;
+
+    /// Redraws the screen with fading pixels blended into the background.
+    fn render_with_persistence(&mut self, filter: &PhosphorFilter) -> Result<(), String>;
+
+    /// Drains pending input, returning the hotkeys triggered since the last call.
+    fn tick(&mut self) -> Vec<Hotkey>;
+
+    /// Samples the keypad for the next frame: keys held now plus any pressed
+    /// and released since the previous sample.
+    fn get_keypad_press(&mut self) -> Vec<u8>;
+
+    fn palette(&self) -> &Palette;
+
+    fn set_palette(&mut self, palette: Palette);
+
+    /// Whether the screen needs drawing again even though it did not change,
+    /// e.g. after a resize.
+    fn take_redraw_request(&mut self) -> bool{
+        false
+    }
+
+    /// Shows a status message to the user.
+    fn report(&mut self, message: &str){
+        println!("{}", message);
+    }
+
+    /// Whether the machine may print every instruction it executes to stdout.
+    fn allows_instruction_trace(&self) -> bool{
+        true
+    }
+
+    fn is_fullscreen(&self) -> bool{
+        false
+    }
+
+    fn set_fullscreen(&mut self, _fullscreen: bool) -> Result<(), String>{
+        Err("This frontend has no fullscreen mode.".to_string())
+    }
+
+    /// Size of a CHIP-8 pixel, measured against the 64x32 screen.
+    fn scale(&self) -> u32{
+        1
+    }
+
+    fn set_scale(&mut self, _scale: u32) -> Result<(), String>{
+        Err("This frontend cannot be scaled.".to_string())
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::keymap::Keymap;
+
+/// How far a stick has to be pushed before it counts as a direction, out of 32767.
+pub const DEFAULT_DEADZONE: i16 = 16000;
+
+/// Controller input as the frontend reports it. Buttons and axes use SDL's
+/// game controller names ("a", "dpup", "leftx", ...), and `controller`
+/// tells apart several pads plugged in at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GamepadEvent{
+    Button { controller: u32, button: String, pressed: bool },
+    Axis { controller: u32, axis: String, value: i16 },
+    Removed { controller: u32 },
+}
+
+/// The d-pad and left stick drive 5/7/8/9 (up/left/down/right), the keys most
+/// games read as WASD on the VIP layout. A and B are 6 and 4.
+pub fn default_bindings() -> Keymap{
+    let mut bindings = Keymap::empty();
+    bindings.bind(0x5, &["dpup", "lefty-"]);
+    bindings.bind(0x7, &["dpleft", "leftx-"]);
+    bindings.bind(0x8, &["dpdown", "lefty+"]);
+    bindings.bind(0x9, &["dpright", "leftx+"]);
+    bindings.bind(0x6, &["a"]);
+    bindings.bind(0x4, &["b"]);
+    bindings
+}
+
+/// Per-ROM bindings live next to the ROM, e.g. `roms/Pong.pad`, in the same
+/// `<key>=<input>|<input>` format as the keymap, one or more per line.
+pub fn path_for_rom(rom_path: &Path) -> PathBuf{
+    rom_path.with_extension("pad")
+}
+
+pub fn load_bindings(bindings: &mut Keymap, path: &Path) -> Result<(), String>{
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read gamepad bindings {}: {}", path.display(), e))?;
+
+    for line in text.lines().map(|line| line.split('#').next().unwrap()){
+        bindings.apply(line)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Turns controller events into held keypad keys.
+///
+/// Axes are split into two inputs by direction, so `leftx-` is the stick
+/// pushed left and `lefty+` is the stick pushed down.
+pub struct Gamepad{
+    bindings: HashMap<String, u8>,
+    deadzone: i16,
+    held: BTreeSet<(u32, String)>,
+}
+
+impl Gamepad{
+    /// A negative deadzone counts as 0, which keeps it safe to negate for the
+    /// other direction.
+    pub fn new(bindings: &Keymap, deadzone: i16) -> Gamepad{
+        Gamepad {
+            bindings: bindings.bindings()
+                .map(|(input, key)| (input.to_ascii_lowercase(), key))
+                .collect(),
+            deadzone: deadzone.max(0),
+            held: BTreeSet::new(),
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &GamepadEvent){
+        match event {
+            GamepadEvent::Button { controller, button, pressed } =>{
+                self.set_held(*controller, button.to_ascii_lowercase(), *pressed);
+            },
+            GamepadEvent::Axis { controller, axis, value } =>{
+                let axis = axis.to_ascii_lowercase();
+                self.set_held(*controller, format!("{}-", axis), *value < -self.deadzone);
+                self.set_held(*controller, format!("{}+", axis), *value > self.deadzone);
+            },
+            GamepadEvent::Removed { controller } =>{
+                self.held.retain(|(held_controller, _)| held_controller != controller);
+            },
+        }
+    }
+
+    fn set_held(&mut self, controller: u32, input: String, held: bool){
+        if held{
+            self.held.insert((controller, input));
+        } else{
+            self.held.remove(&(controller, input));
+        }
+    }
+
+    /// Keypad keys held on any controller, without duplicates.
+    pub fn get_keypad_press(&self) -> Vec<u8>{
+        let keys: BTreeSet<u8> = self.held.iter()
+            .filter_map(|(_, input)| self.bindings.get(input).copied())
+            .collect();
+
+        keys.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn button(controller: u32, button: &str, pressed: bool) -> GamepadEvent{
+        GamepadEvent::Button { controller, button: button.to_string(), pressed }
+    }
+
+    fn axis(controller: u32, axis: &str, value: i16) -> GamepadEvent{
+        GamepadEvent::Axis { controller, axis: axis.to_string(), value }
+    }
+
+    fn gamepad() -> Gamepad{
+        Gamepad::new(&default_bindings(), DEFAULT_DEADZONE)
+    }
+
+    #[test]
+    fn buttons_hold_keys_until_released(){
+        let mut gamepad = gamepad();
+        gamepad.handle_event(&button(0, "a", true));
+        gamepad.handle_event(&button(0, "DPUp", true));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x5, 0x6]);
+
+        gamepad.handle_event(&button(0, "a", false));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x5]);
+
+        // unbound buttons do nothing
+        gamepad.handle_event(&button(0, "start", true));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x5]);
+    }
+
+    #[test]
+    fn axes_count_past_the_deadzone(){
+        let mut gamepad = gamepad();
+        gamepad.handle_event(&axis(0, "leftx", DEFAULT_DEADZONE));
+        assert!(gamepad.get_keypad_press().is_empty());
+        gamepad.handle_event(&axis(0, "leftx", DEFAULT_DEADZONE + 1));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x9]);
+
+        gamepad.handle_event(&axis(0, "leftx", -DEFAULT_DEADZONE));
+        assert!(gamepad.get_keypad_press().is_empty());
+        gamepad.handle_event(&axis(0, "leftx", -DEFAULT_DEADZONE - 1));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x7]);
+
+        gamepad.handle_event(&axis(0, "leftx", 0));
+        assert!(gamepad.get_keypad_press().is_empty());
+    }
+
+    #[test]
+    fn axes_at_their_limits(){
+        let mut gamepad = gamepad();
+        gamepad.handle_event(&axis(0, "lefty", i16::MIN));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x5]);
+        gamepad.handle_event(&axis(0, "lefty", i16::MAX));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x8]);
+    }
+
+    #[test]
+    fn extreme_deadzones(){
+        // only the far end of a stick pushed up or left gets past the largest deadzone
+        let mut gamepad = Gamepad::new(&default_bindings(), i16::MAX);
+        gamepad.handle_event(&axis(0, "leftx", i16::MAX));
+        gamepad.handle_event(&axis(1, "lefty", i16::MIN));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x5]);
+
+        // negative deadzones count as none at all
+        let mut gamepad = Gamepad::new(&default_bindings(), i16::MIN);
+        gamepad.handle_event(&axis(0, "leftx", 0));
+        assert!(gamepad.get_keypad_press().is_empty());
+        gamepad.handle_event(&axis(0, "leftx", 1));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x9]);
+    }
+
+    #[test]
+    fn removing_a_controller_releases_its_keys(){
+        let mut gamepad = gamepad();
+        gamepad.handle_event(&button(0, "a", true));
+        gamepad.handle_event(&axis(0, "leftx", i16::MAX));
+        gamepad.handle_event(&button(1, "b", true));
+        gamepad.handle_event(&button(1, "a", true));
+
+        gamepad.handle_event(&GamepadEvent::Removed { controller: 0 });
+        assert_eq!(gamepad.get_keypad_press(), vec![0x4, 0x6]);
+
+        gamepad.handle_event(&GamepadEvent::Removed { controller: 1 });
+        assert!(gamepad.get_keypad_press().is_empty());
+    }
+
+    #[test]
+    fn keys_held_on_several_controllers_count_once(){
+        let mut gamepad = gamepad();
+        gamepad.handle_event(&button(0, "a", true));
+        gamepad.handle_event(&button(1, "a", true));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x6]);
+
+        gamepad.handle_event(&button(0, "a", false));
+        assert_eq!(gamepad.get_keypad_press(), vec![0x6]);
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::keymap::Keymap;
+
+/// Emulator controls that sit beside the hex keypad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Action{
+    Quit,
+    Pause,
+    /// Reloads the ROM with the current settings.
+    Reset,
+    /// Runs a single frame while paused.
+    FrameAdvance,
+    /// Runs faster for as long as the key is held.
+    Turbo,
+    SpeedUp,
+    SpeedDown,
+    /// Rewinds for as long as the key is held.
+    Rewind,
+    /// Switches to the next built-in colour palette.
+    NextPalette,
+    ToggleFullscreen,
+    ScaleUp,
+    ScaleDown,
+    /// Saves a PNG of the screen.
+    Screenshot,
+    /// Starts or stops recording a GIF.
+    RecordGif,
+    /// Saves the machine to a numbered slot, 1 to `SAVE_STATE_SLOTS`.
+    SaveState(u8),
+    /// Restores the machine from a numbered slot.
+    LoadState(u8),
+}
+
+/// Save state slots with a hotkey each to save and load.
+pub const SAVE_STATE_SLOTS: u8 = 9;
+
+const SAVE_STATE_NAMES: [&str; SAVE_STATE_SLOTS as usize] = [
+    "save_state_1", "save_state_2", "save_state_3", "save_state_4", "save_state_5",
+    "save_state_6", "save_state_7", "save_state_8", "save_state_9",
+];
+const LOAD_STATE_NAMES: [&str; SAVE_STATE_SLOTS as usize] = [
+    "load_state_1", "load_state_2", "load_state_3", "load_state_4", "load_state_5",
+    "load_state_6", "load_state_7", "load_state_8", "load_state_9",
+];
+
+impl Action{
+    pub const ALL: [Action; 14] = [
+        Action::Quit,
+        Action::Pause,
+        Action::Reset,
+        Action::FrameAdvance,
+        Action::Turbo,
+        Action::SpeedUp,
+        Action::SpeedDown,
+        Action::Rewind,
+        Action::NextPalette,
+        Action::ToggleFullscreen,
+        Action::ScaleUp,
+        Action::ScaleDown,
+        Action::Screenshot,
+        Action::RecordGif,
+    ];
+
+    /// Every action, including one to save and one to load each slot.
+    pub fn all() -> impl Iterator<Item = Action>{
+        let slots = 1..=SAVE_STATE_SLOTS;
+        Action::ALL.into_iter()
+            .chain(slots.clone().map(Action::SaveState))
+            .chain(slots.map(Action::LoadState))
+    }
+
+    pub fn name(&self) -> &'static str{
+        match self {
+            Action::Quit => "quit",
+            Action::Pause => "pause",
+            Action::Reset => "reset",
+            Action::FrameAdvance => "frame_advance",
+            Action::Turbo => "turbo",
+            Action::SpeedUp => "speed_up",
+            Action::SpeedDown => "speed_down",
+            Action::Rewind => "rewind",
+            Action::NextPalette => "next_palette",
+            Action::ToggleFullscreen => "fullscreen",
+            Action::ScaleUp => "scale_up",
+            Action::ScaleDown => "scale_down",
+            Action::Screenshot => "screenshot",
+            Action::RecordGif => "record_gif",
+            Action::SaveState(slot) => SAVE_STATE_NAMES[*slot as usize - 1],
+            Action::LoadState(slot) => LOAD_STATE_NAMES[*slot as usize - 1],
+        }
+    }
+
+    /// Whether the action lasts while the key is held rather than firing once.
+    pub fn is_held(&self) -> bool{
+        matches!(self, Action::Turbo | Action::Rewind)
+    }
+}
+
+impl fmt::Display for Action{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Action{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        Action::all()
+            .find(|action| action.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown hotkey action '{}'.", s))
+    }
+}
+
+/// Splits a host key name like `Shift+F1` into the key and whether Shift has
+/// to be held with it.
+pub fn split_shift(host_key: &str) -> (&str, bool){
+    match host_key.get(..6) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("shift+") => (&host_key[6..], true),
+        _ => (host_key, false),
+    }
+}
+
+/// Host keys bound to each action, named the same way as in the `Keymap`
+/// and optionally prefixed with `Shift+`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HotkeyBindings{
+    bindings: BTreeMap<Action, Vec<String>>,
+}
+
+impl HotkeyBindings{
+    /// Keys that stay clear of the VIP keypad layout. F1-F9 load the save
+    /// state slots and Shift+F1-F9 save them.
+    pub fn standard() -> HotkeyBindings{
+        let mut hotkeys = HotkeyBindings { bindings: BTreeMap::new() };
+        hotkeys.bind(Action::Quit, &["Escape"]);
+        hotkeys.bind(Action::Pause, &["P"]);
+        hotkeys.bind(Action::Reset, &["F12"]);
+        hotkeys.bind(Action::FrameAdvance, &["N"]);
+        hotkeys.bind(Action::Turbo, &["Tab"]);
+        hotkeys.bind(Action::SpeedUp, &["="]);
+        hotkeys.bind(Action::SpeedDown, &["-"]);
+        hotkeys.bind(Action::Rewind, &["Backspace"]);
+        hotkeys.bind(Action::NextPalette, &["F10"]);
+        hotkeys.bind(Action::ToggleFullscreen, &["F11"]);
+        hotkeys.bind(Action::ScaleUp, &["]"]);
+        hotkeys.bind(Action::ScaleDown, &["["]);
+        hotkeys.bind(Action::Screenshot, &["PrintScreen", "O"]);
+        hotkeys.bind(Action::RecordGif, &["G"]);
+        for slot in 1..=SAVE_STATE_SLOTS{
+            hotkeys.bind(Action::SaveState(slot), &[&format!("Shift+F{}", slot)]);
+            hotkeys.bind(Action::LoadState(slot), &[&format!("F{}", slot)]);
+        }
+        hotkeys
+    }
+
+    /// Replaces the host keys of an action, taking them away from any other action.
+    pub fn bind(&mut self, action: Action, host_keys: &[&str]){
+        for bound in self.bindings.values_mut(){
+            bound.retain(|host_key| !host_keys.iter().any(|new| new.eq_ignore_ascii_case(host_key)));
+        }
+
+        self.bindings.insert(action, host_keys.iter().map(|host_key| host_key.to_string()).collect());
+    }
+
+    pub fn host_keys(&self, action: Action) -> &[String]{
+        self.bindings.get(&action).map(|host_keys| host_keys.as_slice()).unwrap_or(&[])
+    }
+
+    /// Every (host key, action) pair.
+    pub fn bindings(&self) -> impl Iterator<Item = (&str, Action)>{
+        self.bindings.iter()
+            .flat_map(|(action, host_keys)| host_keys.iter().map(move |host_key| (host_key.as_str(), *action)))
+    }
+
+    /// Applies bindings written as `<action>=<host key>|<host key>,...`, for
+    /// example `pause=P|Pause,turbo=Space`.
+    pub fn apply(&mut self, spec: &str) -> Result<(), String>{
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()){
+            let (action, host_keys) = entry.split_once('=')
+                .ok_or_else(|| format!("Hotkey binding '{}' should look like <action>=<host key>.", entry))?;
+
+            let action: Action = action.trim().parse()?;
+            let host_keys: Vec<&str> = host_keys.split('|')
+                .map(str::trim)
+                .filter(|host_key| !host_key.is_empty())
+                .collect();
+
+            self.bind(action, &host_keys);
+        }
+
+        Ok(())
+    }
+
+    /// Fails if a host key would both press a keypad key and trigger an
+    /// action. Holding Shift does not stop a key pressing the keypad.
+    pub fn check_conflicts(&self, keymap: &Keymap) -> Result<(), String>{
+        for (host_key, action) in self.bindings(){
+            let (unshifted, _) = split_shift(host_key);
+            if let Some((_, key)) = keymap.bindings().find(|(bound, _)| bound.eq_ignore_ascii_case(unshifted)){
+                return Err(format!("'{}' is bound to both keypad key {:X} and the {} hotkey.", host_key, key, action));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for HotkeyBindings{
+    fn default() -> Self{
+        HotkeyBindings::standard()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn save_state_slots_are_hotkeys(){
+        let hotkeys = HotkeyBindings::standard();
+        assert_eq!(hotkeys.host_keys(Action::LoadState(1)), &["F1".to_string()]);
+        assert_eq!(hotkeys.host_keys(Action::SaveState(9)), &["Shift+F9".to_string()]);
+        assert_eq!("save_state_3".parse(), Ok(Action::SaveState(3)));
+        assert_eq!("Load_State_9".parse(), Ok(Action::LoadState(9)));
+        assert!("save_state_10".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn every_action_has_its_own_name(){
+        let names: Vec<&str> = Action::all().map(|action| action.name()).collect();
+        assert_eq!(names.len(), Action::ALL.len() + 2 * SAVE_STATE_SLOTS as usize);
+        for action in Action::all(){
+            assert_eq!(action.name().parse(), Ok(action));
+        }
+    }
+
+    #[test]
+    fn splits_shift(){
+        assert_eq!(split_shift("Shift+F1"), ("F1", true));
+        assert_eq!(split_shift("shift+="), ("=", true));
+        assert_eq!(split_shift("F1"), ("F1", false));
+        assert_eq!(split_shift("Shift"), ("Shift", false));
+    }
+
+    #[test]
+    fn rebinding_takes_keys_from_other_actions(){
+        let mut hotkeys = HotkeyBindings::standard();
+        hotkeys.apply("pause=F1, load_state_2 = F2|Space").unwrap();
+
+        assert_eq!(hotkeys.host_keys(Action::Pause), &["F1".to_string()]);
+        assert!(hotkeys.host_keys(Action::LoadState(1)).is_empty());
+        assert_eq!(hotkeys.host_keys(Action::LoadState(2)), &["F2".to_string(), "Space".to_string()]);
+        assert!(hotkeys.apply("load_state_0=F1").is_err());
+        assert!(hotkeys.apply("pause").is_err());
+    }
+
+    #[test]
+    fn standard_hotkeys_leave_the_keypad_alone(){
+        assert_eq!(HotkeyBindings::standard().check_conflicts(&Keymap::cosmac_vip()), Ok(()));
+    }
+
+    #[test]
+    fn slot_keys_conflict_with_the_keypad(){
+        let mut keymap = Keymap::cosmac_vip();
+        keymap.apply("0=F3").unwrap();
+        let error = HotkeyBindings::standard().check_conflicts(&keymap).unwrap_err();
+        assert!(error.contains("F3"), "{}", error);
+
+        // Shift does not keep a key off the keypad
+        let mut hotkeys = HotkeyBindings::standard();
+        hotkeys.apply("save_state_1=Shift+W").unwrap();
+        assert!(hotkeys.check_conflicts(&Keymap::cosmac_vip()).is_err());
+    }
+}
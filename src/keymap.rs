@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+/// Host keys bound to each key of the hex keypad. Host keys are named the way
+/// the frontend names them (SDL scancode names for the window, e.g. "Q", "Up",
+/// "Keypad 8"), so this stays independent of any particular frontend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap{
+    bindings: BTreeMap<u8, Vec<String>>,
+}
+
+impl Keymap{
+    pub fn empty() -> Keymap{
+        Keymap { bindings: BTreeMap::new() }
+    }
+
+    /// The VIP keypad laid over the left hand side of a QWERTY keyboard:
+    ///
+    /// ```text
+    /// 1 2 3 C      1 2 3 4
+    /// 4 5 6 D  <-  Q W E R
+    /// 7 8 9 E      A S D F
+    /// A 0 B F      Z X C V
+    /// ```
+    pub fn cosmac_vip() -> Keymap{
+        let layout = [
+            (0x1, "1"), (0x2, "2"), (0x3, "3"), (0xC, "4"),
+            (0x4, "Q"), (0x5, "W"), (0x6, "E"), (0xD, "R"),
+            (0x7, "A"), (0x8, "S"), (0x9, "D"), (0xE, "F"),
+            (0xA, "Z"), (0x0, "X"), (0xB, "C"), (0xF, "V"),
+        ];
+
+        let mut keymap = Keymap::empty();
+        for (key, host_key) in layout{
+            keymap.bind(key, &[host_key]);
+        }
+        keymap
+    }
+
+    /// Replaces the host keys of a keypad key. A host key can only drive one
+    /// keypad key, so it is taken away from any key it was bound to before.
+    pub fn bind(&mut self, key: u8, host_keys: &[&str]){
+        for bound in self.bindings.values_mut(){
+            bound.retain(|host_key| !host_keys.iter().any(|new| new.eq_ignore_ascii_case(host_key)));
+        }
+
+        self.bindings.insert(key & 0xF, host_keys.iter().map(|host_key| host_key.to_string()).collect());
+    }
+
+    pub fn host_keys(&self, key: u8) -> &[String]{
+        self.bindings.get(&key).map(|host_keys| host_keys.as_slice()).unwrap_or(&[])
+    }
+
+    /// Every (host key, keypad key) pair.
+    pub fn bindings(&self) -> impl Iterator<Item = (&str, u8)>{
+        self.bindings.iter()
+            .flat_map(|(key, host_keys)| host_keys.iter().map(move |host_key| (host_key.as_str(), *key)))
+    }
+
+    /// Applies bindings written as `<key>=<host key>|<host key>,...`, for
+    /// example `5=W|Up,8=S|Down`. Keys that are not mentioned keep their
+    /// current bindings.
+    pub fn apply(&mut self, spec: &str) -> Result<(), String>{
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()){
+            let (key, host_keys) = entry.split_once('=')
+                .ok_or_else(|| format!("Key binding '{}' should look like <key>=<host key>.", entry))?;
+
+            let key = key.trim();
+            let digits = key.strip_prefix("0x").unwrap_or(key);
+            let key = Some(digits)
+                .filter(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .filter(|key| *key <= 0xF)
+                .ok_or_else(|| format!("'{}' is not a keypad key, expected 0-F.", key))?;
+
+            let host_keys: Vec<&str> = host_keys.split('|')
+                .map(str::trim)
+                .filter(|host_key| !host_key.is_empty())
+                .collect();
+            if host_keys.is_empty(){
+                return Err(format!("Key binding '{}' names no host keys.", entry));
+            }
+
+            self.bind(key, &host_keys);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Keymap{
+    fn default() -> Self{
+        Keymap::cosmac_vip()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn binds_several_host_keys_to_a_key(){
+        let mut keymap = Keymap::cosmac_vip();
+        keymap.apply("5 = W | Up, 8=S|Down").unwrap();
+
+        assert_eq!(keymap.host_keys(0x5), ["W", "Up"]);
+        assert_eq!(keymap.host_keys(0x8), ["S", "Down"]);
+        // keys that are not mentioned keep their bindings
+        assert_eq!(keymap.host_keys(0x1), ["1"]);
+    }
+
+    #[test]
+    fn accepts_hex_keys_with_or_without_0x(){
+        let mut keymap = Keymap::empty();
+        keymap.apply("0xa=Space,F=Return,0x0=0").unwrap();
+
+        assert_eq!(keymap.host_keys(0xA), ["Space"]);
+        assert_eq!(keymap.host_keys(0xF), ["Return"]);
+        assert_eq!(keymap.host_keys(0x0), ["0"]);
+    }
+
+    #[test]
+    fn takes_a_host_key_away_from_its_old_key(){
+        let mut keymap = Keymap::cosmac_vip();
+        keymap.apply("6=w").unwrap();
+
+        assert!(keymap.host_keys(0x5).is_empty());
+        assert_eq!(keymap.bindings().filter(|(host_key, _)| host_key.eq_ignore_ascii_case("W")).count(), 1);
+    }
+
+    #[test]
+    fn rejects_keys_outside_the_keypad(){
+        for spec in ["10=Q", "0x10=Q", "G=Q", "-1=Q", "+5=Q", "0x=Q", "=Q"]{
+            let error = Keymap::cosmac_vip().apply(spec).unwrap_err();
+            assert!(error.contains("is not a keypad key"), "{}: {}", spec, error);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_entries(){
+        assert!(Keymap::cosmac_vip().apply("5").unwrap_err().contains("should look like"));
+        assert!(Keymap::cosmac_vip().apply("5=W,8").is_err());
+        assert_eq!(Keymap::cosmac_vip().apply("5=").unwrap_err(), "Key binding '5=' names no host keys.");
+        assert!(Keymap::cosmac_vip().apply("5= | ").is_err());
+        // empty entries are skipped
+        assert!(Keymap::cosmac_vip().apply(",5=W,,").is_ok());
+    }
+}
@@ -0,0 +1,32 @@
+// the emulation core lives in its own no_std crate
+pub use chip8_core::{framebuffer, machine, symbols};
+
+pub mod assembler;
+pub mod capture;
+pub mod config;
+pub mod dashboard;
+pub mod disasm;
+pub mod emulator;
+pub mod env;
+pub mod expression;
+pub mod ffi;
+pub mod frontend;
+pub mod gamepad;
+pub mod hotkeys;
+pub mod keymap;
+pub mod libretro;
+pub mod movie;
+pub mod net;
+pub mod netplay;
+pub mod palette;
+pub mod persistence;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod rewind;
+pub mod rpc;
+pub mod savestate;
+pub mod scheduler;
+pub mod spectate;
+pub mod terminal;
+pub mod timing;
+pub mod viewport;
@@ -0,0 +1,706 @@
+use std::collections::HashMap;
+use std::ffi::{c_char, c_uint, c_void, CStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::framebuffer::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::gamepad;
+use crate::keymap::Keymap;
+use crate::machine::{self, Machine, Quirks};
+use crate::palette::Palette;
+use crate::savestate::MAX_STATE_SIZE;
+use crate::scheduler::{Speed, FRAMES_PER_SECOND};
+use crate::timing::{self, VipTiming};
+
+/*
+ * The emulator as a libretro core, so RetroArch and other libretro frontends
+ * can host it with their own shaders, netplay and input handling. Building
+ * the library produces the core, e.g. target/release/libchip8_emulator.so,
+ * which frontends expect to be renamed to chip8_libretro.so.
+ *
+ * Only the parts of libretro.h the core uses are declared here. The frontend
+ * calls everything from one thread, and the Mutexes are only there because
+ * statics have to be Sync. A panic unwinding into the frontend would abort
+ * it, so entry points that run the emulator catch them and unload the game.
+ */
+
+pub const RETRO_API_VERSION: c_uint = 1;
+
+pub const RETRO_DEVICE_JOYPAD: c_uint = 1;
+pub const RETRO_DEVICE_KEYBOARD: c_uint = 3;
+
+pub const RETRO_DEVICE_ID_JOYPAD_B: c_uint = 0;
+pub const RETRO_DEVICE_ID_JOYPAD_Y: c_uint = 1;
+pub const RETRO_DEVICE_ID_JOYPAD_SELECT: c_uint = 2;
+pub const RETRO_DEVICE_ID_JOYPAD_START: c_uint = 3;
+pub const RETRO_DEVICE_ID_JOYPAD_UP: c_uint = 4;
+pub const RETRO_DEVICE_ID_JOYPAD_DOWN: c_uint = 5;
+pub const RETRO_DEVICE_ID_JOYPAD_LEFT: c_uint = 6;
+pub const RETRO_DEVICE_ID_JOYPAD_RIGHT: c_uint = 7;
+pub const RETRO_DEVICE_ID_JOYPAD_A: c_uint = 8;
+pub const RETRO_DEVICE_ID_JOYPAD_X: c_uint = 9;
+pub const RETRO_DEVICE_ID_JOYPAD_L: c_uint = 10;
+pub const RETRO_DEVICE_ID_JOYPAD_R: c_uint = 11;
+
+pub const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+pub const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+pub const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+pub const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+
+pub const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+pub const RETRO_REGION_NTSC: c_uint = 0;
+pub const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+pub const SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / FRAMES_PER_SECOND) as usize;
+// the buzzer is a square wave, kept quiet
+const BEEP_HZ: u32 = 440;
+const BEEP_VOLUME: i16 = 3000;
+
+#[repr(C)]
+pub struct RetroSystemInfo{
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry{
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming{
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo{
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo{
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+#[repr(C)]
+pub struct RetroVariable{
+    pub key: *const c_char,
+    pub value: *const c_char,
+}
+
+pub type RetroEnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type RetroVideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type RetroAudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+pub type RetroAudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type RetroInputPollFn = unsafe extern "C" fn();
+pub type RetroInputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+/// The RetroPad buttons by their SDL game controller names, so the usual
+/// gamepad bindings carry over.
+const JOYPAD_BUTTONS: [(c_uint, &str); 12] = [
+    (RETRO_DEVICE_ID_JOYPAD_UP, "dpup"),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, "dpdown"),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, "dpleft"),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, "dpright"),
+    (RETRO_DEVICE_ID_JOYPAD_A, "a"),
+    (RETRO_DEVICE_ID_JOYPAD_B, "b"),
+    (RETRO_DEVICE_ID_JOYPAD_X, "x"),
+    (RETRO_DEVICE_ID_JOYPAD_Y, "y"),
+    (RETRO_DEVICE_ID_JOYPAD_START, "start"),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, "back"),
+    (RETRO_DEVICE_ID_JOYPAD_L, "leftshoulder"),
+    (RETRO_DEVICE_ID_JOYPAD_R, "rightshoulder"),
+];
+
+/// Core options shown in the frontend's menu; the first value is the default.
+const VARIABLES: [(&CStr, &CStr); 3] = [
+    (c"chip8_speed", c"Speed; vip|schip|vip-cycles"),
+    (c"chip8_palette", c"Palette; classic|amber|lcd|octo"),
+    (c"chip8_shift_quirk", c"8XY6/8XYE shift VX in place; enabled|disabled"),
+];
+
+#[derive(Clone, Copy)]
+struct Callbacks{
+    environment: Option<RetroEnvironmentFn>,
+    video_refresh: Option<RetroVideoRefreshFn>,
+    audio_sample_batch: Option<RetroAudioSampleBatchFn>,
+    input_poll: Option<RetroInputPollFn>,
+    input_state: Option<RetroInputStateFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn callbacks() -> Callbacks{
+    *CALLBACKS.lock().unwrap()
+}
+
+fn lock_core() -> MutexGuard<'static, Option<Core>>{
+    // a panic while the lock was held unloads the game, so what is left is safe to use
+    CORE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Runs the body of an entry point, returning `fallback` and unloading the
+/// game if it panics.
+fn catch_panic<T>(entry_point: &str, fallback: T, body: impl FnOnce() -> T) -> T{
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| {
+        eprintln!("{} panicked, unloading the game.", entry_point);
+        *lock_core() = None;
+        fallback
+    })
+}
+
+/// The loaded game and everything a frame needs.
+struct Core{
+    machine: Machine,
+    content: Vec<u8>,
+    speed: Speed,
+    vip_timing: VipTiming,
+    palette: Palette,
+    joypad_keys: Vec<(c_uint, u8)>,
+    keyboard_keys: Vec<(c_uint, u8)>,
+    video: Vec<u32>,
+    audio: Vec<i16>,
+    // set when the program faults; frames stop running until a reset or a state is loaded
+    halted: bool,
+    // position in the beep's square wave, in samples
+    audio_phase: u32,
+}
+
+impl Core{
+    fn new(content: Vec<u8>) -> Result<Core, String>{
+        let joypad_names: HashMap<&str, c_uint> = JOYPAD_BUTTONS.iter()
+            .map(|(id, name)| (*name, *id))
+            .collect();
+        let joypad_keys = gamepad::default_bindings().bindings()
+            .filter_map(|(name, key)| joypad_names.get(name).map(|id| (*id, key)))
+            .collect();
+        // RETROK codes match ASCII for letters and digits, in lower case
+        let keyboard_keys = Keymap::cosmac_vip().bindings()
+            .filter(|(name, _)| name.len() == 1)
+            .map(|(name, key)| (name.to_ascii_lowercase().as_bytes()[0] as c_uint, key))
+            .collect();
+
+        let mut core = Core {
+            machine: Machine::new(Quirks::default(), 0),
+            content,
+            speed: Speed::VIP,
+            vip_timing: VipTiming::new(),
+            palette: Palette::default(),
+            joypad_keys,
+            keyboard_keys,
+            video: Vec::new(),
+            audio: vec![0; SAMPLES_PER_FRAME * 2],
+            audio_phase: 0,
+            halted: false,
+        };
+        core.apply_variables();
+        core.reset()?;
+
+        Ok(core)
+    }
+
+    /// Powers the machine back on. Random numbers are seeded from the ROM so
+    /// that every run is the same, which netplay and run-ahead depend on.
+    fn reset(&mut self) -> Result<(), String>{
+        let mut machine = Machine::new(self.machine.quirks, machine::rom_hash(&self.content) as u32);
+        machine.load_rom(&self.content)?;
+        machine.trace = false;
+
+        self.machine = machine;
+        self.vip_timing = VipTiming::new();
+        self.halted = false;
+
+        Ok(())
+    }
+
+    /// Reads the core options. Unknown values keep the current setting.
+    fn apply_variables(&mut self){
+        let speed = get_variable(c"chip8_speed")
+            .and_then(|value| value.parse().ok())
+            .and_then(|speed| timing::paced_speed(speed).ok());
+        if let Some(speed) = speed{
+            self.speed = speed;
+        }
+        if let Some(palette) = get_variable(c"chip8_palette").and_then(|value| Palette::theme(&value)){
+            self.palette = palette;
+            self.machine.screen_changed = true;
+        }
+        if let Some(value) = get_variable(c"chip8_shift_quirk"){
+            self.machine.quirks.ignore_y_in_8xy_shift_instruction = value == "enabled";
+        }
+    }
+
+    fn run_frame(&mut self, input_state: Option<RetroInputStateFn>){
+        let mut keys = Vec::new();
+        if let Some(input_state) = input_state{
+            for (id, key) in &self.joypad_keys{
+                if unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, *id) } != 0{
+                    keys.push(*key);
+                }
+            }
+            for (code, key) in &self.keyboard_keys{
+                if unsafe { input_state(0, RETRO_DEVICE_KEYBOARD, 0, *code) } != 0{
+                    keys.push(*key);
+                }
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        self.machine.keypad = keys;
+
+        if self.halted{
+            return;
+        }
+        if let Err(e) = timing::run_paced_frame(&mut self.machine, self.speed, &mut self.vip_timing){
+            eprintln!("Machine halted: {}", e);
+            self.halted = true;
+        }
+    }
+
+    /// Converts the framebuffer to XRGB8888.
+    fn draw(&mut self){
+        let background = xrgb(&self.palette, false);
+        let foreground = xrgb(&self.palette, true);
+
+        self.video.clear();
+        self.video.extend(self.machine.framebuffer.pixels.iter()
+            .map(|pixel| if *pixel { foreground } else { background }));
+        self.machine.screen_changed = false;
+    }
+
+    /// One frame of audio: the buzzer while the sound timer runs, else silence.
+    fn mix_audio(&mut self){
+        let half_period = SAMPLE_RATE / BEEP_HZ / 2;
+
+        for frame in self.audio.chunks_mut(2){
+            let sample = if self.machine.sound_timer > 0{
+                self.audio_phase = (self.audio_phase + 1) % (half_period * 2);
+                if self.audio_phase < half_period { BEEP_VOLUME } else { -BEEP_VOLUME }
+            } else{
+                0
+            };
+            frame.fill(sample);
+        }
+    }
+}
+
+fn xrgb(palette: &Palette, lit: bool) -> u32{
+    let colour = if lit { palette.foreground() } else { palette.background() };
+    (colour.red as u32) << 16 | (colour.green as u32) << 8 | colour.blue as u32
+}
+
+/// The current value of a core option, if the frontend knows it.
+fn get_variable(key: &CStr) -> Option<String>{
+    let environment = callbacks().environment?;
+    let mut variable = RetroVariable { key: key.as_ptr(), value: std::ptr::null() };
+
+    let found = unsafe { environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable as *mut _ as *mut c_void) };
+    if !found || variable.value.is_null(){
+        return None;
+    }
+
+    Some(unsafe { CStr::from_ptr(variable.value) }.to_string_lossy().into_owned())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint{
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: RetroEnvironmentFn){
+    CALLBACKS.lock().unwrap().environment = Some(callback);
+
+    let mut variables: Vec<RetroVariable> = VARIABLES.iter()
+        .map(|(key, value)| RetroVariable { key: key.as_ptr(), value: value.as_ptr() })
+        .collect();
+    variables.push(RetroVariable { key: std::ptr::null(), value: std::ptr::null() });
+
+    unsafe { callback(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void) };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: RetroVideoRefreshFn){
+    CALLBACKS.lock().unwrap().video_refresh = Some(callback);
+}
+
+/// Unused, the core sends each frame's audio in one batch.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: RetroAudioSampleFn){
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: RetroAudioSampleBatchFn){
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: RetroInputPollFn){
+    CALLBACKS.lock().unwrap().input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: RetroInputStateFn){
+    CALLBACKS.lock().unwrap().input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init(){
+}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit(){
+    *lock_core() = None;
+}
+
+/// # Safety
+///
+/// `info` must point to a writable `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo){
+    let Some(info) = info.as_mut() else {
+        return;
+    };
+
+    info.library_name = c"CHIP-8".as_ptr();
+    info.library_version = concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char;
+    info.valid_extensions = c"ch8|c8".as_ptr();
+    info.need_fullpath = false;
+    info.block_extract = false;
+}
+
+/// # Safety
+///
+/// `info` must point to a writable `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo){
+    let Some(info) = info.as_mut() else {
+        return;
+    };
+
+    info.geometry = RetroGameGeometry {
+        base_width: SCREEN_WIDTH,
+        base_height: SCREEN_HEIGHT,
+        max_width: SCREEN_WIDTH * 2,
+        max_height: SCREEN_HEIGHT * 2,
+        aspect_ratio: 2.0,
+    };
+    info.timing = RetroSystemTiming {
+        fps: FRAMES_PER_SECOND as f64,
+        sample_rate: SAMPLE_RATE as f64,
+    };
+}
+
+/// Only the one keypad, so every device is read the same way.
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint){
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset(){
+    catch_panic("retro_reset", (), || {
+        if let Some(core) = lock_core().as_mut(){
+            if let Err(e) = core.reset(){
+                eprintln!("Could not reset: {}", e);
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run(){
+    catch_panic("retro_run", (), || {
+        let callbacks = callbacks();
+
+        // the frontend may call back into the core from these, e.g. for the
+        // RAM, so they run while the core is unlocked and get copies
+        let mut updated = false;
+        if let Some(environment) = callbacks.environment{
+            unsafe { environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut bool as *mut c_void) };
+        }
+        if let Some(input_poll) = callbacks.input_poll{
+            unsafe { input_poll() };
+        }
+
+        let (video, width, height, audio) = {
+            let mut core = lock_core();
+            let Some(core) = core.as_mut() else {
+                return;
+            };
+
+            if updated{
+                core.apply_variables();
+            }
+            core.run_frame(callbacks.input_state);
+            core.draw();
+            core.mix_audio();
+
+            let framebuffer = &core.machine.framebuffer;
+            (core.video.clone(), framebuffer.width, framebuffer.height, core.audio.clone())
+        };
+
+        if let Some(video_refresh) = callbacks.video_refresh{
+            unsafe { video_refresh(video.as_ptr() as *const c_void, width, height, width as usize * 4) };
+        }
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch{
+            unsafe { audio_sample_batch(audio.as_ptr(), audio.len() / 2) };
+        }
+    })
+}
+
+/// States are padded to a fixed size, which libretro needs to know up front.
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize{
+    MAX_STATE_SIZE
+}
+
+/// # Safety
+///
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool{
+    catch_panic("retro_serialize", false, || {
+        let core = lock_core();
+        let Some(core) = core.as_ref() else {
+            return false;
+        };
+
+        let state = core.machine.save_state();
+        if data.is_null() || state.len() > size{
+            return false;
+        }
+
+        let buffer = std::slice::from_raw_parts_mut(data as *mut u8, size);
+        buffer[..state.len()].copy_from_slice(&state);
+        buffer[state.len()..].fill(0);
+
+        true
+    })
+}
+
+/// # Safety
+///
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool{
+    catch_panic("retro_unserialize", false, || {
+        let mut core = lock_core();
+        let Some(core) = core.as_mut() else {
+            return false;
+        };
+        if data.is_null(){
+            return false;
+        }
+
+        // the padding after the state is ignored
+        let state = std::slice::from_raw_parts(data as *const u8, size);
+        // only states with a 64x32 or 128x64 screen load, which the geometry covers
+        match core.machine.load_state(state) {
+            Ok(()) =>{
+                core.halted = false;
+                true
+            },
+            Err(e) =>{
+                eprintln!("Could not load state: {}", e);
+                false
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset(){
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char){
+}
+
+/// # Safety
+///
+/// `game` must be null or point to a `retro_game_info` whose data is `size`
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool{
+    catch_panic("retro_load_game", false, || {
+        let Some(game) = game.as_ref() else {
+            return false;
+        };
+        if game.data.is_null(){
+            return false;
+        }
+
+        if let Some(environment) = callbacks().environment{
+            let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+            if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void){
+                eprintln!("The frontend does not support XRGB8888.");
+                return false;
+            }
+        }
+
+        let content = std::slice::from_raw_parts(game.data as *const u8, game.size).to_vec();
+        match Core::new(content) {
+            Ok(core) =>{
+                *lock_core() = Some(core);
+                true
+            },
+            Err(e) =>{
+                eprintln!("Could not load game: {}", e);
+                false
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool{
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game(){
+    *lock_core() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint{
+    RETRO_REGION_NTSC
+}
+
+/// Exposes the 4 KB of RAM for cheats and achievements.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void{
+    let mut core = lock_core();
+    match core.as_mut() {
+        Some(core) if id == RETRO_MEMORY_SYSTEM_RAM => core.machine.ram.as_mut_ptr() as *mut c_void,
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize{
+    match lock_core().as_ref() {
+        Some(core) if id == RETRO_MEMORY_SYSTEM_RAM => core.machine.ram.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    // v0 := 0, point I at its font glyph, draw it, sound timer := 60, then halt on an empty stack
+    const ROM: [u8; 12] = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x61, 0x3C, 0xF1, 0x18, 0x00, 0xEE];
+
+    fn core() -> Core{
+        let mut core = Core::new(ROM.to_vec()).unwrap();
+        core.speed = Speed::InstructionsPerFrame(5);
+        core
+    }
+
+    #[test]
+    fn draws_the_screen_in_the_palette(){
+        let mut core = core();
+        core.run_frame(None);
+        core.draw();
+
+        let foreground = xrgb(&core.palette, true);
+        let background = xrgb(&core.palette, false);
+        assert_eq!(core.video.len(), (SCREEN_WIDTH * SCREEN_HEIGHT) as usize);
+        // the top row of the 0 glyph is 0xF0
+        assert_eq!(&core.video[..5], &[foreground, foreground, foreground, foreground, background]);
+        assert!(!core.machine.screen_changed);
+    }
+
+    #[test]
+    fn beeps_while_the_sound_timer_runs(){
+        let mut core = core();
+        core.mix_audio();
+        assert!(core.audio.iter().all(|sample| *sample == 0));
+
+        core.run_frame(None);
+        core.mix_audio();
+        assert_eq!(core.audio.len(), SAMPLES_PER_FRAME * 2);
+        assert!(core.audio.contains(&BEEP_VOLUME) && core.audio.contains(&-BEEP_VOLUME));
+        // both channels carry the same sample
+        assert!(core.audio.chunks(2).all(|frame| frame[0] == frame[1]));
+    }
+
+    #[test]
+    fn halts_on_a_fault_until_reset(){
+        let mut core = core();
+        core.run_frame(None);
+        core.run_frame(None);
+        assert!(core.halted);
+
+        let program_counter = core.machine.program_counter;
+        core.run_frame(None);
+        assert_eq!(core.machine.program_counter, program_counter);
+
+        core.reset().unwrap();
+        assert!(!core.halted);
+        assert_eq!(core.machine.program_counter, machine::PROGRAM_START);
+    }
+
+    static RAM_SEEN_BY_FRONTEND: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn video_refresh(_data: *const c_void, _width: c_uint, _height: c_uint, _pitch: usize){
+        RAM_SEEN_BY_FRONTEND.store(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), Ordering::Relaxed);
+    }
+
+    // the only test that uses the core through the entry points, as they share one
+    #[test]
+    fn entry_points_save_and_load_states_and_allow_calls_back_in(){
+        *lock_core() = Some(core());
+        retro_set_video_refresh(video_refresh);
+
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            retro_run();
+            let _ = done.send(());
+        });
+        finished.recv_timeout(Duration::from_secs(10)).expect("retro_run deadlocked");
+        assert_eq!(RAM_SEEN_BY_FRONTEND.load(Ordering::Relaxed), machine::RAM_SIZE);
+
+        let mut state = vec![0xAA; retro_serialize_size()];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+        let saved = lock_core().as_ref().unwrap().machine.save_state();
+        assert_eq!(&state[..saved.len()], &saved[..]);
+        assert!(state[saved.len()..].iter().all(|byte| *byte == 0));
+        assert!(!unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, saved.len() - 1) });
+
+        retro_run();
+        assert!(lock_core().as_ref().unwrap().halted);
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+        let core = lock_core();
+        let core = core.as_ref().unwrap();
+        assert_eq!(core.machine.save_state(), saved);
+        assert!(!core.halted);
+    }
+}
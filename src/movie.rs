@@ -0,0 +1,350 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::machine::{hash_bytes, Machine, Quirks};
+use crate::timing;
+
+/*
+ * Movie files (big endian): "C8MV", version u16, ROM hash u64, quirk flags u8
+ * and seed u32, then one record per frame until the end of the file:
+ *   key mask u16, instructions u32, checksum u64
+ * The checksum is of the machine at the end of the frame, so a replay finds
+ * the exact frame it went wrong on. With no counts up front, recording just
+ * appends to the file.
+ */
+
+pub const MOVIE_MAGIC: &[u8; 4] = b"C8MV";
+pub const MOVIE_VERSION: u16 = 2;
+const HEADER_SIZE: usize = 4 + 2 + 8 + 1 + 4;
+const FRAME_RECORD_SIZE: usize = 2 + 4 + 8;
+
+/// Inputs for one 60 Hz frame: the keypad as a bitmask (bit N = key N held)
+/// and how many instructions ran before the timers ticked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MovieFrame{
+    pub keys: u16,
+    pub instructions: u32,
+}
+
+/// A recording that starts from power-on, so the ROM hash, quirks and RNG
+/// seed are enough to rebuild the starting machine.
+#[derive(Clone, Debug)]
+pub struct Movie{
+    pub rom_hash: u64,
+    pub quirks: Quirks,
+    pub seed: u32,
+    pub frames: Vec<MovieFrame>,
+    /// The state checksum at the end of each frame.
+    pub checksums: Vec<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence{
+    pub frame: u32,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+pub fn keys_to_mask(keys: &[u8]) -> u16{
+    keys.iter().fold(0, |mask, key| mask | 1 << (key & 0xF))
+}
+
+pub fn mask_to_keys(mask: u16) -> Vec<u8>{
+    (0..16).filter(|key| mask >> key & 1 == 1).collect()
+}
+
+pub fn state_checksum(machine: &Machine) -> u64{
+    hash_bytes(&machine.save_state())
+}
+
+impl Movie{
+    pub fn new(rom_hash: u64, quirks: Quirks, seed: u32) -> Movie{
+        Movie {
+            rom_hash,
+            quirks,
+            seed,
+            frames: Vec::new(),
+            checksums: Vec::new(),
+        }
+    }
+
+    /// Appends a finished frame along with a checksum of the machine.
+    pub fn record_frame(&mut self, keys: &[u8], instructions: u32, machine: &Machine){
+        self.frames.push(MovieFrame { keys: keys_to_mask(keys), instructions });
+        self.checksums.push(state_checksum(machine));
+    }
+
+    /// Compares the machine against the checksum recorded for this frame.
+    pub fn verify_frame(&self, frame: u32, machine: &Machine) -> Result<(), Divergence>{
+        match self.checksums.get(frame as usize) {
+            Some(&expected) =>{
+                let actual = state_checksum(machine);
+                if actual != expected{
+                    return Err(Divergence { frame, expected, actual });
+                }
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.frames.len() * FRAME_RECORD_SIZE);
+
+        data.extend_from_slice(MOVIE_MAGIC);
+        data.extend_from_slice(&MOVIE_VERSION.to_be_bytes());
+        data.extend_from_slice(&self.rom_hash.to_be_bytes());
+        data.push(self.quirks.ignore_y_in_8xy_shift_instruction as u8);
+        data.extend_from_slice(&self.seed.to_be_bytes());
+
+        for frame in 0..self.frames.len(){
+            data.extend_from_slice(&self.frame_record(frame));
+        }
+
+        data
+    }
+
+    fn frame_record(&self, frame: usize) -> Vec<u8>{
+        let mut record = Vec::with_capacity(FRAME_RECORD_SIZE);
+        record.extend_from_slice(&self.frames[frame].keys.to_be_bytes());
+        record.extend_from_slice(&self.frames[frame].instructions.to_be_bytes());
+        record.extend_from_slice(&self.checksums[frame].to_be_bytes());
+        record
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, String>{
+        if !data.starts_with(MOVIE_MAGIC){
+            return Err("Not a movie file.".to_string());
+        }
+        if data.len() < HEADER_SIZE{
+            return Err("Movie file is truncated.".to_string());
+        }
+        let (header, records) = data.split_at(HEADER_SIZE);
+
+        let version = u16::from_be_bytes(header[4..6].try_into().unwrap());
+        if version != MOVIE_VERSION{
+            return Err(format!("Movie version {} is not supported (expected {}).", version, MOVIE_VERSION));
+        }
+
+        let rom_hash = u64::from_be_bytes(header[6..14].try_into().unwrap());
+        let quirks = Quirks { ignore_y_in_8xy_shift_instruction: header[14] != 0 };
+        let seed = u32::from_be_bytes(header[15..19].try_into().unwrap());
+
+        if !records.len().is_multiple_of(FRAME_RECORD_SIZE){
+            return Err("Movie file is truncated.".to_string());
+        }
+        let frame_count = records.len() / FRAME_RECORD_SIZE;
+        let mut frames = Vec::with_capacity(frame_count);
+        let mut checksums = Vec::with_capacity(frame_count);
+        for record in records.chunks(FRAME_RECORD_SIZE){
+            let keys = u16::from_be_bytes(record[0..2].try_into().unwrap());
+            let instructions = u32::from_be_bytes(record[2..6].try_into().unwrap());
+            frames.push(MovieFrame { keys, instructions });
+            checksums.push(u64::from_be_bytes(record[6..14].try_into().unwrap()));
+        }
+
+        Ok(Movie { rom_hash, quirks, seed, frames, checksums })
+    }
+
+    pub fn load(path: &Path) -> Result<Movie, String>{
+        let data = fs::read(path)
+            .map_err(|e| format!("Could not read movie {}: {}", path.display(), e))?;
+
+        Movie::from_bytes(&data)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String>{
+        fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Could not write movie {}: {}", path.display(), e))
+    }
+
+    /// Builds the machine the movie was recorded on.
+    pub fn create_machine(&self, rom: &[u8]) -> Result<Machine, String>{
+        let mut machine = Machine::new(self.quirks, self.seed);
+        machine.load_rom(rom)?;
+
+        if machine.rom_hash != self.rom_hash{
+            return Err(format!("Movie was recorded on ROM {:016x}, not {:016x}.", self.rom_hash, machine.rom_hash));
+        }
+
+        Ok(machine)
+    }
+}
+
+/// Writes a movie out as it is recorded, so that stopping the emulator in
+/// any way loses at most the frame in progress.
+pub struct MovieRecorder{
+    pub movie: Movie,
+    path: PathBuf,
+    file: File,
+}
+
+impl MovieRecorder{
+    pub fn create(path: &Path, movie: Movie) -> Result<MovieRecorder, String>{
+        let mut file = File::create(path)
+            .map_err(|e| format!("Could not create movie {}: {}", path.display(), e))?;
+        file.write_all(&movie.to_bytes())
+            .map_err(|e| format!("Could not write movie {}: {}", path.display(), e))?;
+
+        Ok(MovieRecorder { movie, path: path.to_path_buf(), file })
+    }
+
+    /// Records a finished frame and appends it to the file.
+    pub fn record_frame(&mut self, keys: &[u8], instructions: u32, machine: &Machine) -> Result<(), String>{
+        self.movie.record_frame(keys, instructions, machine);
+        let record = self.movie.frame_record(self.movie.frames.len() - 1);
+
+        self.file.write_all(&record)
+            .map_err(|e| format!("Could not write movie {}: {}", self.path.display(), e))
+    }
+
+    pub fn path(&self) -> &Path{
+        &self.path
+    }
+}
+
+/// Steps through a movie frame by frame for live playback.
+pub struct MoviePlayer{
+    pub movie: Movie,
+    pub frame: u32,
+    pub divergence: Option<Divergence>,
+}
+
+impl MoviePlayer{
+    pub fn new(movie: Movie) -> MoviePlayer{
+        MoviePlayer { movie, frame: 0, divergence: None }
+    }
+
+    pub fn is_finished(&self) -> bool{
+        self.frame as usize >= self.movie.frames.len()
+    }
+
+    pub fn current_frame(&self) -> Option<MovieFrame>{
+        self.movie.frames.get(self.frame as usize).copied()
+    }
+
+    /// Finishes the current frame, remembering the first frame whose state diverged.
+    pub fn end_frame(&mut self, machine: &Machine) -> Result<(), Divergence>{
+        let result = self.movie.verify_frame(self.frame, machine);
+        if let Err(divergence) = &result{
+            self.divergence.get_or_insert_with(|| divergence.clone());
+        }
+        self.frame += 1;
+        result
+    }
+}
+
+/// Replays a whole movie headlessly, stopping at the first diverging frame.
+/// Returns the number of frames played.
+pub fn replay(machine: &mut Machine, movie: &Movie) -> Result<u32, Divergence>{
+    replay_with(machine, movie, |_| {})
+}
+
+/// Like `replay`, calling `on_frame` with the machine at the end of every frame.
+pub fn replay_with(machine: &mut Machine, movie: &Movie, mut on_frame: impl FnMut(&Machine)) -> Result<u32, Divergence>{
+    for (frame_number, frame) in movie.frames.iter().enumerate(){
+        machine.keypad = mask_to_keys(frame.keys);
+        // frames that fault are never recorded, so a fault here means the
+        // run has already gone its own way, which the checksums catch
+        let _ = timing::run_instructions(machine, frame.instructions);
+        machine.tick_timers();
+        on_frame(machine);
+
+        movie.verify_frame(frame_number as u32, machine)?;
+    }
+
+    Ok(movie.frames.len() as u32)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // V0 = random, V1 += 1, skip unless key V0 is held, V2 += 1, jump 0x202
+    const ROM: [u8; 10] = [0xC0, 0x0F, 0x71, 0x01, 0xE0, 0xA1, 0x72, 0x01, 0x12, 0x02];
+
+    fn record(frames: u32) -> Movie{
+        let mut movie = Movie::new(crate::machine::rom_hash(&ROM), Quirks::default(), 42);
+        let mut machine = movie.create_machine(&ROM).unwrap();
+        machine.trace = false;
+
+        for frame in 0..frames{
+            let keys: Vec<u8> = (0..16).filter(|key| (frame + *key as u32).is_multiple_of(3)).collect();
+            machine.keypad = keys.clone();
+            timing::run_instructions(&mut machine, 11).unwrap();
+            machine.tick_timers();
+            movie.record_frame(&keys, 11, &machine);
+        }
+        movie
+    }
+
+    #[test]
+    fn round_trip(){
+        let movie = record(5);
+        let data = movie.to_bytes();
+        assert_eq!(data.len(), HEADER_SIZE + 5 * FRAME_RECORD_SIZE);
+
+        let loaded = Movie::from_bytes(&data).unwrap();
+        assert_eq!(loaded.rom_hash, movie.rom_hash);
+        assert_eq!(loaded.seed, 42);
+        assert_eq!(loaded.frames, movie.frames);
+        assert_eq!(loaded.checksums, movie.checksums);
+    }
+
+    #[test]
+    fn rejects_broken_files(){
+        let data = record(2).to_bytes();
+
+        assert!(Movie::from_bytes(b"C8SP").is_err());
+        assert!(Movie::from_bytes(&data[..HEADER_SIZE - 1]).is_err());
+        assert!(Movie::from_bytes(&data[..data.len() - 1]).is_err());
+
+        let mut other_version = data.clone();
+        other_version[5] = 1;
+        assert!(Movie::from_bytes(&other_version).is_err());
+
+        // a header alone is a movie with no frames
+        assert_eq!(Movie::from_bytes(&data[..HEADER_SIZE]).unwrap().frames.len(), 0);
+    }
+
+    #[test]
+    fn replays_without_diverging(){
+        let movie = record(20);
+        let mut machine = movie.create_machine(&ROM).unwrap();
+        machine.trace = false;
+        assert_eq!(replay(&mut machine, &movie), Ok(20));
+    }
+
+    #[test]
+    fn reports_the_exact_frame_that_diverged(){
+        let mut movie = record(20);
+        movie.frames[13].keys ^= 1;
+
+        let mut machine = movie.create_machine(&ROM).unwrap();
+        machine.trace = false;
+        let divergence = replay(&mut machine, &movie).unwrap_err();
+        assert_eq!(divergence.frame, 13);
+    }
+
+    #[test]
+    fn recorder_appends_each_frame(){
+        let path = std::env::temp_dir().join(format!("chip8-movie-test-{}.c8m", std::process::id()));
+        let movie = record(3);
+
+        let mut recorder = MovieRecorder::create(&path, Movie::new(movie.rom_hash, movie.quirks, movie.seed)).unwrap();
+        let mut machine = movie.create_machine(&ROM).unwrap();
+        machine.trace = false;
+        for frame in &movie.frames{
+            machine.keypad = mask_to_keys(frame.keys);
+            timing::run_instructions(&mut machine, frame.instructions).unwrap();
+            machine.tick_timers();
+            recorder.record_frame(&machine.keypad.clone(), frame.instructions, &machine).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), recorder.movie.to_bytes());
+        }
+
+        let loaded = Movie::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.checksums, movie.checksums);
+    }
+}
@@ -0,0 +1,138 @@
+use std::future::Future;
+use std::io::{self, ErrorKind, Read};
+use std::net::TcpListener as StdTcpListener;
+use std::thread;
+
+use tokio::net::{TcpListener, TcpStream};
+
+/// Who is at the other end of a connection, for telling the user what
+/// happened to it.
+pub struct Peer{
+    pub stopped_responding: &'static str,
+    pub left: &'static str,
+    /// Ends "Lost the connection to ...".
+    pub name: &'static str,
+}
+
+impl Peer{
+    pub fn connection_error(&self, error: io::Error) -> String{
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => self.stopped_responding.to_string(),
+            ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => self.left.to_string(),
+            _ => format!("Lost the connection to {}: {}", self.name, error),
+        }
+    }
+}
+
+/// Reads the big endian numbers of the network protocols.
+pub struct Reader<R>{
+    inner: R,
+    peer: &'static Peer,
+}
+
+impl<R: Read> Reader<R>{
+    pub fn new(inner: R, peer: &'static Peer) -> Reader<R>{
+        Reader { inner, peer }
+    }
+
+    pub fn get_ref(&self) -> &R{
+        &self.inner
+    }
+
+    pub fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), String>{
+        self.inner.read_exact(buffer).map_err(|e| self.peer.connection_error(e))
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String>{
+        let mut bytes = [0; 1];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String>{
+        let mut bytes = [0; 2];
+        self.read_exact(&mut bytes)?;
+        Ok(u16::from_be_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String>{
+        let mut bytes = [0; 4];
+        self.read_exact(&mut bytes)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String>{
+        let mut bytes = [0; 8];
+        self.read_exact(&mut bytes)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+/// Accepts connections on 127.0.0.1 in a thread of its own and serves each
+/// as a task there. Port 0 picks a free one; the port listened on is
+/// returned. `name`, e.g. "dashboard", is used in messages.
+pub fn serve_locally<F, Fut>(port: u16, name: &'static str, serve: F) -> Result<u16, String>
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = StdTcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| format!("Could not listen on 127.0.0.1:{}: {}", port, e))?;
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .map_err(|e| format!("Could not start the {} runtime: {}", name, e))?;
+
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            let listener = match TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(e) => return println!("The {} stopped: {}", name, e),
+            };
+
+            loop{
+                match listener.accept().await {
+                    Ok((stream, _)) =>{
+                        tokio::spawn(serve(stream));
+                    },
+                    Err(e) => println!("A {} connection failed: {}", name, e),
+                }
+            }
+        })
+    });
+
+    Ok(port)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const PEER: Peer = Peer {
+        stopped_responding: "It stopped responding.",
+        left: "It left.",
+        name: "it",
+    };
+
+    #[test]
+    fn reads_big_endian_numbers(){
+        let bytes: &[u8] = &[7, 0x12, 0x34, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        let mut reader = Reader::new(bytes, &PEER);
+
+        assert_eq!(reader.read_u8(), Ok(7));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u32(), Ok(0x100));
+        assert_eq!(reader.read_u64(), Ok(2));
+        assert_eq!(reader.read_u8(), Err("It left.".to_string()));
+    }
+
+    #[test]
+    fn describes_errors_in_terms_of_the_peer(){
+        assert_eq!(PEER.connection_error(io::Error::from(ErrorKind::TimedOut)), "It stopped responding.");
+        assert_eq!(PEER.connection_error(io::Error::from(ErrorKind::ConnectionReset)), "It left.");
+        assert!(PEER.connection_error(io::Error::other("oops")).starts_with("Lost the connection to it: "));
+    }
+}
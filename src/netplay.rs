@@ -0,0 +1,409 @@
+use std::collections::BTreeMap;
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::machine::{Machine, Quirks};
+use crate::movie;
+use crate::net::{Peer, Reader};
+use crate::scheduler::Speed;
+
+/*
+ * Two-player netplay in lockstep. Both machines start from the same ROM,
+ * seed and settings and run frame by frame on the same inputs, so they stay
+ * identical without ever sending the screen.
+ *
+ * Each player owns half of the keypad, the left two columns of the COSMAC VIP
+ * layout for the host and the right two for the guest, which covers games
+ * like Pong where player one uses 1/4 and player two C/D. Keys pressed on
+ * frame N are sent to the other side and take effect on frame N + delay, so a
+ * round trip shorter than the delay never stalls the game. Every
+ * CHECKSUM_INTERVAL frames both sides send a hash of their machine to catch
+ * any desync.
+ *
+ * Wire format (big endian): the host sends "C8NP", version u16, ROM hash
+ * u64, seed u32, quirk flags u8, input delay u8 and instructions per frame
+ * u32 (0 for VIP cycle timing); the guest answers "C8NP", version u16 and
+ * its ROM hash u64. After that either side sends
+ *   1, frame u32, key mask u16      the sender's keys for that frame
+ *   2, frame u32, checksum u64      the sender's state after that frame
+ */
+
+const NETPLAY_MAGIC: &[u8; 4] = b"C8NP";
+const NETPLAY_VERSION: u16 = 1;
+const INPUT_MESSAGE: u8 = 1;
+const CHECKSUM_MESSAGE: u8 = 2;
+// frames between checksums, once a second at 60 Hz
+const CHECKSUM_INTERVAL: u32 = 60;
+// waiting longer than this for the other player's input ends the session
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+static OTHER_PLAYER: Peer = Peer {
+    stopped_responding: "The other player stopped responding.",
+    left: "The other player left.",
+    name: "the other player",
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Player{
+    One,
+    Two,
+}
+
+impl Player{
+    /// The half of the keypad this player controls, as a key mask.
+    pub fn keys(self) -> u16{
+        let keys: &[u8] = match self {
+            Player::One => &[0x1, 0x2, 0x4, 0x5, 0x7, 0x8, 0xA, 0x0],
+            Player::Two => &[0x3, 0x6, 0x9, 0xB, 0xC, 0xD, 0xE, 0xF],
+        };
+        movie::keys_to_mask(keys)
+    }
+
+    pub fn other(self) -> Player{
+        match self {
+            Player::One => Player::Two,
+            Player::Two => Player::One,
+        }
+    }
+}
+
+/// What both machines have to agree on before the first frame. The host
+/// decides and the guest takes it over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Session{
+    pub rom_hash: u64,
+    pub seed: u32,
+    pub quirks: Quirks,
+    pub speed: Speed,
+    pub input_delay: u32,
+}
+
+/// The machines differed after `frame`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Desync{
+    pub frame: u32,
+    pub local: u64,
+    pub remote: u64,
+}
+
+pub struct Netplay{
+    reader: Reader<BufReader<TcpStream>>,
+    writer: TcpStream,
+    pub player: Player,
+    pub input_delay: u32,
+    // the frame about to run
+    frame: u32,
+    local_inputs: BTreeMap<u32, u16>,
+    remote_inputs: BTreeMap<u32, u16>,
+    local_checksums: BTreeMap<u32, u64>,
+    remote_checksums: BTreeMap<u32, u64>,
+    desynced: bool,
+}
+
+impl Netplay{
+    /// Listens for the other player on `bind_address`, e.g. `127.0.0.1` for
+    /// this computer only or `0.0.0.0` for every network. Port 0 picks a free one.
+    pub fn listen(bind_address: &str, port: u16) -> Result<TcpListener, String>{
+        TcpListener::bind((bind_address, port))
+            .map_err(|e| format!("Could not listen on {}:{}: {}", bind_address, port, e))
+    }
+
+    /// Waits for the other player to join on a listener from `listen`.
+    pub fn host(listener: TcpListener, session: &Session) -> Result<Netplay, String>{
+        if session.speed == Speed::Uncapped{
+            return Err("Netplay needs a fixed number of instructions per frame, uncapped speed would desync.".to_string());
+        }
+        if session.input_delay > u8::MAX as u32{
+            return Err(format!("An input delay of {} frames is too long.", session.input_delay));
+        }
+
+        let (stream, _) = listener.accept()
+            .map_err(|e| format!("Could not accept the other player: {}", e))?;
+        let mut netplay = Netplay::new(stream, Player::One, session.input_delay)?;
+
+        let speed = match session.speed {
+            Speed::InstructionsPerFrame(instructions) => instructions,
+            _ => 0,
+        };
+        let mut hello = Vec::new();
+        hello.extend_from_slice(NETPLAY_MAGIC);
+        hello.extend_from_slice(&NETPLAY_VERSION.to_be_bytes());
+        hello.extend_from_slice(&session.rom_hash.to_be_bytes());
+        hello.extend_from_slice(&session.seed.to_be_bytes());
+        hello.push(session.quirks.ignore_y_in_8xy_shift_instruction as u8);
+        hello.push(session.input_delay as u8);
+        hello.extend_from_slice(&speed.to_be_bytes());
+        netplay.send(&hello)?;
+
+        netplay.read_greeting()?;
+        let rom_hash = netplay.reader.read_u64()?;
+        if rom_hash != session.rom_hash{
+            return Err(format!("The other player is running ROM {:016x}, not {:016x}.", rom_hash, session.rom_hash));
+        }
+
+        Ok(netplay)
+    }
+
+    /// Joins a host at `address`, e.g. `192.168.1.5:7000`, and returns the
+    /// session to start from.
+    pub fn join(address: &str, rom_hash: u64) -> Result<(Netplay, Session), String>{
+        let stream = TcpStream::connect(address)
+            .map_err(|e| format!("Could not connect to {}: {}", address, e))?;
+        let mut netplay = Netplay::new(stream, Player::Two, 0)?;
+
+        netplay.read_greeting()?;
+        let host_rom_hash = netplay.reader.read_u64()?;
+        let seed = netplay.reader.read_u32()?;
+        let quirks = Quirks { ignore_y_in_8xy_shift_instruction: netplay.reader.read_u8()? != 0 };
+        let input_delay = netplay.reader.read_u8()? as u32;
+        let speed = match netplay.reader.read_u32()? {
+            0 => Speed::VipCycles,
+            instructions => Speed::InstructionsPerFrame(instructions),
+        };
+
+        let mut answer = Vec::new();
+        answer.extend_from_slice(NETPLAY_MAGIC);
+        answer.extend_from_slice(&NETPLAY_VERSION.to_be_bytes());
+        answer.extend_from_slice(&rom_hash.to_be_bytes());
+        netplay.send(&answer)?;
+
+        if host_rom_hash != rom_hash{
+            return Err(format!("The host is running ROM {:016x}, not {:016x}.", host_rom_hash, rom_hash));
+        }
+
+        netplay.input_delay = input_delay;
+        Ok((netplay, Session { rom_hash, seed, quirks, speed, input_delay }))
+    }
+
+    fn new(stream: TcpStream, player: Player, input_delay: u32) -> Result<Netplay, String>{
+        // inputs are tiny and late ones stall the game
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(PEER_TIMEOUT)).map_err(|e| e.to_string())?;
+        let writer = stream.try_clone().map_err(|e| e.to_string())?;
+
+        Ok(Netplay {
+            reader: Reader::new(BufReader::new(stream), &OTHER_PLAYER),
+            writer,
+            player,
+            input_delay,
+            frame: 0,
+            local_inputs: BTreeMap::new(),
+            remote_inputs: BTreeMap::new(),
+            local_checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            desynced: false,
+        })
+    }
+
+    /// Sends this player's keys and returns the keypad for the frame about
+    /// to run, waiting for the other player's keys if they are not in yet.
+    pub fn exchange_inputs(&mut self, local_keys: &[u8]) -> Result<Vec<u8>, String>{
+        let mask = movie::keys_to_mask(local_keys) & self.player.keys();
+        let input_frame = self.frame + self.input_delay;
+        self.local_inputs.insert(input_frame, mask);
+
+        let mut message = vec![INPUT_MESSAGE];
+        message.extend_from_slice(&input_frame.to_be_bytes());
+        message.extend_from_slice(&mask.to_be_bytes());
+        self.send(&message)?;
+
+        // nobody pressed anything before the first delayed inputs arrive
+        let remote = if self.frame < self.input_delay{
+            0
+        } else{
+            while !self.remote_inputs.contains_key(&self.frame) {
+                self.receive()?;
+            }
+            self.remote_inputs.remove(&self.frame).unwrap()
+        };
+        let local = self.local_inputs.remove(&self.frame).unwrap_or(0);
+
+        Ok(movie::mask_to_keys(local | remote))
+    }
+
+    /// Called after each frame. Returns the first desync found, once.
+    pub fn end_frame(&mut self, machine: &Machine) -> Result<Option<Desync>, String>{
+        self.frame += 1;
+
+        if self.frame.is_multiple_of(CHECKSUM_INTERVAL){
+            let checksum = movie::state_checksum(machine);
+            self.local_checksums.insert(self.frame, checksum);
+
+            let mut message = vec![CHECKSUM_MESSAGE];
+            message.extend_from_slice(&self.frame.to_be_bytes());
+            message.extend_from_slice(&checksum.to_be_bytes());
+            self.send(&message)?;
+        }
+
+        let compared: Vec<u32> = self.local_checksums.keys()
+            .filter(|frame| self.remote_checksums.contains_key(frame))
+            .copied()
+            .collect();
+        let mut desync = None;
+        for frame in compared{
+            let local = self.local_checksums.remove(&frame).unwrap();
+            let remote = self.remote_checksums.remove(&frame).unwrap();
+            if local != remote && !self.desynced{
+                self.desynced = true;
+                desync = Some(Desync { frame, local, remote });
+            }
+        }
+
+        Ok(desync)
+    }
+
+    fn receive(&mut self) -> Result<(), String>{
+        match self.reader.read_u8()? {
+            INPUT_MESSAGE =>{
+                let frame = self.reader.read_u32()?;
+                let mask = self.reader.read_u16()? & self.player.other().keys();
+                self.remote_inputs.insert(frame, mask);
+            },
+            CHECKSUM_MESSAGE =>{
+                let frame = self.reader.read_u32()?;
+                let checksum = self.reader.read_u64()?;
+                self.remote_checksums.insert(frame, checksum);
+            },
+            other => return Err(format!("Unknown netplay message {}.", other)),
+        }
+
+        Ok(())
+    }
+
+    fn read_greeting(&mut self) -> Result<(), String>{
+        let mut magic = [0; 4];
+        self.reader.read_exact(&mut magic)?;
+        if &magic != NETPLAY_MAGIC{
+            return Err("The other side is not a CHIP-8 netplay session.".to_string());
+        }
+
+        let version = self.reader.read_u16()?;
+        if version != NETPLAY_VERSION{
+            return Err(format!("Netplay version {} is not supported (expected {}).", version, NETPLAY_VERSION));
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), String>{
+        self.writer.write_all(data).map_err(|e| OTHER_PLAYER.connection_error(e))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::thread;
+
+    use super::*;
+    use crate::machine;
+    use crate::timing;
+
+    // V0 = random key, V1 += 1, skip unless key V0 is held, V2 += 1, jump 0x202
+    const ROM: [u8; 10] = [0xC0, 0x0F, 0x71, 0x01, 0xE0, 0xA1, 0x72, 0x01, 0x12, 0x02];
+    const INPUT_DELAY: u32 = 3;
+    const FRAMES: u32 = CHECKSUM_INTERVAL + 10;
+
+    struct Played{
+        keypads: Vec<Vec<u8>>,
+        desyncs: Vec<Desync>,
+    }
+
+    /// Plays FRAMES frames, pressing `keys` on `press_frame` and flipping a
+    /// register on `perturb_frame`. Returns the connection too, so that it
+    /// stays open until the other side is done.
+    fn play(mut netplay: Netplay, session: Session, keys: &[u8], press_frame: u32, perturb_frame: Option<u32>) -> (Played, Netplay){
+        let mut machine = Machine::new(session.quirks, session.seed);
+        machine.trace = false;
+        machine.load_rom(&ROM).unwrap();
+        let mut played = Played { keypads: Vec::new(), desyncs: Vec::new() };
+
+        for frame in 0..FRAMES{
+            let local_keys = if frame == press_frame { keys.to_vec() } else { vec![] };
+            machine.keypad = netplay.exchange_inputs(&local_keys).unwrap();
+            played.keypads.push(machine.keypad.clone());
+
+            timing::run_instructions(&mut machine, 10).unwrap();
+            machine.tick_timers();
+            if perturb_frame == Some(frame){
+                machine.variable_registers[5] ^= 1;
+            }
+
+            played.desyncs.extend(netplay.end_frame(&machine).unwrap());
+        }
+        (played, netplay)
+    }
+
+    /// Runs a host and a guest on 127.0.0.1 in two threads.
+    fn play_both(perturb_frame: Option<u32>) -> (Played, Played){
+        let session = Session {
+            rom_hash: machine::rom_hash(&ROM),
+            seed: 1234,
+            quirks: Quirks::default(),
+            speed: Speed::InstructionsPerFrame(10),
+            input_delay: INPUT_DELAY,
+        };
+        let listener = Netplay::listen("127.0.0.1", 0).unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(address.ip().is_loopback());
+
+        let host = thread::spawn(move || {
+            let netplay = Netplay::host(listener, &session).unwrap();
+            assert_eq!(netplay.player, Player::One);
+            // 0xC belongs to the other player
+            play(netplay, session, &[0x1, 0xC], 5, perturb_frame)
+        });
+        let guest = thread::spawn(move || {
+            let (netplay, joined) = Netplay::join(&address.to_string(), session.rom_hash).unwrap();
+            assert_eq!(joined, session);
+            assert_eq!(netplay.player, Player::Two);
+            play(netplay, joined, &[0xC], 10, None)
+        });
+
+        let (host, _host_connection) = host.join().unwrap();
+        let (guest, _guest_connection) = guest.join().unwrap();
+        (host, guest)
+    }
+
+    #[test]
+    fn inputs_take_effect_after_the_delay_on_both_sides(){
+        let (host, guest) = play_both(None);
+
+        assert_eq!(host.keypads, guest.keypads);
+        for (frame, keypad) in host.keypads.iter().enumerate(){
+            let expected: Vec<u8> = match frame as u32 {
+                frame if frame == 5 + INPUT_DELAY => vec![0x1],
+                frame if frame == 10 + INPUT_DELAY => vec![0xC],
+                _ => vec![],
+            };
+            assert_eq!(keypad, &expected, "frame {}", frame);
+        }
+
+        assert!(host.desyncs.is_empty());
+        assert!(guest.desyncs.is_empty());
+    }
+
+    #[test]
+    fn reports_a_desync_once(){
+        let (host, guest) = play_both(Some(20));
+
+        let desync = host.desyncs[0];
+        assert_eq!(host.desyncs.len(), 1);
+        assert_eq!(desync.frame, CHECKSUM_INTERVAL);
+        assert_ne!(desync.local, desync.remote);
+        assert_eq!(guest.desyncs, vec![Desync { frame: CHECKSUM_INTERVAL, local: desync.remote, remote: desync.local }]);
+    }
+
+    #[test]
+    fn rejects_uncapped_speed(){
+        let session = Session {
+            rom_hash: 0,
+            seed: 0,
+            quirks: Quirks::default(),
+            speed: Speed::Uncapped,
+            input_delay: 0,
+        };
+        let listener = Netplay::listen("127.0.0.1", 0).unwrap();
+        assert!(Netplay::host(listener, &session).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Colour{
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Colour{
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> Colour{
+        Colour { red, green, blue }
+    }
+
+    /// Parses `#RRGGBB`, with or without the `#`.
+    pub fn parse(text: &str) -> Result<Colour, String>{
+        let hex = text.trim().strip_prefix('#').unwrap_or(text.trim());
+        let value = Some(hex)
+            .filter(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("'{}' is not a colour, expected #RRGGBB.", text.trim()))?;
+
+        Ok(Colour::rgb((value >> 16) as u8, (value >> 8) as u8, value as u8))
+    }
+
+    /// Mixes towards `other` by `amount`, 0 giving this colour and 1 giving `other`.
+    pub fn blend(&self, other: Colour, amount: f32) -> Colour{
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * amount.clamp(0.0, 1.0)).round() as u8;
+
+        Colour::rgb(mix(self.red, other.red), mix(self.green, other.green), mix(self.blue, other.blue))
+    }
+
+    pub fn to_hex(&self) -> String{
+        format!("#{:02X}{:02X}{:02X}", self.red, self.green, self.blue)
+    }
+}
+
+/// Colours indexed by which bitplanes a pixel is set in: background, plane 1,
+/// plane 2 and both. Plain CHIP-8 only has plane 1, so only the first two
+/// colours show; XO-CHIP uses all four.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette{
+    pub name: String,
+    pub colours: [Colour; 4],
+}
+
+/// Built-in themes as (name, background, plane 1, plane 2, both planes).
+const THEMES: [(&str, [Colour; 4]); 4] = [
+    ("classic", [
+        Colour::rgb(0x00, 0x00, 0x00), Colour::rgb(0x00, 0xFF, 0x00),
+        Colour::rgb(0x00, 0x80, 0x00), Colour::rgb(0xAA, 0xFF, 0xAA),
+    ]),
+    ("amber", [
+        Colour::rgb(0x1A, 0x10, 0x00), Colour::rgb(0xFF, 0xB0, 0x00),
+        Colour::rgb(0x8C, 0x5A, 0x00), Colour::rgb(0xFF, 0xE0, 0x90),
+    ]),
+    ("lcd", [
+        Colour::rgb(0x9B, 0xBC, 0x0F), Colour::rgb(0x0F, 0x38, 0x0F),
+        Colour::rgb(0x8B, 0xAC, 0x0F), Colour::rgb(0x30, 0x62, 0x30),
+    ]),
+    // Octo's default colours
+    ("octo", [
+        Colour::rgb(0x99, 0x66, 0x00), Colour::rgb(0xFF, 0xCC, 0x00),
+        Colour::rgb(0xFF, 0x66, 0x00), Colour::rgb(0x66, 0x22, 0x00),
+    ]),
+];
+
+impl Palette{
+    pub fn theme_names() -> impl Iterator<Item = &'static str>{
+        THEMES.iter().map(|(name, _)| *name)
+    }
+
+    pub fn theme(name: &str) -> Option<Palette>{
+        THEMES.iter()
+            .find(|(theme, _)| theme.eq_ignore_ascii_case(name.trim()))
+            .map(|(theme, colours)| Palette { name: theme.to_string(), colours: *colours })
+    }
+
+    /// The built-in theme after this one, wrapping around. Custom palettes go
+    /// to the first theme.
+    pub fn next_theme(&self) -> Palette{
+        let position = THEMES.iter().position(|(theme, _)| *theme == self.name);
+        let (name, colours) = THEMES[position.map(|i| (i + 1) % THEMES.len()).unwrap_or(0)];
+
+        Palette { name: name.to_string(), colours }
+    }
+
+    /// Either a theme name, or two to four `#RRGGBB` colours separated by
+    /// commas: background, foreground, then the XO-CHIP plane 2 and overlap
+    /// colours. Missing plane colours default to the foreground.
+    pub fn parse(spec: &str) -> Result<Palette, String>{
+        if let Some(palette) = Palette::theme(spec){
+            return Ok(palette);
+        }
+
+        let colours = spec.split(',')
+            .map(Colour::parse)
+            .collect::<Result<Vec<Colour>, String>>()?;
+        if colours.len() < 2 || colours.len() > 4{
+            let themes: Vec<&str> = Palette::theme_names().collect();
+            return Err(format!("Palette '{}' should be one of {} or 2 to 4 colours.", spec, themes.join(", ")));
+        }
+
+        let foreground = colours[1];
+        Ok(Palette {
+            name: "custom".to_string(),
+            colours: [
+                colours[0],
+                foreground,
+                colours.get(2).copied().unwrap_or(foreground),
+                colours.get(3).copied().unwrap_or(foreground),
+            ],
+        })
+    }
+
+    pub fn background(&self) -> Colour{
+        self.colours[0]
+    }
+
+    pub fn foreground(&self) -> Colour{
+        self.colours[1]
+    }
+
+    /// Per-ROM palettes live next to the ROM, e.g. `roms/Pong.palette`,
+    /// holding anything `parse` accepts.
+    pub fn path_for_rom(rom_path: &Path) -> PathBuf{
+        rom_path.with_extension("palette")
+    }
+
+    pub fn load(path: &Path) -> Result<Palette, String>{
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read palette {}: {}", path.display(), e))?;
+
+        Palette::parse(text.trim())
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+impl Default for Palette{
+    fn default() -> Self{
+        Palette::theme("classic").unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parses_colours_with_or_without_a_hash(){
+        assert_eq!(Colour::parse("#FFB000"), Ok(Colour::rgb(0xFF, 0xB0, 0x00)));
+        assert_eq!(Colour::parse(" 0f380f "), Ok(Colour::rgb(0x0F, 0x38, 0x0F)));
+        assert_eq!(Colour::rgb(0x0F, 0x38, 0x0F).to_hex(), "#0F380F");
+    }
+
+    #[test]
+    fn rejects_anything_but_six_hex_digits(){
+        for text in ["+FFFFF", "#+FFFFF", "-00001", "##FFFFFF", "#FFF", "#FFFFFFF", "#GGGGGG", "", "#"]{
+            assert!(Colour::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parses_themes_by_name(){
+        let palette = Palette::parse(" Amber ").unwrap();
+        assert_eq!(palette.name, "amber");
+        assert_eq!(palette.foreground(), Colour::rgb(0xFF, 0xB0, 0x00));
+        assert_eq!(Palette::theme("octo").unwrap().background(), Colour::rgb(0x99, 0x66, 0x00));
+        assert_eq!(Palette::theme("sepia"), None);
+    }
+
+    #[test]
+    fn plane_colours_default_to_the_foreground(){
+        let palette = Palette::parse("#000000,#FFFFFF").unwrap();
+        assert_eq!(palette.name, "custom");
+        assert_eq!(palette.colours, [Colour::rgb(0, 0, 0), Colour::rgb(255, 255, 255), Colour::rgb(255, 255, 255), Colour::rgb(255, 255, 255)]);
+
+        let palette = Palette::parse("#000000, #FFFFFF, #FF0000, #00FF00").unwrap();
+        assert_eq!(palette.colours[2], Colour::rgb(255, 0, 0));
+        assert_eq!(palette.colours[3], Colour::rgb(0, 255, 0));
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_colours(){
+        assert!(Palette::parse("#000000").unwrap_err().contains("2 to 4 colours"));
+        assert!(Palette::parse("#000000,#111111,#222222,#333333,#444444").is_err());
+        assert!(Palette::parse("#000000,+FFFFF").is_err());
+    }
+
+    #[test]
+    fn next_theme_cycles_through_the_themes(){
+        let names: Vec<&str> = Palette::theme_names().collect();
+        let mut palette = Palette::default();
+
+        for i in 1..=names.len(){
+            palette = palette.next_theme();
+            assert_eq!(palette.name, names[i % names.len()]);
+        }
+        assert_eq!(Palette::parse("#000000,#FFFFFF").unwrap().next_theme().name, names[0]);
+    }
+}
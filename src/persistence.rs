@@ -0,0 +1,189 @@
+use std::str::FromStr;
+
+use crate::framebuffer::Framebuffer;
+
+/// How a pixel fades once it is switched off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecayCurve{
+    /// Loses the same amount of brightness every frame.
+    Linear,
+    /// Loses the same fraction of its brightness every frame, fading fast and then lingering.
+    Exponential,
+}
+
+impl FromStr for DecayCurve{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s.to_ascii_lowercase().as_str() {
+            "linear" => Ok(DecayCurve::Linear),
+            "exponential" => Ok(DecayCurve::Exponential),
+            _ => Err(format!("Unknown decay curve '{}', expected linear or exponential.", s)),
+        }
+    }
+}
+
+/// Below this a pixel counts as fully dark.
+const DARK: f32 = 1.0 / 256.0;
+
+/// Imitates the afterglow of a CRT phosphor so sprites that are erased and
+/// redrawn every frame stop flickering.
+///
+/// Every pixel has a brightness between 0 and 1. Lit pixels are at full
+/// brightness, and pixels that go dark take `decay_frames` frames to fade out.
+pub struct PhosphorFilter{
+    decay_frames: u32,
+    curve: DecayCurve,
+    width: u32,
+    height: u32,
+    intensities: Vec<f32>,
+}
+
+impl PhosphorFilter{
+    pub fn new(decay_frames: u32, curve: DecayCurve) -> PhosphorFilter{
+        PhosphorFilter {
+            decay_frames: decay_frames.max(1),
+            curve,
+            width: 0,
+            height: 0,
+            intensities: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32{
+        self.width
+    }
+
+    pub fn height(&self) -> u32{
+        self.height
+    }
+
+    /// Brightness of every pixel, row by row.
+    pub fn intensities(&self) -> &[f32]{
+        &self.intensities
+    }
+
+    pub fn intensity_at(&self, x: u32, y: u32) -> f32{
+        self.intensities[(y * self.width + x) as usize]
+    }
+
+    /// Whether any pixel is still fading out, i.e. the screen needs redrawing
+    /// even though the framebuffer did not change.
+    pub fn is_fading(&self) -> bool{
+        self.intensities.iter().any(|intensity| *intensity > DARK && *intensity < 1.0)
+    }
+
+    /// Advances the afterglow by one frame, called once per 60 Hz frame.
+    pub fn update(&mut self, framebuffer: &Framebuffer){
+        if framebuffer.width != self.width || framebuffer.height != self.height{
+            // a resolution change starts from a clean screen
+            self.width = framebuffer.width;
+            self.height = framebuffer.height;
+            self.intensities = vec![0.0; framebuffer.pixels.len()];
+        }
+
+        let linear_step = 1.0 / self.decay_frames as f32;
+        // reaches DARK after decay_frames frames
+        let exponential_factor = DARK.powf(1.0 / self.decay_frames as f32);
+
+        for (intensity, lit) in self.intensities.iter_mut().zip(&framebuffer.pixels){
+            *intensity = if *lit{
+                1.0
+            } else{
+                let faded = match self.curve {
+                    DecayCurve::Linear => *intensity - linear_step,
+                    DecayCurve::Exponential => *intensity * exponential_factor,
+                };
+                // rounding can leave the last frame of the fade a hair above DARK
+                if faded <= DARK + f32::EPSILON { 0.0 } else { faded }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Brightness of the top left pixel over the frames after it is switched off.
+    fn fade(curve: DecayCurve, decay_frames: u32) -> Vec<f32>{
+        let mut filter = PhosphorFilter::new(decay_frames, curve);
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.pixels[0] = true;
+        filter.update(&framebuffer);
+        assert_eq!(filter.intensity_at(0, 0), 1.0);
+
+        framebuffer.pixels[0] = false;
+        (0..decay_frames)
+            .map(|_| {
+                filter.update(&framebuffer);
+                assert_eq!(filter.is_fading(), filter.intensity_at(0, 0) > 0.0);
+                filter.intensity_at(0, 0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn linear_fades_by_the_same_amount_every_frame(){
+        assert_eq!(fade(DecayCurve::Linear, 4), vec![0.75, 0.5, 0.25, 0.0]);
+
+        for decay_frames in 1..=120{
+            let intensities = fade(DecayCurve::Linear, decay_frames);
+            assert_eq!(intensities.last(), Some(&0.0), "{} frames", decay_frames);
+            assert!(intensities[..intensities.len() - 1].iter().all(|intensity| *intensity > 0.0));
+        }
+    }
+
+    #[test]
+    fn exponential_fades_by_the_same_fraction_every_frame(){
+        let intensities = fade(DecayCurve::Exponential, 8);
+
+        for decay_frames in 1..=120{
+            let intensities = fade(DecayCurve::Exponential, decay_frames);
+            assert_eq!(intensities.last(), Some(&0.0), "{} frames", decay_frames);
+            assert!(intensities[..intensities.len() - 1].iter().all(|intensity| *intensity > 0.0));
+        }
+        let ratio = intensities[1] / intensities[0];
+        assert!((intensities[0] - ratio).abs() < 1e-5);
+        for pair in intensities[..intensities.len() - 1].windows(2){
+            assert!((pair[1] / pair[0] - ratio).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn lit_pixels_stay_at_full_brightness(){
+        let mut filter = PhosphorFilter::new(4, DecayCurve::Exponential);
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.pixels[5] = true;
+
+        for _ in 0..10{
+            filter.update(&framebuffer);
+            assert_eq!(filter.intensities()[5], 1.0);
+            assert!(!filter.is_fading());
+        }
+    }
+
+    #[test]
+    fn a_resolution_change_starts_dark(){
+        let mut filter = PhosphorFilter::new(4, DecayCurve::Linear);
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.pixels.fill(true);
+        filter.update(&framebuffer);
+
+        let mut hires = Framebuffer::new();
+        hires.width *= 2;
+        hires.height *= 2;
+        hires.pixels = vec![false; (hires.width * hires.height) as usize];
+        filter.update(&hires);
+
+        assert_eq!((filter.width(), filter.height()), (128, 64));
+        assert!(filter.intensities().iter().all(|intensity| *intensity == 0.0));
+    }
+
+    #[test]
+    fn parses_decay_curves(){
+        assert_eq!("Linear".parse(), Ok(DecayCurve::Linear));
+        assert_eq!("exponential".parse(), Ok(DecayCurve::Exponential));
+        assert!("cubic".parse::<DecayCurve>().is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// the wrappers pyo3 generates convert PyErr into itself
+#![allow(clippy::useless_conversion)]
+
+use std::fs;
+use std::path::PathBuf;
+
+use pyo3::exceptions::{PyIndexError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::machine::{self, Machine, Quirks, RAM_SIZE};
+use crate::scheduler::Speed;
+use crate::timing::{self, VipTiming};
+
+/*
+ * Python bindings, for driving games from scripts and notebooks. Build with
+ * `cargo build --release --features python` and copy the library to
+ * chip8_emulator.so (chip8_emulator.pyd on Windows) somewhere on the path:
+ *
+ *     import chip8_emulator
+ *     machine = chip8_emulator.Machine.from_file("roms/Pong (alt).ch8")
+ *     machine.keys = [0x1]
+ *     machine.run_frames(60)
+ *     screen = machine.framebuffer_array()
+ */
+
+#[pymodule]
+fn chip8_emulator(module: &Bound<'_, PyModule>) -> PyResult<()>{
+    module.add_class::<PyMachine>()?;
+    Ok(())
+}
+
+fn value_error(error: String) -> PyErr{
+    PyValueError::new_err(error)
+}
+
+/// The program did something the machine cannot, like returning with an empty stack.
+fn runtime_error(error: String) -> PyErr{
+    PyRuntimeError::new_err(error)
+}
+
+/// A CHIP-8 machine running a ROM. Frames are run on demand, as fast as the
+/// caller asks for them.
+#[pyclass(name = "Machine", module = "chip8_emulator")]
+pub struct PyMachine{
+    machine: Machine,
+    rom: Vec<u8>,
+    seed: u32,
+    speed: Speed,
+    vip_timing: VipTiming,
+}
+
+impl PyMachine{
+    fn power_on(rom: &[u8], seed: u32, quirks: Quirks) -> PyResult<Machine>{
+        let mut machine = Machine::new(quirks, seed);
+        machine.load_rom(rom).map_err(value_error)?;
+        machine.trace = false;
+        Ok(machine)
+    }
+
+    fn memory_range(address: usize, length: usize) -> PyResult<std::ops::Range<usize>>{
+        match address.checked_add(length) {
+            Some(end) if end <= RAM_SIZE => Ok(address..end),
+            _ => Err(PyIndexError::new_err(format!("{} bytes at {:#05x} run past the end of memory.", length, address))),
+        }
+    }
+}
+
+#[pymethods]
+impl PyMachine{
+    /// `speed` is "vip", "schip", "vip-cycles" or a number of instructions
+    /// per frame. The same seed and inputs always give the same run.
+    #[new]
+    #[pyo3(signature = (rom, seed=0, speed="vip", shift_quirk=true))]
+    fn new(rom: &[u8], seed: u32, speed: &str, shift_quirk: bool) -> PyResult<PyMachine>{
+        let quirks = Quirks { ignore_y_in_8xy_shift_instruction: shift_quirk };
+
+        let mut machine = PyMachine {
+            machine: PyMachine::power_on(rom, seed, quirks)?,
+            rom: rom.to_vec(),
+            seed,
+            speed: Speed::VIP,
+            vip_timing: VipTiming::new(),
+        };
+        machine.set_speed(speed)?;
+
+        Ok(machine)
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (path, seed=0, speed="vip", shift_quirk=true))]
+    fn from_file(path: PathBuf, seed: u32, speed: &str, shift_quirk: bool) -> PyResult<PyMachine>{
+        let rom = fs::read(&path)
+            .map_err(|e| value_error(format!("Could not read ROM {}: {}", path.display(), e)))?;
+        PyMachine::new(&rom, seed, speed, shift_quirk)
+    }
+
+    /// Powers the machine back on with the same ROM, seed and settings.
+    fn reset(&mut self) -> PyResult<()>{
+        self.machine = PyMachine::power_on(&self.rom, self.seed, self.machine.quirks)?;
+        self.vip_timing = VipTiming::new();
+        Ok(())
+    }
+
+    /// Runs a single instruction without touching the timers. Raises
+    /// RuntimeError if the program faults, leaving the machine at that
+    /// instruction.
+    fn step(&mut self) -> PyResult<()>{
+        self.machine.step().map_err(runtime_error)
+    }
+
+    /// Runs whole 60 Hz frames at the set speed and returns the number of
+    /// instructions run. Raises RuntimeError as step does.
+    #[pyo3(signature = (frames=1))]
+    fn run_frames(&mut self, frames: u32) -> PyResult<u32>{
+        let mut instructions = 0;
+        for _ in 0..frames{
+            instructions += timing::run_paced_frame(&mut self.machine, self.speed, &mut self.vip_timing)
+                .map_err(runtime_error)?;
+        }
+        Ok(instructions)
+    }
+
+    #[getter]
+    fn speed(&self) -> String{
+        self.speed.to_string()
+    }
+
+    #[setter]
+    fn set_speed(&mut self, speed: &str) -> PyResult<()>{
+        match speed.parse().and_then(timing::paced_speed) {
+            Ok(parsed) =>{
+                self.speed = parsed;
+                Ok(())
+            },
+            Err(e) => Err(value_error(e)),
+        }
+    }
+
+    /// Keypad keys held down, 0x0 to 0xF.
+    #[getter]
+    fn keys(&self) -> Vec<u8>{
+        self.machine.keypad.clone()
+    }
+
+    #[setter]
+    fn set_keys(&mut self, keys: Vec<u8>) -> PyResult<()>{
+        if let Some(key) = keys.iter().find(|key| **key > 0xF){
+            return Err(value_error(format!("There is no key {:#x} on the keypad.", key)));
+        }
+
+        let mut keys = keys;
+        keys.sort();
+        keys.dedup();
+        self.machine.keypad = keys;
+        Ok(())
+    }
+
+    #[getter]
+    fn width(&self) -> u32{
+        self.machine.framebuffer.width
+    }
+
+    #[getter]
+    fn height(&self) -> u32{
+        self.machine.framebuffer.height
+    }
+
+    /// The screen row by row, one byte per pixel: 1 lit, 0 dark.
+    fn framebuffer<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes>{
+        let pixels: Vec<u8> = self.machine.framebuffer.pixels.iter()
+            .map(|pixel| *pixel as u8)
+            .collect();
+        PyBytes::new_bound(py, &pixels)
+    }
+
+    /// The screen as a (height, width) numpy array of uint8. Needs numpy.
+    fn framebuffer_array<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>>{
+        let numpy = py.import_bound("numpy")?;
+        numpy.call_method1("frombuffer", (self.framebuffer(py), numpy.getattr("uint8")?))?
+            .call_method1("reshape", (self.machine.framebuffer.height, self.machine.framebuffer.width))
+    }
+
+    /// Whether the frame changed the screen since the last call.
+    fn take_screen_changed(&mut self) -> bool{
+        std::mem::take(&mut self.machine.screen_changed)
+    }
+
+    #[pyo3(signature = (address, length=1))]
+    fn read_memory<'py>(&self, py: Python<'py>, address: usize, length: usize) -> PyResult<Bound<'py, PyBytes>>{
+        let range = PyMachine::memory_range(address, length)?;
+        Ok(PyBytes::new_bound(py, &self.machine.ram[range]))
+    }
+
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> PyResult<()>{
+        let range = PyMachine::memory_range(address, data.len())?;
+        self.machine.ram[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// V0 to VF.
+    #[getter]
+    fn registers(&self) -> Vec<u8>{
+        self.machine.variable_registers.to_vec()
+    }
+
+    #[getter]
+    fn index_register(&self) -> u16{
+        machine::get_index_register(&self.machine.ram)
+    }
+
+    #[getter]
+    fn program_counter(&self) -> u16{
+        self.machine.program_counter
+    }
+
+    #[getter]
+    fn delay_timer(&self) -> u8{
+        self.machine.delay_timer
+    }
+
+    #[getter]
+    fn sound_timer(&self) -> u8{
+        self.machine.sound_timer
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes>{
+        PyBytes::new_bound(py, &self.machine.save_state())
+    }
+
+    /// Restores a state from save_state. Nothing changes if the state is
+    /// malformed or belongs to another ROM.
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()>{
+        self.machine.load_state(state).map_err(value_error)
+    }
+
+    fn __repr__(&self) -> String{
+        format!("Machine(rom_hash={:016x}, speed='{}', pc={:#05x})", self.machine.rom_hash, self.speed, self.machine.program_counter)
+    }
+}
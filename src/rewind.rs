@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+
+use crate::machine::Machine;
+
+/// Ring buffer of machine snapshots for rewinding gameplay.
+///
+/// Only the newest snapshot is kept whole. Every older one is stored as the
+/// XOR against its successor, run-length encoded. Consecutive frames differ in
+/// a handful of bytes, so a snapshot usually costs tens of bytes. Since each
+/// delta only depends on newer states, the oldest can be dropped at any time.
+pub struct RewindBuffer{
+    capacity: usize,
+    interval: u32,
+    frames_until_capture: u32,
+    // frames of rewinding left before stepping back another snapshot
+    frames_until_rewind: u32,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer{
+    /// Keeps up to `capacity` snapshots, one taken every `interval` frames.
+    pub fn new(capacity: usize, interval: u32) -> RewindBuffer{
+        RewindBuffer {
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+            frames_until_capture: 0,
+            frames_until_rewind: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize{
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.newest.is_none()
+    }
+
+    pub fn clear(&mut self){
+        self.newest = None;
+        self.deltas.clear();
+        self.frames_until_capture = 0;
+        self.frames_until_rewind = 0;
+    }
+
+    /// Called once per frame; snapshots the machine every `interval` frames.
+    pub fn record_frame(&mut self, machine: &Machine){
+        // rewinding again steps back straight away
+        self.frames_until_rewind = 0;
+
+        if self.frames_until_capture > 0{
+            self.frames_until_capture -= 1;
+            return;
+        }
+        self.frames_until_capture = self.interval - 1;
+
+        self.push(machine.save_state());
+    }
+
+    pub fn push(&mut self, state: Vec<u8>){
+        if let Some(newest) = self.newest.take(){
+            self.deltas.push_back(compress(&xor(&newest, &state), newest.len()));
+        }
+        self.newest = Some(state);
+
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Called once per frame while rewinding. Every `interval` frames, starting
+    /// with the first, steps one snapshot back and returns it, so rewinding
+    /// plays at the speed the game ran. The oldest snapshot is never removed,
+    /// so holding rewind past the start stays on it.
+    pub fn rewind(&mut self) -> Option<Vec<u8>>{
+        if self.frames_until_rewind > 0{
+            self.frames_until_rewind -= 1;
+            return None;
+        }
+
+        let current = self.newest.take()?;
+        self.frames_until_rewind = self.interval - 1;
+
+        let previous = match self.deltas.pop_back() {
+            Some(delta) => decompress(&delta, &current),
+            None => current,
+        };
+        self.newest = Some(previous.clone());
+
+        // Resuming continues from the restored snapshot, which is already stored.
+        self.frames_until_capture = self.interval;
+
+        Some(previous)
+    }
+}
+
+/// XOR of two states, padded with zeroes since the stack makes their lengths vary.
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8>{
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0) ^ b.get(i).unwrap_or(&0))
+        .collect()
+}
+
+/*
+ * A delta is the length of the older state (varint) followed by the XOR of
+ * both states, which is mostly zeroes, encoded as repeated
+ *   zero run length (varint), literal length (varint), literal bytes
+ */
+fn compress(delta: &[u8], previous_length: usize) -> Vec<u8>{
+    let mut output = Vec::new();
+    let mut position = 0;
+
+    write_varint(&mut output, previous_length);
+
+    while position < delta.len() {
+        let zeroes = delta[position..].iter().take_while(|b| **b == 0).count();
+        position += zeroes;
+
+        let literal = delta[position..].iter().take_while(|b| **b != 0).count();
+        write_varint(&mut output, zeroes);
+        write_varint(&mut output, literal);
+        output.extend_from_slice(&delta[position..position + literal]);
+        position += literal;
+    }
+
+    output
+}
+
+/// Reconstructs the older state from a delta and the state that followed it.
+fn decompress(compressed: &[u8], current: &[u8]) -> Vec<u8>{
+    let mut position = 0;
+    let previous_length = read_varint(compressed, &mut position);
+
+    let mut delta = Vec::with_capacity(previous_length.max(current.len()));
+    while position < compressed.len() {
+        let zeroes = read_varint(compressed, &mut position);
+        let literal = read_varint(compressed, &mut position);
+
+        delta.resize(delta.len() + zeroes, 0);
+        delta.extend_from_slice(&compressed[position..position + literal]);
+        position += literal;
+    }
+
+    let mut previous = xor(current, &delta);
+    previous.truncate(previous_length);
+    previous
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize){
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> usize{
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = input[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0{
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::machine::Quirks;
+
+    // V0 += 1, jump 0x200
+    fn counting_machine() -> Machine{
+        let mut machine = Machine::new(Quirks::default(), 1);
+        machine.trace = false;
+        machine.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        machine
+    }
+
+    fn run_frame(machine: &mut Machine){
+        machine.step().unwrap();
+        machine.step().unwrap();
+    }
+
+    /// Runs `frames` frames, recording each, then rewinds for `rewind_frames`,
+    /// returning V0 after every frame of rewinding.
+    fn counts_while_rewinding(interval: u32, frames: u32, rewind_frames: u32) -> Vec<u8>{
+        let mut machine = counting_machine();
+        let mut buffer = RewindBuffer::new(100, interval);
+        for _ in 0..frames{
+            run_frame(&mut machine);
+            buffer.record_frame(&machine);
+        }
+
+        (0..rewind_frames)
+            .map(|_| {
+                if let Some(state) = buffer.rewind(){
+                    machine.load_state(&state).unwrap();
+                }
+                machine.variable_registers[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rewinds_a_frame_at_a_time(){
+        assert_eq!(counts_while_rewinding(1, 5, 6), vec![4, 3, 2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn rewinds_at_the_speed_the_game_ran(){
+        // snapshots after frames 1, 4 and 7
+        assert_eq!(counts_while_rewinding(3, 8, 7), vec![4, 4, 4, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn rewinding_again_steps_back_straight_away(){
+        let mut machine = counting_machine();
+        let mut buffer = RewindBuffer::new(100, 4);
+        for _ in 0..9{
+            run_frame(&mut machine);
+            buffer.record_frame(&machine);
+        }
+
+        assert!(buffer.rewind().is_some());
+        assert!(buffer.rewind().is_none());
+        buffer.record_frame(&machine);
+        assert!(buffer.rewind().is_some());
+    }
+
+    #[test]
+    fn keeps_only_the_newest_snapshots(){
+        let mut machine = counting_machine();
+        let mut buffer = RewindBuffer::new(3, 1);
+        for _ in 0..10{
+            run_frame(&mut machine);
+            buffer.record_frame(&machine);
+        }
+        assert_eq!(buffer.len(), 3);
+
+        let mut oldest = Vec::new();
+        for _ in 0..5{
+            oldest = buffer.rewind().unwrap();
+        }
+        machine.load_state(&oldest).unwrap();
+        assert_eq!(machine.variable_registers[0], 8);
+    }
+
+    #[test]
+    fn restores_states_of_different_lengths(){
+        let mut buffer = RewindBuffer::new(10, 1);
+        let states = [vec![1, 0, 0, 7], vec![1, 2], vec![0, 0, 0, 0, 0, 9], vec![]];
+        for state in &states{
+            buffer.push(state.clone());
+        }
+
+        for state in states.iter().rev().skip(1){
+            assert_eq!(&buffer.rewind().unwrap(), state);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+use crate::machine::{self, Machine};
+use crate::net;
+
+/*
+ * Remote control for a running emulator, for test automation and external
+ * tools. JSON-RPC 2.0 over TCP on localhost, one request or response per line:
+ *
+ *     {"jsonrpc": "2.0", "id": 1, "method": "press_key", "params": {"key": 5}}
+ *     {"jsonrpc": "2.0", "id": 1, "result": null}
+ *
+ * Methods:
+ *   load_rom {path}            power on with another ROM
+ *   pause, resume
+ *   step {frames = 1}          run frames while paused, answered once they have run
+ *   press_key {key}, release_key {key}   held alongside the keyboard
+ *   read_registers             {v, i, pc, stack, delay_timer, sound_timer}
+ *   read_memory {address, length = 1}   {data} as hex
+ *   read_framebuffer           {width, height, rows} with a '0' or '1' per pixel
+ *   save_state                 {state} as hex
+ *   load_state {state}
+ *
+ * Connections are served on a tokio runtime in a thread of their own; calls
+ * are handed to the emulator loop, which answers them between frames.
+ */
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// the call was understood but the emulator could not carry it out
+const CALL_FAILED: i64 = -32000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcCall{
+    LoadRom{ path: String },
+    Pause,
+    Resume,
+    Step{ frames: u32 },
+    PressKey{ key: u8 },
+    ReleaseKey{ key: u8 },
+    ReadRegisters,
+    ReadMemory{ address: usize, length: usize },
+    ReadFramebuffer,
+    SaveState,
+    LoadState{ state: Vec<u8> },
+}
+
+#[derive(Deserialize)]
+struct PathParams{
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct StepParams{
+    #[serde(default = "default_frames")]
+    frames: u32,
+}
+
+#[derive(Deserialize)]
+struct KeyParams{
+    key: u8,
+}
+
+#[derive(Deserialize)]
+struct MemoryParams{
+    address: usize,
+    #[serde(default = "default_length")]
+    length: usize,
+}
+
+#[derive(Deserialize)]
+struct StateParams{
+    state: String,
+}
+
+fn default_frames() -> u32{
+    1
+}
+
+fn default_length() -> usize{
+    1
+}
+
+impl RpcCall{
+    fn parse(method: &str, params: Value) -> Result<RpcCall, (i64, String)>{
+        let call = match method {
+            "load_rom" =>{
+                let PathParams { path } = parse_params(params)?;
+                RpcCall::LoadRom { path }
+            },
+            "pause" => RpcCall::Pause,
+            "resume" => RpcCall::Resume,
+            "step" =>{
+                let StepParams { frames } = parse_params(params)?;
+                RpcCall::Step { frames }
+            },
+            "press_key" | "release_key" =>{
+                let KeyParams { key } = parse_params(params)?;
+                if key > 0xF{
+                    return Err((INVALID_PARAMS, format!("There is no key {:#x} on the keypad.", key)));
+                }
+                if method == "press_key" { RpcCall::PressKey { key } } else { RpcCall::ReleaseKey { key } }
+            },
+            "read_registers" => RpcCall::ReadRegisters,
+            "read_memory" =>{
+                let MemoryParams { address, length } = parse_params(params)?;
+                RpcCall::ReadMemory { address, length }
+            },
+            "read_framebuffer" => RpcCall::ReadFramebuffer,
+            "save_state" => RpcCall::SaveState,
+            "load_state" =>{
+                let StateParams { state } = parse_params(params)?;
+                RpcCall::LoadState { state: from_hex(&state).map_err(|e| (INVALID_PARAMS, e))? }
+            },
+            _ => return Err((METHOD_NOT_FOUND, format!("Unknown method '{}'.", method))),
+        };
+
+        Ok(call)
+    }
+}
+
+/// Missing params count as an empty object, so calls whose params all have
+/// defaults can leave them out.
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, (i64, String)>{
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, format!("Invalid params: {}", e)))
+}
+
+/// A call waiting for the emulator loop to answer it.
+pub struct RpcRequest{
+    pub call: RpcCall,
+    reply: oneshot::Sender<Result<Value, String>>,
+}
+
+impl RpcRequest{
+    pub fn reply(self, result: Result<Value, String>){
+        // the client may have disconnected in the meantime
+        let _ = self.reply.send(result);
+    }
+}
+
+pub struct RpcServer{
+    requests: Receiver<RpcRequest>,
+    port: u16,
+}
+
+impl RpcServer{
+    /// Listens on 127.0.0.1; port 0 picks a free one.
+    pub fn start(port: u16) -> Result<RpcServer, String>{
+        let (sender, requests) = mpsc::channel();
+        let port = net::serve_locally(port, "JSON-RPC server", move |stream| serve_connection(stream, sender.clone()))?;
+
+        Ok(RpcServer { requests, port })
+    }
+
+    pub fn port(&self) -> u16{
+        self.port
+    }
+
+    /// Calls that arrived since the last frame.
+    pub fn pending(&self) -> Vec<RpcRequest>{
+        self.requests.try_iter().collect()
+    }
+}
+
+async fn serve_connection(stream: TcpStream, sender: Sender<RpcRequest>){
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty(){
+            continue;
+        }
+
+        if let Some(response) = handle_line(&line, &sender).await{
+            let mut text = response.to_string();
+            text.push('\n');
+            if writer.write_all(text.as_bytes()).await.is_err(){
+                break;
+            }
+        }
+    }
+}
+
+/// Answers a single request line. Notifications, requests without an id,
+/// get no response.
+async fn handle_line(line: &str, sender: &Sender<RpcRequest>) -> Option<Value>{
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, &format!("Parse error: {}", e))),
+    };
+
+    let id = request.get("id").cloned();
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return Some(error_response(id.unwrap_or(Value::Null), INVALID_REQUEST, "The request has no method.")),
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let result = match RpcCall::parse(method, params) {
+        Ok(call) =>{
+            let (reply, answer) = oneshot::channel();
+            if sender.send(RpcRequest { call, reply }).is_err(){
+                return Some(error_response(id.unwrap_or(Value::Null), CALL_FAILED, "The emulator has stopped."));
+            }
+            answer.await
+                .unwrap_or_else(|_| Err("The emulator has stopped.".to_string()))
+                .map_err(|e| (CALL_FAILED, e))
+        },
+        Err(error) => Err(error),
+    };
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => error_response(id, code, &message),
+    })
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value{
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// The registers as read_registers returns them.
+pub fn registers(machine: &Machine) -> Value{
+    json!({
+        "v": machine.variable_registers,
+        "i": machine::get_index_register(&machine.ram),
+        "pc": machine.program_counter,
+        "stack": machine.stack,
+        "delay_timer": machine.delay_timer,
+        "sound_timer": machine.sound_timer,
+    })
+}
+
+pub fn to_hex(data: &[u8]) -> String{
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(text: &str) -> Result<Vec<u8>, String>{
+    if !text.len().is_multiple_of(2) || !text.is_ascii(){
+        return Err("Expected an even number of hex digits.".to_string());
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| format!("Invalid hex '{}'.", &text[i..i + 2])))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use std::thread;
+
+    use super::*;
+
+    fn parse(method: &str, params: Value) -> Result<RpcCall, i64>{
+        RpcCall::parse(method, params).map_err(|(code, _)| code)
+    }
+
+    /// Sends a line through handle_line, with a stand-in emulator that
+    /// answers every call with its Debug text.
+    fn respond(line: &str) -> Option<Value>{
+        let (sender, requests) = mpsc::channel::<RpcRequest>();
+        let emulator = thread::spawn(move || {
+            for request in requests{
+                let text = format!("{:?}", request.call);
+                request.reply(Ok(Value::String(text)));
+            }
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let response = runtime.block_on(handle_line(line, &sender));
+        drop(sender);
+        emulator.join().unwrap();
+        response
+    }
+
+    fn error_code(response: &Option<Value>) -> Option<i64>{
+        response.as_ref()?.get("error")?.get("code")?.as_i64()
+    }
+
+    #[test]
+    fn parses_calls(){
+        assert_eq!(parse("pause", Value::Null), Ok(RpcCall::Pause));
+        assert_eq!(parse("step", Value::Null), Ok(RpcCall::Step { frames: 1 }));
+        assert_eq!(parse("step", json!({ "frames": 30 })), Ok(RpcCall::Step { frames: 30 }));
+        assert_eq!(parse("press_key", json!({ "key": 15 })), Ok(RpcCall::PressKey { key: 15 }));
+        assert_eq!(parse("read_memory", json!({ "address": 512 })), Ok(RpcCall::ReadMemory { address: 512, length: 1 }));
+        assert_eq!(parse("load_state", json!({ "state": "00ff" })), Ok(RpcCall::LoadState { state: vec![0x00, 0xFF] }));
+    }
+
+    #[test]
+    fn rejects_bad_params(){
+        assert_eq!(parse("press_key", json!({ "key": 16 })), Err(INVALID_PARAMS));
+        assert_eq!(parse("press_key", Value::Null), Err(INVALID_PARAMS));
+        assert_eq!(parse("step", json!({ "frames": -1 })), Err(INVALID_PARAMS));
+        assert_eq!(parse("step", json!({ "frames": u64::from(u32::MAX) + 1 })), Err(INVALID_PARAMS));
+        assert_eq!(parse("load_rom", json!({ "file": "pong.ch8" })), Err(INVALID_PARAMS));
+        assert_eq!(parse("load_state", json!({ "state": "abc" })), Err(INVALID_PARAMS));
+        assert_eq!(parse("load_state", json!({ "state": "zz" })), Err(INVALID_PARAMS));
+        assert_eq!(parse("reboot", Value::Null), Err(METHOD_NOT_FOUND));
+    }
+
+    #[test]
+    fn answers_calls(){
+        let response = respond(r#"{"jsonrpc": "2.0", "id": 7, "method": "step", "params": {"frames": 2}}"#);
+        assert_eq!(response, Some(json!({ "jsonrpc": "2.0", "id": 7, "result": "Step { frames: 2 }" })));
+    }
+
+    #[test]
+    fn notifications_get_no_response(){
+        assert_eq!(respond(r#"{"jsonrpc": "2.0", "method": "pause"}"#), None);
+        assert_eq!(respond(r#"{"jsonrpc": "2.0", "method": "reboot"}"#), None);
+    }
+
+    #[test]
+    fn reports_errors_with_their_codes(){
+        let response = respond("{not json");
+        assert_eq!(error_code(&response), Some(PARSE_ERROR));
+        assert_eq!(response.unwrap()["id"], Value::Null);
+
+        let response = respond(r#"{"jsonrpc": "2.0", "id": "a"}"#);
+        assert_eq!(error_code(&response), Some(INVALID_REQUEST));
+        assert_eq!(response.unwrap()["id"], "a");
+
+        assert_eq!(error_code(&respond(r#"{"jsonrpc": "2.0", "id": 1, "method": 5}"#)), Some(INVALID_REQUEST));
+        assert_eq!(error_code(&respond(r#"{"jsonrpc": "2.0", "id": 1, "method": "reboot"}"#)), Some(METHOD_NOT_FOUND));
+        assert_eq!(error_code(&respond(r#"{"jsonrpc": "2.0", "id": 1, "method": "press_key", "params": {"key": 99}}"#)), Some(INVALID_PARAMS));
+    }
+
+    #[test]
+    fn reports_a_stopped_emulator(){
+        let (sender, requests) = mpsc::channel::<RpcRequest>();
+        drop(requests);
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let response = runtime.block_on(handle_line(r#"{"jsonrpc": "2.0", "id": 1, "method": "pause"}"#, &sender));
+        assert_eq!(error_code(&response), Some(CALL_FAILED));
+    }
+}
@@ -0,0 +1,36 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::machine::Machine;
+
+pub use chip8_core::savestate::{MAX_STATE_SIZE, STATE_MAGIC, STATE_VERSION};
+
+/// States are kept per ROM, e.g. `states/9f3c.../slot1.state`.
+pub fn slot_path(directory: &Path, rom_hash: u64, slot: u8) -> PathBuf{
+    directory
+        .join(format!("{:016x}", rom_hash))
+        .join(format!("slot{}.state", slot))
+}
+
+pub fn save_to_slot(machine: &Machine, directory: &Path, slot: u8) -> Result<PathBuf, String>{
+    let path = slot_path(directory, machine.rom_hash, slot);
+
+    if let Some(parent) = path.parent(){
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Could not create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&path, machine.save_state())
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+
+    Ok(path)
+}
+
+pub fn load_from_slot(machine: &mut Machine, directory: &Path, slot: u8) -> Result<PathBuf, String>{
+    let path = slot_path(directory, machine.rom_hash, slot);
+
+    let data = fs::read(&path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    machine.load_state(&data)?;
+
+    Ok(path)
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const FRAMES_PER_SECOND: u32 = 60;
+pub const FRAME_INTERVAL: Duration = Duration::from_micros(16667);
+
+/// Sleeping is only trusted to within this much; the rest is spun out.
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+/// Falling further behind than this, e.g. after a pause or a slow frame,
+/// restarts the schedule instead of running frames back to back to catch up.
+const MAX_LAG_FRAMES: u32 = 5;
+/// Speed multipliers are kept within this range so frame intervals stay sane.
+const MIN_SPEED_MULTIPLIER: f64 = 1.0 / 1024.0;
+const MAX_SPEED_MULTIPLIER: f64 = 1024.0;
+
+/// How many instructions run in each 60 Hz frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed{
+    /// A fixed number of instructions every frame.
+    InstructionsPerFrame(u32),
+    /// As many instructions as fit in the frame; only the timers keep pace.
+    Uncapped,
+    /// As many instructions as the COSMAC VIP would run, counting machine cycles.
+    VipCycles,
+}
+
+impl Speed{
+    /// Roughly what a COSMAC VIP manages, about 660 instructions a second.
+    pub const VIP: Speed = Speed::InstructionsPerFrame(11);
+    /// What SCHIP games on the HP 48 were written against.
+    pub const SUPER_CHIP: Speed = Speed::InstructionsPerFrame(30);
+}
+
+impl fmt::Display for Speed{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self {
+            Speed::InstructionsPerFrame(instructions) => write!(f, "{} instructions/frame", instructions),
+            Speed::Uncapped => write!(f, "uncapped"),
+            Speed::VipCycles => write!(f, "VIP cycle timing"),
+        }
+    }
+}
+
+/// Accepts the preset names `vip`, `schip`, `uncapped` and `vip-cycles`, or a
+/// number of instructions per frame.
+impl FromStr for Speed{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s.to_ascii_lowercase().as_str() {
+            "vip" | "chip8" | "chip-8" => Ok(Speed::VIP),
+            "schip" | "superchip" => Ok(Speed::SUPER_CHIP),
+            "uncapped" => Ok(Speed::Uncapped),
+            "vip-cycles" | "cycles" => Ok(Speed::VipCycles),
+            number => number.parse()
+                .map(Speed::InstructionsPerFrame)
+                .map_err(|_| format!("Unknown speed '{}', expected vip, schip, uncapped, vip-cycles or instructions per frame.", s)),
+        }
+    }
+}
+
+/// Measured rates over the last report period.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Report{
+    pub frames_per_second: f64,
+    pub instructions_per_second: f64,
+}
+
+/// Paces a 60 Hz frame loop and measures how fast it actually runs.
+pub struct FrameScheduler{
+    frame_interval: Duration,
+    next_frame: Instant,
+    report_start: Instant,
+    frames_since_report: u32,
+    instructions_since_report: u64,
+}
+
+impl FrameScheduler{
+    pub fn new() -> FrameScheduler{
+        FrameScheduler::new_at(Instant::now())
+    }
+
+    // the clock is passed in below so the schedule can be tested
+    fn new_at(now: Instant) -> FrameScheduler{
+        FrameScheduler {
+            frame_interval: FRAME_INTERVAL,
+            next_frame: now + FRAME_INTERVAL,
+            report_start: now,
+            frames_since_report: 0,
+            instructions_since_report: 0,
+        }
+    }
+
+    /// Runs frames `multiplier` times faster than 60 Hz, or slower below 1.
+    pub fn set_speed_multiplier(&mut self, multiplier: f64){
+        assert!(multiplier.is_finite() && multiplier > 0.0, "Invalid speed multiplier {}.", multiplier);
+        let multiplier = multiplier.clamp(MIN_SPEED_MULTIPLIER, MAX_SPEED_MULTIPLIER);
+        self.frame_interval = FRAME_INTERVAL.div_f64(multiplier);
+    }
+
+    /// Starts the schedule over from now, e.g. after being paused.
+    pub fn restart(&mut self){
+        self.restart_at(Instant::now());
+    }
+
+    fn restart_at(&mut self, now: Instant){
+        self.next_frame = now + self.frame_interval;
+    }
+
+    /// When the frame being run is due to end.
+    pub fn deadline(&self) -> Instant{
+        self.next_frame
+    }
+
+    pub fn is_frame_due(&self) -> bool{
+        Instant::now() >= self.next_frame
+    }
+
+    /// Sleeps until the current frame is over and schedules the next one.
+    pub fn wait_for_next_frame(&mut self){
+        loop {
+            let now = Instant::now();
+            if now >= self.next_frame{
+                break;
+            }
+
+            let remaining = self.next_frame - now;
+            if remaining > SPIN_THRESHOLD{
+                thread::sleep(remaining - SPIN_THRESHOLD);
+            } else{
+                thread::yield_now();
+            }
+        }
+
+        self.schedule_next_frame(Instant::now());
+    }
+
+    fn schedule_next_frame(&mut self, now: Instant){
+        self.next_frame += self.frame_interval;
+
+        if now > self.next_frame + self.frame_interval * MAX_LAG_FRAMES{
+            self.restart_at(now);
+        }
+    }
+
+    /// Counts a finished frame, returning the measured rates about once a second.
+    pub fn record_frame(&mut self, instructions: u32) -> Option<Report>{
+        self.record_frame_at(instructions, Instant::now())
+    }
+
+    fn record_frame_at(&mut self, instructions: u32, now: Instant) -> Option<Report>{
+        self.frames_since_report += 1;
+        self.instructions_since_report += instructions as u64;
+
+        let elapsed = now - self.report_start;
+        if elapsed < Duration::from_secs(1){
+            return None;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        let report = Report {
+            frames_per_second: self.frames_since_report as f64 / seconds,
+            instructions_per_second: self.instructions_since_report as f64 / seconds,
+        };
+
+        self.report_start = now;
+        self.frames_since_report = 0;
+        self.instructions_since_report = 0;
+
+        Some(report)
+    }
+}
+
+impl Default for FrameScheduler{
+    fn default() -> Self{
+        FrameScheduler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn parses_speeds(){
+        assert_eq!("VIP".parse(), Ok(Speed::VIP));
+        assert_eq!("schip".parse(), Ok(Speed::SUPER_CHIP));
+        assert_eq!("uncapped".parse(), Ok(Speed::Uncapped));
+        assert_eq!("vip-cycles".parse(), Ok(Speed::VipCycles));
+        assert_eq!("100".parse(), Ok(Speed::InstructionsPerFrame(100)));
+        assert!("fast".parse::<Speed>().is_err());
+        assert!("-1".parse::<Speed>().is_err());
+    }
+
+    #[test]
+    fn frames_follow_each_other_at_60_hz(){
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler::new_at(start);
+        assert_eq!(scheduler.deadline(), start + FRAME_INTERVAL);
+
+        scheduler.schedule_next_frame(start + FRAME_INTERVAL);
+        scheduler.schedule_next_frame(start + FRAME_INTERVAL * 2);
+
+        assert_eq!(scheduler.deadline(), start + FRAME_INTERVAL * 3);
+    }
+
+    #[test]
+    fn the_multiplier_shortens_or_stretches_frames(){
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler::new_at(start);
+
+        scheduler.set_speed_multiplier(4.0);
+        scheduler.restart_at(start);
+        assert_eq!(scheduler.deadline(), start + FRAME_INTERVAL / 4);
+
+        scheduler.set_speed_multiplier(0.5);
+        scheduler.schedule_next_frame(start);
+        assert_eq!(scheduler.deadline(), start + FRAME_INTERVAL / 4 + FRAME_INTERVAL * 2);
+
+        // absurd multipliers are held to a sane range
+        scheduler.set_speed_multiplier(1e12);
+        assert_eq!(scheduler.frame_interval, FRAME_INTERVAL.div_f64(MAX_SPEED_MULTIPLIER));
+        scheduler.set_speed_multiplier(1e-12);
+        assert_eq!(scheduler.frame_interval, FRAME_INTERVAL.div_f64(MIN_SPEED_MULTIPLIER));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid speed multiplier")]
+    fn a_multiplier_of_zero_is_a_bug(){
+        FrameScheduler::new().set_speed_multiplier(0.0);
+    }
+
+    #[test]
+    fn falling_far_behind_restarts_the_schedule(){
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler::new_at(start);
+
+        // a little behind is caught up by running frames back to back
+        let late = start + FRAME_INTERVAL * 4;
+        scheduler.schedule_next_frame(late);
+        assert_eq!(scheduler.deadline(), start + FRAME_INTERVAL * 2);
+
+        let paused = start + Duration::from_secs(5);
+        scheduler.schedule_next_frame(paused);
+        assert_eq!(scheduler.deadline(), paused + FRAME_INTERVAL);
+    }
+
+    #[test]
+    fn reports_rates_about_once_a_second(){
+        let start = Instant::now();
+        let mut scheduler = FrameScheduler::new_at(start);
+
+        for frame in 1..60{
+            assert_eq!(scheduler.record_frame_at(10, start + FRAME_INTERVAL * frame), None);
+        }
+        let report = scheduler.record_frame_at(10, start + Duration::from_secs(2)).unwrap();
+        assert_eq!(report, Report { frames_per_second: 30.0, instructions_per_second: 300.0 });
+
+        // the next window starts where the report was made
+        assert_eq!(scheduler.record_frame_at(5, start + Duration::from_millis(2500)), None);
+        let report = scheduler.record_frame_at(5, start + Duration::from_secs(3)).unwrap();
+        assert_eq!(report, Report { frames_per_second: 2.0, instructions_per_second: 10.0 });
+    }
+}
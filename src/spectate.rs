@@ -0,0 +1,403 @@
+use std::collections::VecDeque;
+use std::io::BufReader;
+use std::net::TcpStream as StdTcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::machine::Machine;
+use crate::movie::{self, MovieFrame};
+use crate::net::{self, Peer, Reader};
+use crate::savestate::MAX_STATE_SIZE;
+
+/*
+ * Spectator mode: one emulator broadcasts its session and any number of
+ * read-only viewers watch it, for demos and for keeping an eye on long
+ * automated runs. Viewers can join at any time.
+ *
+ * Like a movie, the broadcast is the keys and instruction count of each
+ * frame, which viewers run on their own copy of the ROM. Every
+ * SNAPSHOT_INTERVAL ticks, and whenever a viewer joins or falls behind, the
+ * whole machine is sent as a save state. Viewers start from the first
+ * snapshot they get, and anything done outside of frames, like loading a
+ * state, rewinding or resetting, reaches them with the next one.
+ *
+ * Wire format (big endian): the broadcaster sends "C8SP" and version u16,
+ * then
+ *   1, key mask u16, instructions u32     a frame
+ *   2, length u32, save state             the machine as it is now
+ */
+
+const SPECTATE_MAGIC: &[u8; 4] = b"C8SP";
+const SPECTATE_VERSION: u16 = 1;
+const FRAME_MESSAGE: u8 = 1;
+const SNAPSHOT_MESSAGE: u8 = 2;
+// a second at 60 Hz
+const SNAPSHOT_INTERVAL: u32 = 60;
+// messages a slow viewer can fall behind by before it waits for a snapshot
+const MESSAGE_BACKLOG: usize = 600;
+// a viewer this many frames behind jumps ahead to the latest snapshot it has
+const SKIP_AHEAD_FRAMES: usize = 30;
+// a viewer this many frames behind plays faster until it has caught up
+const CATCH_UP_FRAMES: usize = 4;
+const CATCH_UP_SPEED: f64 = 1.5;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static BROADCAST: Peer = Peer {
+    stopped_responding: "The broadcast stopped responding.",
+    left: "The broadcast ended.",
+    name: "the broadcast",
+};
+
+pub struct Broadcaster{
+    messages: broadcast::Sender<Arc<Vec<u8>>>,
+    // set when a viewer joins or misses messages, so the next tick sends a snapshot
+    needs_snapshot: Arc<AtomicBool>,
+    ticks: u32,
+    port: u16,
+}
+
+impl Broadcaster{
+    /// Broadcasts on 127.0.0.1; port 0 picks a free one.
+    pub fn start(port: u16) -> Result<Broadcaster, String>{
+        let (messages, _) = broadcast::channel(MESSAGE_BACKLOG);
+        let needs_snapshot = Arc::new(AtomicBool::new(false));
+
+        let connection = Connection { messages: messages.clone(), needs_snapshot: needs_snapshot.clone() };
+        let port = net::serve_locally(port, "broadcast", move |stream| connection.clone().serve(stream))?;
+
+        Ok(Broadcaster { messages, needs_snapshot, ticks: 0, port })
+    }
+
+    pub fn port(&self) -> u16{
+        self.port
+    }
+
+    pub fn viewers(&self) -> usize{
+        self.messages.receiver_count()
+    }
+
+    /// Sends the keys and instructions of the frame that just ran.
+    pub fn publish_frame(&self, keys: &[u8], instructions: u32){
+        if self.viewers() == 0{
+            return;
+        }
+
+        let mut message = vec![FRAME_MESSAGE];
+        message.extend_from_slice(&movie::keys_to_mask(keys).to_be_bytes());
+        message.extend_from_slice(&instructions.to_be_bytes());
+        // fails only when the last viewer left in the meantime
+        let _ = self.messages.send(Arc::new(message));
+    }
+
+    /// Called once per tick of the emulator loop, frame or not. Sends a
+    /// snapshot when one is due.
+    pub fn publish_state(&mut self, machine: &Machine){
+        self.ticks = self.ticks.wrapping_add(1);
+        if self.viewers() == 0{
+            return;
+        }
+
+        let due = self.ticks.is_multiple_of(SNAPSHOT_INTERVAL);
+        if self.needs_snapshot.swap(false, Ordering::Relaxed) || due{
+            let state = machine.save_state();
+            let mut message = vec![SNAPSHOT_MESSAGE];
+            message.extend_from_slice(&(state.len() as u32).to_be_bytes());
+            message.extend_from_slice(&state);
+            let _ = self.messages.send(Arc::new(message));
+        }
+    }
+}
+
+/// What each viewer's connection shares with the emulator.
+#[derive(Clone)]
+struct Connection{
+    messages: broadcast::Sender<Arc<Vec<u8>>>,
+    needs_snapshot: Arc<AtomicBool>,
+}
+
+impl Connection{
+    async fn serve(self, mut stream: TcpStream){
+        let _ = stream.set_nodelay(true);
+        let mut messages = self.messages.subscribe();
+
+        let mut greeting = Vec::new();
+        greeting.extend_from_slice(SPECTATE_MAGIC);
+        greeting.extend_from_slice(&SPECTATE_VERSION.to_be_bytes());
+        if stream.write_all(&greeting).await.is_err(){
+            return;
+        }
+        self.needs_snapshot.store(true, Ordering::Relaxed);
+
+        // frames mean nothing to a viewer until it has a snapshot to run them on
+        let mut synced = false;
+        loop{
+            match messages.recv().await {
+                Ok(message) =>{
+                    if !synced && message[0] != SNAPSHOT_MESSAGE{
+                        continue;
+                    }
+                    synced = true;
+                    if stream.write_all(&message).await.is_err(){
+                        break;
+                    }
+                },
+                Err(RecvError::Lagged(_)) =>{
+                    synced = false;
+                    self.needs_snapshot.store(true, Ordering::Relaxed);
+                },
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// What a viewer receives, in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BroadcastEvent{
+    Frame(MovieFrame),
+    Snapshot(Vec<u8>),
+    Ended(String),
+}
+
+pub struct Viewer{
+    events: Receiver<BroadcastEvent>,
+    queue: VecDeque<BroadcastEvent>,
+}
+
+impl Viewer{
+    /// Starts watching the broadcast at `address`, e.g. `127.0.0.1:7100`.
+    pub fn join(address: &str) -> Result<Viewer, String>{
+        let stream = StdTcpStream::connect(address)
+            .map_err(|e| format!("Could not connect to {}: {}", address, e))?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT)).map_err(|e| e.to_string())?;
+        let mut reader = Reader::new(BufReader::new(stream), &BROADCAST);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != SPECTATE_MAGIC{
+            return Err(format!("{} is not broadcasting a CHIP-8 session.", address));
+        }
+        let version = reader.read_u16()?;
+        if version != SPECTATE_VERSION{
+            return Err(format!("Broadcast version {} is not supported (expected {}).", version, SPECTATE_VERSION));
+        }
+
+        // the broadcaster may sit paused for as long as it likes
+        reader.get_ref().get_ref().set_read_timeout(None).map_err(|e| e.to_string())?;
+        let (sender, events) = mpsc::channel();
+        thread::spawn(move || receive_events(reader, sender));
+
+        Ok(Viewer { events, queue: VecDeque::new() })
+    }
+
+    /// The next thing to apply, if it has arrived.
+    pub fn next_event(&mut self) -> Option<BroadcastEvent>{
+        self.queue.extend(self.events.try_iter());
+
+        if self.frames_queued() > SKIP_AHEAD_FRAMES{
+            let latest_snapshot = self.queue.iter()
+                .rposition(|event| matches!(event, BroadcastEvent::Snapshot(_)));
+            if let Some(position) = latest_snapshot{
+                self.queue.drain(..position);
+            }
+        }
+
+        self.queue.pop_front()
+    }
+
+    /// How fast to play so as not to fall behind the broadcast.
+    pub fn speed_multiplier(&self) -> f64{
+        if self.frames_queued() > CATCH_UP_FRAMES { CATCH_UP_SPEED } else { 1.0 }
+    }
+
+    fn frames_queued(&self) -> usize{
+        self.queue.iter().filter(|event| matches!(event, BroadcastEvent::Frame(_))).count()
+    }
+}
+
+fn receive_events(mut reader: Reader<BufReader<StdTcpStream>>, sender: Sender<BroadcastEvent>){
+    loop{
+        let event = match receive_event(&mut reader) {
+            Ok(event) => event,
+            Err(e) =>{
+                let _ = sender.send(BroadcastEvent::Ended(e));
+                return;
+            },
+        };
+        if sender.send(event).is_err(){
+            return;
+        }
+    }
+}
+
+fn receive_event(reader: &mut Reader<BufReader<StdTcpStream>>) -> Result<BroadcastEvent, String>{
+    match reader.read_u8()? {
+        FRAME_MESSAGE =>{
+            let keys = reader.read_u16()?;
+            let instructions = reader.read_u32()?;
+            Ok(BroadcastEvent::Frame(MovieFrame { keys, instructions }))
+        },
+        SNAPSHOT_MESSAGE =>{
+            let length = reader.read_u32()? as usize;
+            if length > MAX_STATE_SIZE{
+                return Err(format!("A snapshot of {} bytes is too large.", length));
+            }
+            let mut state = vec![0; length];
+            reader.read_exact(&mut state)?;
+            Ok(BroadcastEvent::Snapshot(state))
+        },
+        other => Err(format!("Unknown broadcast message {}.", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Instant;
+
+    use super::*;
+    use crate::machine::Quirks;
+    use crate::timing;
+
+    // counts in v0, v1 and v2, the last only while key 0 is held
+    const ROM: [u8; 10] = [0x70, 0x01, 0x81, 0x04, 0xE3, 0xA1, 0x72, 0x01, 0x12, 0x00];
+
+    fn machine(seed: u32) -> Machine{
+        let mut machine = Machine::new(Quirks::default(), seed);
+        machine.load_rom(&ROM).unwrap();
+        machine.trace = false;
+        machine
+    }
+
+    /// Runs a frame and publishes it like the emulator loop does.
+    fn broadcast_frame(broadcaster: &mut Broadcaster, machine: &mut Machine, frame: u32){
+        machine.keypad = if (frame / 7).is_multiple_of(2) { vec![0] } else { vec![] };
+        timing::run_instructions(machine, 10).unwrap();
+        machine.tick_timers();
+        broadcaster.publish_frame(&machine.keypad, 10);
+        broadcaster.publish_state(machine);
+    }
+
+    /// Applies what has arrived like the emulator loop does, returning how many frames ran.
+    fn watch(viewer: &mut Viewer, machine: &mut Machine) -> usize{
+        let mut frames = 0;
+        while let Some(event) = viewer.next_event() {
+            match event {
+                BroadcastEvent::Frame(frame) =>{
+                    machine.keypad = movie::mask_to_keys(frame.keys);
+                    timing::run_instructions(machine, frame.instructions).unwrap();
+                    machine.tick_timers();
+                    frames += 1;
+                },
+                BroadcastEvent::Snapshot(state) => machine.load_state(&state).unwrap(),
+                BroadcastEvent::Ended(e) => panic!("{}", e),
+            }
+        }
+        frames
+    }
+
+    fn join(broadcaster: &Broadcaster) -> Viewer{
+        let viewer = Viewer::join(&format!("127.0.0.1:{}", broadcaster.port())).unwrap();
+        let start = Instant::now();
+        while broadcaster.viewers() == 0 {
+            assert!(start.elapsed() < CONNECT_TIMEOUT, "the broadcaster never saw the viewer");
+            thread::sleep(Duration::from_millis(1));
+        }
+        viewer
+    }
+
+    /// Keeps watching until the viewer's machine matches, which it only can
+    /// once everything broadcast has been applied.
+    fn watch_until_synced(viewer: &mut Viewer, machine: &mut Machine, broadcast: &Machine) -> usize{
+        let start = Instant::now();
+        let mut frames = 0;
+        loop{
+            frames += watch(viewer, machine);
+            if machine.save_state() == broadcast.save_state(){
+                return frames;
+            }
+            assert!(start.elapsed() < CONNECT_TIMEOUT, "the viewer never caught up");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn a_viewer_joining_mid_session_runs_the_same_machine(){
+        let mut broadcaster = Broadcaster::start(0).unwrap();
+        let mut broadcast = machine(1);
+        for frame in 0..100{
+            broadcast_frame(&mut broadcaster, &mut broadcast, frame);
+        }
+
+        let mut viewer = join(&broadcaster);
+        let mut watched = machine(2);
+        for frame in 100..250{
+            broadcast_frame(&mut broadcaster, &mut broadcast, frame);
+            watch(&mut viewer, &mut watched);
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        watch_until_synced(&mut viewer, &mut watched, &broadcast);
+        assert_eq!(watched.variable_registers, broadcast.variable_registers);
+    }
+
+    #[test]
+    fn a_lagging_viewer_skips_ahead_to_the_latest_snapshot(){
+        let mut broadcaster = Broadcaster::start(0).unwrap();
+        let mut broadcast = machine(1);
+        let mut viewer = join(&broadcaster);
+        let mut watched = machine(2);
+
+        // the viewer does not look at the broadcast for four seconds' worth of frames
+        for frame in 0..250{
+            broadcast_frame(&mut broadcaster, &mut broadcast, frame);
+        }
+        // let the backlog reach the viewer before it looks
+        thread::sleep(Duration::from_millis(50));
+
+        let frames = watch_until_synced(&mut viewer, &mut watched, &broadcast);
+        assert!(frames < 250 - SKIP_AHEAD_FRAMES, "ran {} frames instead of skipping ahead", frames);
+    }
+
+    fn queued_viewer(events: Vec<BroadcastEvent>) -> Viewer{
+        let (sender, receiver) = mpsc::channel();
+        for event in events{
+            sender.send(event).unwrap();
+        }
+        Viewer { events: receiver, queue: VecDeque::new() }
+    }
+
+    fn frames(count: usize) -> Vec<BroadcastEvent>{
+        vec![BroadcastEvent::Frame(MovieFrame { keys: 0, instructions: 10 }); count]
+    }
+
+    #[test]
+    fn catches_up_when_a_few_frames_behind(){
+        let mut viewer = queued_viewer(frames(CATCH_UP_FRAMES + 2));
+
+        assert!(matches!(viewer.next_event(), Some(BroadcastEvent::Frame(_))));
+        assert_eq!(viewer.speed_multiplier(), CATCH_UP_SPEED);
+        viewer.next_event();
+        assert_eq!(viewer.speed_multiplier(), 1.0);
+    }
+
+    #[test]
+    fn skips_ahead_only_when_there_is_a_snapshot_to_skip_to(){
+        let mut viewer = queued_viewer(frames(SKIP_AHEAD_FRAMES + 5));
+        assert!(matches!(viewer.next_event(), Some(BroadcastEvent::Frame(_))));
+
+        let mut events = frames(SKIP_AHEAD_FRAMES + 5);
+        events.push(BroadcastEvent::Snapshot(vec![1, 2, 3]));
+        events.extend(frames(2));
+        let mut viewer = queued_viewer(events);
+
+        assert_eq!(viewer.next_event(), Some(BroadcastEvent::Snapshot(vec![1, 2, 3])));
+        assert_eq!(viewer.frames_queued(), 2);
+    }
+}
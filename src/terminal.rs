@@ -0,0 +1,408 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Stdout, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use crate::framebuffer::Framebuffer;
+use crate::frontend::{Frontend, Hotkey};
+use crate::hotkeys::{Action, HotkeyBindings};
+use crate::keymap::Keymap;
+use crate::palette::{Colour, Palette};
+use crate::persistence::PhosphorFilter;
+
+/*
+ * Most terminals only report key presses, repeated while a key is held, and
+ * never the release. Unless the terminal supports the kitty keyboard protocol
+ * a key counts as held until it has not been seen for HOLD_TIMEOUT, which is
+ * long enough to cover the pause before the key starts repeating.
+ */
+const HOLD_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// How screen pixels are packed into character cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminalMode{
+    /// `▀` with the top pixel as the text colour and the bottom one as the
+    /// background: 1x2 pixels per cell, in full colour.
+    HalfBlock,
+    /// Braille dots: 2x4 pixels per cell, so 128x64 fits an 80 column
+    /// terminal, but every cell has a single foreground colour.
+    Braille,
+}
+
+impl TerminalMode{
+    /// Screen pixels per character cell, as (width, height).
+    fn cell_size(self) -> (u32, u32){
+        match self {
+            TerminalMode::HalfBlock => (1, 2),
+            TerminalMode::Braille => (2, 4),
+        }
+    }
+}
+
+impl FromStr for TerminalMode{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s.to_ascii_lowercase().as_str() {
+            "half-block" | "halfblock" => Ok(TerminalMode::HalfBlock),
+            "braille" => Ok(TerminalMode::Braille),
+            _ => Err(format!("Unknown terminal mode '{}', expected half-block or braille.", s)),
+        }
+    }
+}
+
+/// Where the screen sits in the terminal, in character cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Layout{
+    left: u16,
+    top: u16,
+    columns: u16,
+    rows: u16,
+}
+
+/// Draws the screen with coloured characters and reads the keyboard from
+/// stdin, so the emulator runs over SSH without a window. The terminal is
+/// put back the way it was when this is dropped.
+pub struct TerminalDisplay{
+    stdout: Stdout,
+    mode: TerminalMode,
+    palette: Palette,
+    // host key names in lower case, matching the SDL names in keymaps
+    keypad_keys: HashMap<String, u8>,
+    hotkey_keys: HashMap<String, Action>,
+    // when each held key was last pressed or repeated
+    held_keys: HashMap<String, Instant>,
+    // the terminal reports key releases, so keys never time out
+    reports_releases: bool,
+    keys_since_sample: BTreeSet<u8>,
+    status: String,
+    layout: Option<Layout>,
+    redraw_requested: bool,
+}
+
+impl TerminalDisplay{
+    pub fn new(mode: TerminalMode) -> Result<TerminalDisplay, String>{
+        let mut stdout = io::stdout();
+
+        terminal::enable_raw_mode().map_err(|e| format!("Could not switch the terminal to raw mode: {}", e))?;
+        execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All))
+            .map_err(|e| format!("Could not set up the terminal: {}", e))?;
+
+        let reports_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if reports_releases{
+            execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))
+                .map_err(|e| format!("Could not set up the terminal: {}", e))?;
+        }
+
+        let mut display = TerminalDisplay {
+            stdout,
+            mode,
+            palette: Palette::default(),
+            keypad_keys: HashMap::new(),
+            hotkey_keys: HashMap::new(),
+            held_keys: HashMap::new(),
+            reports_releases,
+            keys_since_sample: BTreeSet::new(),
+            status: String::new(),
+            layout: None,
+            redraw_requested: false,
+        };
+        display.set_keymap(&Keymap::cosmac_vip());
+        display.set_hotkeys(&HotkeyBindings::standard());
+
+        Ok(display)
+    }
+
+    /// Binds keys by their SDL names. Keys a terminal cannot report on their
+    /// own, like Shift, are never seen.
+    pub fn set_keymap(&mut self, keymap: &Keymap){
+        self.keypad_keys = keymap.bindings()
+            .map(|(name, key)| (name.to_ascii_lowercase(), key))
+            .collect();
+    }
+
+    pub fn set_hotkeys(&mut self, hotkeys: &HotkeyBindings){
+        self.hotkey_keys = hotkeys.bindings()
+            .map(|(name, action)| (name.to_ascii_lowercase(), action))
+            .collect();
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, hotkeys: &mut Vec<Hotkey>){
+        // raw mode stops Ctrl+C from interrupting the process
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL){
+            hotkeys.push(Hotkey::Pressed(Action::Quit));
+            return;
+        }
+
+        let Some(name) = key_name(key.code) else {
+            return;
+        };
+
+        match key.kind {
+            KeyEventKind::Press | KeyEventKind::Repeat =>{
+                // without release events repeats also arrive as presses
+                let repeat = self.held_keys.insert(name.clone(), Instant::now()).is_some();
+                if repeat || key.kind == KeyEventKind::Repeat{
+                    return;
+                }
+
+                if let Some(action) = self.hotkey_for(&name, key.modifiers.contains(KeyModifiers::SHIFT)){
+                    hotkeys.push(Hotkey::Pressed(action));
+                }
+            },
+            KeyEventKind::Release =>{
+                self.held_keys.remove(&name);
+                self.release(&name, hotkeys);
+            },
+        }
+    }
+
+    fn release(&self, name: &str, hotkeys: &mut Vec<Hotkey>){
+        // Shift may have been let go first, so release whichever binding was held
+        for action in [self.hotkey_for(name, false), self.hotkey_for(name, true)].into_iter().flatten(){
+            if action.is_held() && !hotkeys.contains(&Hotkey::Released(action)){
+                hotkeys.push(Hotkey::Released(action));
+            }
+        }
+    }
+
+    /// The action a key triggers, preferring its Shift binding while Shift is held.
+    fn hotkey_for(&self, name: &str, shift: bool) -> Option<Action>{
+        shift.then(|| self.hotkey_keys.get(&format!("shift+{}", name)))
+            .flatten()
+            .or_else(|| self.hotkey_keys.get(name))
+            .copied()
+    }
+
+    /// Lets go of keys that have not repeated for a while.
+    fn expire_held_keys(&mut self, hotkeys: &mut Vec<Hotkey>){
+        let now = Instant::now();
+        let expired: Vec<String> = self.held_keys.iter()
+            .filter(|(_, seen)| now.duration_since(**seen) >= HOLD_TIMEOUT)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in expired{
+            self.held_keys.remove(&name);
+            self.release(&name, hotkeys);
+        }
+    }
+
+    fn held_keypad_keys(&self) -> Vec<u8>{
+        self.held_keys.keys()
+            .filter_map(|name| self.keypad_keys.get(name).copied())
+            .collect()
+    }
+
+    /// Centres a `width` x `height` screen in the terminal, cutting off what
+    /// does not fit, with the status line underneath.
+    fn draw(&mut self, width: u32, height: u32, pixel_colour: impl Fn(u32, u32) -> Option<Colour>) -> Result<(), String>{
+        let (terminal_columns, terminal_rows) = terminal::size()
+            .map_err(|e| format!("Could not read the terminal size: {}", e))?;
+        let (cell_width, cell_height) = self.mode.cell_size();
+
+        let columns = (width.div_ceil(cell_width) as u16).min(terminal_columns);
+        let rows = (height.div_ceil(cell_height) as u16).min(terminal_rows.saturating_sub(1));
+        let layout = Layout {
+            left: (terminal_columns - columns) / 2,
+            top: terminal_rows.saturating_sub(rows + 1) / 2,
+            columns,
+            rows,
+        };
+
+        let mut output: Vec<u8> = Vec::new();
+        if self.layout != Some(layout){
+            queue!(output, ResetColor, Clear(ClearType::All)).map_err(|e| e.to_string())?;
+            self.layout = Some(layout);
+        }
+
+        let background = self.palette.background();
+        let colour_at = |x: u32, y: u32| {
+            if x < width && y < height { pixel_colour(x, y) } else { None }
+        };
+
+        let mut current_colours = None;
+        for row in 0..layout.rows{
+            queue!(output, MoveTo(layout.left, layout.top + row)).map_err(|e| e.to_string())?;
+
+            for column in 0..layout.columns{
+                let x = column as u32 * cell_width;
+                let y = row as u32 * cell_height;
+                let (character, foreground, cell_background) = match self.mode {
+                    TerminalMode::HalfBlock =>{
+                        let top = colour_at(x, y).unwrap_or(background);
+                        let bottom = colour_at(x, y + 1).unwrap_or(background);
+                        ('▀', top, bottom)
+                    },
+                    TerminalMode::Braille => braille_cell(x, y, background, &colour_at),
+                };
+
+                if current_colours != Some((foreground, cell_background)){
+                    queue!(output, SetForegroundColor(to_terminal_color(foreground)), SetBackgroundColor(to_terminal_color(cell_background)))
+                        .map_err(|e| e.to_string())?;
+                    current_colours = Some((foreground, cell_background));
+                }
+                queue!(output, Print(character)).map_err(|e| e.to_string())?;
+            }
+        }
+
+        let status: String = self.status.chars().take(terminal_columns as usize).collect();
+        queue!(output, ResetColor, MoveTo(0, layout.top + layout.rows), Clear(ClearType::CurrentLine), Print(status))
+            .map_err(|e| e.to_string())?;
+
+        self.stdout.write_all(&output)
+            .and_then(|_| self.stdout.flush())
+            .map_err(|e| format!("Could not draw to the terminal: {}", e))
+    }
+}
+
+impl Frontend for TerminalDisplay{
+    fn render(&mut self, framebuffer: &Framebuffer) -> Result<(), String>{
+        let foreground = self.palette.foreground();
+
+        self.draw(framebuffer.width, framebuffer.height, |x, y| {
+            framebuffer.get_pixel_at(x, y).then_some(foreground)
+        })
+    }
+
+    fn render_with_persistence(&mut self, filter: &PhosphorFilter) -> Result<(), String>{
+        let background = self.palette.background();
+        let foreground = self.palette.foreground();
+
+        self.draw(filter.width(), filter.height(), |x, y| {
+            let intensity = filter.intensity_at(x, y);
+            (intensity > 0.0).then(|| background.blend(foreground, intensity))
+        })
+    }
+
+    /// Reads pending key presses. F1-F9 load a save state slot, Shift+F1-F9
+    /// save one and Ctrl+C quits as well as the quit hotkey.
+    fn tick(&mut self) -> Vec<Hotkey>{
+        let mut hotkeys = Vec::new();
+
+        while event::poll(Duration::ZERO).unwrap_or(false) {
+            match event::read() {
+                Ok(Event::Key(key)) => self.handle_key(key, &mut hotkeys),
+                Ok(Event::Resize(..)) => self.redraw_requested = true,
+                Ok(_) => {},
+                Err(e) =>{
+                    self.status = format!("Could not read the keyboard: {}", e);
+                    break;
+                },
+            }
+
+            let held = self.held_keypad_keys();
+            self.keys_since_sample.extend(held);
+        }
+
+        if !self.reports_releases{
+            self.expire_held_keys(&mut hotkeys);
+        }
+
+        hotkeys
+    }
+
+    fn get_keypad_press(&mut self) -> Vec<u8>{
+        let mut keys = std::mem::take(&mut self.keys_since_sample);
+        keys.extend(self.held_keypad_keys());
+
+        keys.into_iter().collect()
+    }
+
+    fn palette(&self) -> &Palette{
+        &self.palette
+    }
+
+    fn set_palette(&mut self, palette: Palette){
+        self.palette = palette;
+        self.redraw_requested = true;
+    }
+
+    fn take_redraw_request(&mut self) -> bool{
+        std::mem::take(&mut self.redraw_requested)
+    }
+
+    /// Shows the message on the status line below the screen.
+    fn report(&mut self, message: &str){
+        self.status = message.to_string();
+        self.redraw_requested = true;
+    }
+
+    // stdout is the screen
+    fn allows_instruction_trace(&self) -> bool{
+        false
+    }
+}
+
+impl Drop for TerminalDisplay{
+    fn drop(&mut self){
+        if self.reports_releases{
+            let _ = execute!(self.stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.stdout, ResetColor, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// The braille character for the 2x4 pixels from (x, y), drawn in the colour
+/// that stands out most from the background.
+fn braille_cell(x: u32, y: u32, background: Colour, colour_at: &impl Fn(u32, u32) -> Option<Colour>) -> (char, Colour, Colour){
+    // dot bits in braille order: the left column top to bottom, the right
+    // column top to bottom, then the bottom row left and right
+    const DOTS: [(u32, u32); 8] = [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2), (0, 3), (1, 3)];
+
+    let mut bits = 0;
+    let mut foreground = background;
+    for (bit, (dx, dy)) in DOTS.iter().enumerate(){
+        if let Some(colour) = colour_at(x + dx, y + dy){
+            bits |= 1 << bit;
+            if contrast(colour, background) > contrast(foreground, background){
+                foreground = colour;
+            }
+        }
+    }
+
+    (char::from_u32(0x2800 + bits).unwrap_or(' '), foreground, background)
+}
+
+fn contrast(colour: Colour, other: Colour) -> u32{
+    colour.red.abs_diff(other.red) as u32 + colour.green.abs_diff(other.green) as u32 + colour.blue.abs_diff(other.blue) as u32
+}
+
+/// The SDL scancode name for a key, in lower case, so keymaps work unchanged.
+fn key_name(code: KeyCode) -> Option<String>{
+    let name = match code {
+        KeyCode::Char(' ') => "space".to_string(),
+        KeyCode::Char(c) => c.to_lowercase().to_string(),
+        KeyCode::F(number) => format!("f{}", number),
+        KeyCode::Esc => "escape".to_string(),
+        KeyCode::Enter => "return".to_string(),
+        KeyCode::Tab => "tab".to_string(),
+        KeyCode::Backspace => "backspace".to_string(),
+        KeyCode::Up => "up".to_string(),
+        KeyCode::Down => "down".to_string(),
+        KeyCode::Left => "left".to_string(),
+        KeyCode::Right => "right".to_string(),
+        KeyCode::Home => "home".to_string(),
+        KeyCode::End => "end".to_string(),
+        KeyCode::PageUp => "pageup".to_string(),
+        KeyCode::PageDown => "pagedown".to_string(),
+        KeyCode::Insert => "insert".to_string(),
+        KeyCode::Delete => "delete".to_string(),
+        KeyCode::PrintScreen => "printscreen".to_string(),
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+fn to_terminal_color(colour: Colour) -> Color{
+    Color::Rgb { r: colour.red, g: colour.green, b: colour.blue }
+}
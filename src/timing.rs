@@ -0,0 +1,259 @@
+use crate::machine::Machine;
+use crate::scheduler::Speed;
+
+/*
+ * Timing of the original COSMAC VIP interpreter, counted in 1802 machine
+ * cycles (8 clocks of the 1.7609 MHz crystal, about 4.54 us each).
+ *
+ * Every frame the CDP1861 display chip steals a DMA cycle for each byte it
+ * shows: 32 rows of 8 bytes, each row repeated on 4 scanlines. The 60 Hz
+ * interrupt routine that feeds it and counts down the timers takes a few
+ * dozen more. Whatever is left runs the interpreter.
+ *
+ * Instruction costs are estimates, not measurements: a fixed fetch and decode,
+ * plus a body worked out from the length of each of the interpreter's
+ * routines. 8XYN runs through a single routine that patches the 1802
+ * arithmetic instruction in and executes it, so every form costs the same.
+ * The result runs VIP games at about their original speed, but it is not
+ * cycle accurate.
+ */
+pub const CYCLES_PER_FRAME: u32 = 3668;
+pub const DISPLAY_DMA_CYCLES: u32 = 32 * 4 * 8;
+pub const INTERRUPT_CYCLES: u32 = 46;
+pub const FETCH_CYCLES: u32 = 40;
+
+// there is no clock to run against when the host paces the frames
+const UNCAPPED_ERROR: &str = "Uncapped speed needs a real time clock, give a number of instructions instead.";
+
+/// Cycles the interpreter gets in each frame.
+pub const INTERPRETER_CYCLES_PER_FRAME: u32 = CYCLES_PER_FRAME - DISPLAY_DMA_CYCLES - INTERRUPT_CYCLES;
+
+/// Machine cycles the instruction at the program counter will take.
+pub fn instruction_cycles(machine: &Machine) -> u32{
+    // an instruction past the end of memory fails in step(), whatever it costs
+    let pc = machine.program_counter as usize;
+    let first_byte = machine.ram.get(pc).copied().unwrap_or(0);
+    let second_byte = machine.ram.get(pc + 1).copied().unwrap_or(0);
+    let x = (first_byte & 0xF) as usize;
+    let vx = machine.variable_registers[x];
+    let vy = machine.variable_registers[(second_byte >> 4) as usize];
+
+    let body = match (first_byte >> 4, second_byte) {
+        (0x0, 0xE0) => 24,
+        (0x0, 0xEE) => 10,
+        (0x0, _) => 10,
+        (0x1, _) => 12,
+        (0x2, _) => 26,
+        // skips cost a little more than falling through
+        (0x3, nn) if vx == nn => 14,
+        (0x4, nn) if vx != nn => 14,
+        (0x3, _) | (0x4, _) => 10,
+        (0x5, _) if vx == vy => 18,
+        (0x5, _) => 14,
+        (0x6, _) => 6,
+        (0x7, _) => 10,
+        (0x8, _) => 44,
+        (0x9, _) if vx != vy => 18,
+        (0x9, _) => 14,
+        (0xA, _) => 12,
+        (0xB, _) => 22,
+        (0xC, _) => 36,
+        (0xD, _) => sprite_cycles(vx, second_byte & 0xF),
+        (0xE, _) => 16,
+        (0xF, 0x07) | (0xF, 0x15) | (0xF, 0x18) => 10,
+        (0xF, 0x0A) => 16,
+        (0xF, 0x1E) => 19,
+        (0xF, 0x29) => 20,
+        (0xF, 0x33) => 204,
+        // copies one register per loop iteration
+        (0xF, 0x55) | (0xF, 0x65) => 14 + 14 * (x as u32 + 1),
+        _ => 10,
+    };
+
+    FETCH_CYCLES + body
+}
+
+/// Sprites are drawn a byte at a time. A sprite that is not byte aligned is
+/// shifted into place one bit at a time and then spans two bytes per row.
+fn sprite_cycles(x: u8, height: u8) -> u32{
+    let shift = (x % 8) as u32;
+    let row_cycles = if shift == 0 { 18 } else { 34 + 4 * shift };
+
+    26 + height as u32 * row_cycles
+}
+
+/// Runs the machine a frame at a time on the VIP's cycle budget.
+pub struct VipTiming{
+    /// Cycles the last instruction of the previous frame ran over by.
+    overrun: u32,
+}
+
+impl VipTiming{
+    pub fn new() -> VipTiming{
+        VipTiming { overrun: 0 }
+    }
+
+    /// Runs the instructions that fit in one frame, up to the 60 Hz interrupt,
+    /// and returns how many ran. The caller ticks the timers afterwards.
+    /// Stops at the first instruction that fails.
+    ///
+    /// Like the VIP interpreter, DXYN waits for the interrupt before drawing
+    /// so sprites never tear. A draw therefore ends the frame unless it comes
+    /// straight after the interrupt.
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Result<u32, String>{
+        if self.overrun >= INTERPRETER_CYCLES_PER_FRAME{
+            self.overrun -= INTERPRETER_CYCLES_PER_FRAME;
+            return Ok(0);
+        }
+
+        let mut cycles_left = INTERPRETER_CYCLES_PER_FRAME - self.overrun;
+        let mut instructions = 0;
+        self.overrun = 0;
+
+        loop {
+            let is_draw = machine.ram.get(machine.program_counter as usize).is_some_and(|byte| byte >> 4 == 0xD);
+            if is_draw && instructions > 0{
+                // idle until the vertical blank
+                break;
+            }
+
+            let cycles = instruction_cycles(machine);
+            machine.step()?;
+            instructions += 1;
+
+            if cycles >= cycles_left{
+                self.overrun = cycles - cycles_left;
+                break;
+            }
+            cycles_left -= cycles;
+        }
+
+        Ok(instructions)
+    }
+}
+
+/// Checks that a speed can be run by `run_paced_frame`. Hosts call this when
+/// the speed is set, to turn down uncapped speed up front.
+pub fn paced_speed(speed: Speed) -> Result<Speed, String>{
+    match speed {
+        Speed::Uncapped => Err(UNCAPPED_ERROR.to_string()),
+        speed => Ok(speed),
+    }
+}
+
+/// Runs one whole 60 Hz frame, timer tick included, for hosts that pace the
+/// frames themselves like the libretro core and the language bindings.
+/// Uncapped speed is an error, see `paced_speed`.
+pub fn run_paced_frame(machine: &mut Machine, speed: Speed, vip_timing: &mut VipTiming) -> Result<u32, String>{
+    let instructions = match speed {
+        Speed::InstructionsPerFrame(instructions) =>{
+            run_instructions(machine, instructions)?;
+            instructions
+        },
+        Speed::VipCycles => vip_timing.run_frame(machine)?,
+        Speed::Uncapped => return Err(UNCAPPED_ERROR.to_string()),
+    };
+    machine.tick_timers();
+
+    Ok(instructions)
+}
+
+/// Runs `count` instructions, stopping at the first that fails.
+pub fn run_instructions(machine: &mut Machine, count: u32) -> Result<(), String>{
+    for _ in 0..count{
+        machine.step()?;
+    }
+    Ok(())
+}
+
+impl Default for VipTiming{
+    fn default() -> Self{
+        VipTiming::new()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::machine::{Quirks, PROGRAM_START};
+
+    fn machine(rom: &[u8]) -> Machine{
+        let mut machine = Machine::new(Quirks::default(), 0);
+        machine.load_rom(rom).unwrap();
+        machine
+    }
+
+    #[test]
+    fn sprites_cost_more_when_not_byte_aligned(){
+        let mut machine = machine(&[0xD0, 0x15]);
+        machine.variable_registers[0] = 8;
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 26 + 5 * 18);
+
+        machine.variable_registers[0] = 11;
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 26 + 5 * (34 + 4 * 3));
+    }
+
+    #[test]
+    fn skips_cost_more_than_falling_through(){
+        let mut machine = machine(&[0x30, 0x07, 0x50, 0x10]);
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 10);
+        machine.variable_registers[0] = 7;
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 14);
+
+        machine.program_counter = PROGRAM_START + 2;
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 14);
+        machine.variable_registers[1] = 7;
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 18);
+    }
+
+    #[test]
+    fn register_copies_cost_per_register(){
+        let machine = machine(&[0xF3, 0x55, 0x00, 0xE0]);
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 14 + 14 * 4);
+
+        let mut machine = machine;
+        machine.program_counter = PROGRAM_START + 2;
+        assert_eq!(instruction_cycles(&machine), FETCH_CYCLES + 24);
+    }
+
+    #[test]
+    fn a_draw_ends_the_frame_unless_it_comes_first(){
+        // v0 := 1, draw, then jump to itself forever
+        let mut machine = machine(&[0x60, 0x01, 0xD0, 0x05, 0x12, 0x04]);
+        let mut timing = VipTiming::new();
+
+        assert_eq!(timing.run_frame(&mut machine), Ok(1));
+        assert_eq!(machine.program_counter, PROGRAM_START + 2);
+
+        let instructions = timing.run_frame(&mut machine).unwrap();
+        assert!(instructions > 1);
+        assert_eq!(machine.program_counter, PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn overrun_carries_into_the_next_frame(){
+        // a jump to itself costs 52 cycles, and 26 frames hold exactly 1299 of them
+        let mut machine = machine(&[0x12, 0x00]);
+        let mut timing = VipTiming::new();
+        let jump_cycles = instruction_cycles(&machine);
+        assert_eq!(26 * INTERPRETER_CYCLES_PER_FRAME % jump_cycles, 0);
+
+        let frames: Vec<u32> = (0..26).map(|_| timing.run_frame(&mut machine).unwrap()).collect();
+
+        assert_eq!(frames[0], 50);
+        assert_eq!(timing.overrun, 0);
+        assert_eq!(frames.iter().sum::<u32>(), 26 * INTERPRETER_CYCLES_PER_FRAME / jump_cycles);
+        assert_eq!(frames.iter().filter(|instructions| **instructions == 49).count(), 1);
+    }
+
+    #[test]
+    fn an_overrun_longer_than_a_frame_skips_it(){
+        let mut machine = machine(&[0x12, 0x00]);
+        let mut timing = VipTiming { overrun: INTERPRETER_CYCLES_PER_FRAME + 10 };
+
+        assert_eq!(timing.run_frame(&mut machine), Ok(0));
+        assert_eq!(timing.overrun, 10);
+        assert_eq!(machine.program_counter, PROGRAM_START);
+        assert_eq!(timing.run_frame(&mut machine), Ok(50));
+    }
+}
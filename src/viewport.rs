@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+/// How the screen is fitted into a window of any size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleMode{
+    /// Every CHIP-8 pixel is the same whole number of window pixels, so
+    /// nothing looks uneven, at the cost of wider borders.
+    Integer,
+    /// As large as fits while keeping the 2:1 shape.
+    Aspect,
+}
+
+impl FromStr for ScaleMode{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>{
+        match s.to_ascii_lowercase().as_str() {
+            "integer" => Ok(ScaleMode::Integer),
+            "aspect" => Ok(ScaleMode::Aspect),
+            _ => Err(format!("Unknown scale mode '{}', expected integer or aspect.", s)),
+        }
+    }
+}
+
+/// The area of the window the screen is drawn into, centred with borders
+/// around it where the window's shape does not match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Viewport{
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    screen_width: u32,
+    screen_height: u32,
+}
+
+impl Viewport{
+    /// Fits a `screen_width` x `screen_height` screen into the window.
+    pub fn fit(window_width: u32, window_height: u32, screen_width: u32, screen_height: u32, mode: ScaleMode) -> Viewport{
+        let (width, height) = match mode {
+            ScaleMode::Integer =>{
+                let scale = (window_width / screen_width).min(window_height / screen_height).max(1);
+                (screen_width * scale, screen_height * scale)
+            },
+            ScaleMode::Aspect =>{
+                // whichever side runs out first decides the size
+                if window_width as u64 * screen_height as u64 <= window_height as u64 * screen_width as u64{
+                    (window_width, window_width * screen_height / screen_width)
+                } else{
+                    (window_height * screen_width / screen_height, window_height)
+                }
+            },
+        };
+
+        Viewport {
+            x: (window_width as i32 - width as i32) / 2,
+            y: (window_height as i32 - height as i32) / 2,
+            width,
+            height,
+            screen_width,
+            screen_height,
+        }
+    }
+
+    /// Window area covered by a screen pixel, as (x, y, width, height).
+    /// Edges are rounded per pixel so non-integer scales leave no gaps.
+    pub fn pixel_rect(&self, x: u32, y: u32) -> (i32, i32, u32, u32){
+        let left = x * self.width / self.screen_width;
+        let right = (x + 1) * self.width / self.screen_width;
+        let top = y * self.height / self.screen_height;
+        let bottom = (y + 1) * self.height / self.screen_height;
+
+        (self.x + left as i32, self.y + top as i32, (right - left).max(1), (bottom - top).max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn area(viewport: Viewport) -> (i32, i32, u32, u32){
+        (viewport.x, viewport.y, viewport.width, viewport.height)
+    }
+
+    #[test]
+    fn integer_mode_uses_whole_multiples(){
+        assert_eq!(area(Viewport::fit(700, 400, 64, 32, ScaleMode::Integer)), (30, 40, 640, 320));
+        // switching to high resolution halves the scale and keeps the same area
+        assert_eq!(area(Viewport::fit(700, 400, 128, 64, ScaleMode::Integer)), (30, 40, 640, 320));
+    }
+
+    #[test]
+    fn aspect_mode_fills_the_shorter_side(){
+        assert_eq!(area(Viewport::fit(1000, 400, 64, 32, ScaleMode::Aspect)), (100, 0, 800, 400));
+        assert_eq!(area(Viewport::fit(1000, 400, 128, 64, ScaleMode::Aspect)), (100, 0, 800, 400));
+        assert_eq!(area(Viewport::fit(500, 1000, 64, 32, ScaleMode::Aspect)), (0, 375, 500, 250));
+    }
+
+    #[test]
+    fn a_window_smaller_than_the_screen(){
+        // integer mode never goes below one window pixel per screen pixel and overhangs evenly
+        assert_eq!(area(Viewport::fit(40, 20, 64, 32, ScaleMode::Integer)), (-12, -6, 64, 32));
+        assert_eq!(area(Viewport::fit(40, 20, 64, 32, ScaleMode::Aspect)), (0, 0, 40, 20));
+
+        let viewport = Viewport::fit(40, 20, 64, 32, ScaleMode::Aspect);
+        let (_, _, width, height) = viewport.pixel_rect(63, 31);
+        assert_eq!((width, height), (1, 1));
+    }
+
+    #[test]
+    fn pixels_cover_the_viewport_without_gaps(){
+        for (screen_width, screen_height) in [(64, 32), (128, 64)]{
+            let viewport = Viewport::fit(1000, 700, screen_width, screen_height, ScaleMode::Aspect);
+
+            let mut right = viewport.x;
+            for x in 0..screen_width{
+                let (left, _, width, _) = viewport.pixel_rect(x, 0);
+                assert_eq!(left, right, "gap before column {}", x);
+                right = left + width as i32;
+            }
+            assert_eq!(right, viewport.x + viewport.width as i32);
+
+            let mut bottom = viewport.y;
+            for y in 0..screen_height{
+                let (_, top, _, height) = viewport.pixel_rect(0, y);
+                assert_eq!(top, bottom, "gap before row {}", y);
+                bottom = top + height as i32;
+            }
+            assert_eq!(bottom, viewport.y + viewport.height as i32);
+        }
+    }
+
+    #[test]
+    fn parses_scale_modes(){
+        assert_eq!("Integer".parse(), Ok(ScaleMode::Integer));
+        assert_eq!("aspect".parse(), Ok(ScaleMode::Aspect));
+        assert!("stretch".parse::<ScaleMode>().is_err());
+    }
+}